@@ -0,0 +1,91 @@
+#define MEMPOOL_HASH_LENGTH 32
+// Longest removal reason is 'sizelimit' with 9 chars + the null terminator.
+#define MAX_REMOVAL_REASON_LENGTH 9 + 1
+#define MAX_REJECT_REASON_LENGTH 118
+
+struct mempool_added
+{
+    u8      txid[MEMPOOL_HASH_LENGTH];
+    s32     vsize;
+    s64     fee;
+};
+
+struct mempool_removed
+{
+    u8      txid[MEMPOOL_HASH_LENGTH];
+    char    reason[MAX_REMOVAL_REASON_LENGTH];
+    s32     vsize;
+    s64     fee;
+    u64     entry_time;
+};
+
+struct mempool_replaced
+{
+    u8      replaced_txid[MEMPOOL_HASH_LENGTH];
+    s32     replaced_vsize;
+    s64     replaced_fee;
+    u64     replaced_entry_time;
+    u8      replacement_txid[MEMPOOL_HASH_LENGTH];
+    s32     replacement_vsize;
+    s64     replacement_fee;
+};
+
+struct mempool_rejected
+{
+    u8      txid[MEMPOOL_HASH_LENGTH];
+    char    reason[MAX_REJECT_REASON_LENGTH];
+};
+
+BPF_PERF_OUTPUT(perf_mempool_added);
+BPF_PERF_OUTPUT(perf_mempool_removed);
+BPF_PERF_OUTPUT(perf_mempool_replaced);
+BPF_PERF_OUTPUT(perf_mempool_rejected);
+
+int trace_mempool_added(struct pt_regs *ctx) {
+    struct mempool_added a = {};
+
+    bpf_usdt_readarg_p(1, ctx, &a.txid, MEMPOOL_HASH_LENGTH);
+    bpf_usdt_readarg(2, ctx, &a.vsize);
+    bpf_usdt_readarg(3, ctx, &a.fee);
+
+    perf_mempool_added.perf_submit(ctx, &a, sizeof(a));
+    return 0;
+};
+
+int trace_mempool_removed(struct pt_regs *ctx) {
+    struct mempool_removed r = {};
+
+    bpf_usdt_readarg_p(1, ctx, &r.txid, MEMPOOL_HASH_LENGTH);
+    bpf_usdt_readarg_p(2, ctx, &r.reason, MAX_REMOVAL_REASON_LENGTH);
+    bpf_usdt_readarg(3, ctx, &r.vsize);
+    bpf_usdt_readarg(4, ctx, &r.fee);
+    bpf_usdt_readarg(5, ctx, &r.entry_time);
+
+    perf_mempool_removed.perf_submit(ctx, &r, sizeof(r));
+    return 0;
+};
+
+int trace_mempool_replaced(struct pt_regs *ctx) {
+    struct mempool_replaced r = {};
+
+    bpf_usdt_readarg_p(1, ctx, &r.replaced_txid, MEMPOOL_HASH_LENGTH);
+    bpf_usdt_readarg(2, ctx, &r.replaced_vsize);
+    bpf_usdt_readarg(3, ctx, &r.replaced_fee);
+    bpf_usdt_readarg(4, ctx, &r.replaced_entry_time);
+    bpf_usdt_readarg_p(5, ctx, &r.replacement_txid, MEMPOOL_HASH_LENGTH);
+    bpf_usdt_readarg(6, ctx, &r.replacement_vsize);
+    bpf_usdt_readarg(7, ctx, &r.replacement_fee);
+
+    perf_mempool_replaced.perf_submit(ctx, &r, sizeof(r));
+    return 0;
+};
+
+int trace_mempool_rejected(struct pt_regs *ctx) {
+    struct mempool_rejected r = {};
+
+    bpf_usdt_readarg_p(1, ctx, &r.txid, MEMPOOL_HASH_LENGTH);
+    bpf_usdt_readarg_p(2, ctx, &r.reason, MAX_REJECT_REASON_LENGTH);
+
+    perf_mempool_rejected.perf_submit(ctx, &r, sizeof(r));
+    return 0;
+};
@@ -0,0 +1,97 @@
+// Uses MAX_PEER_ADDR_LENGTH and MAX_PEER_CONN_TYPE_LENGTH from
+// p2p_in_and_outbound.c.
+#define MAX_MISBEHAVING_MESSAGE_LENGTH 128
+
+// Used for net:inbound_connection and net:outbound_connection.
+struct new_connection
+{
+    u64     id;
+    char    addr[MAX_PEER_ADDR_LENGTH];
+    char    conn_type[MAX_PEER_CONN_TYPE_LENGTH];
+    u32     network;
+    u64     existing;
+};
+
+// Used for net:closed_connection and net:evicted_inbound_connection.
+struct closed_connection
+{
+    u64     id;
+    char    addr[MAX_PEER_ADDR_LENGTH];
+    char    conn_type[MAX_PEER_CONN_TYPE_LENGTH];
+    u32     network;
+    u64     time_established;
+};
+
+struct misbehaving_connection
+{
+    u64     id;
+    char    message[MAX_MISBEHAVING_MESSAGE_LENGTH];
+};
+
+BPF_PERF_OUTPUT(perf_inbound_connections);
+BPF_PERF_OUTPUT(perf_outbound_connections);
+BPF_PERF_OUTPUT(perf_closed_connections);
+BPF_PERF_OUTPUT(perf_evicted_connections);
+BPF_PERF_OUTPUT(perf_misbehaving_connections);
+
+int trace_inbound_connection(struct pt_regs *ctx) {
+    struct new_connection c = {};
+
+    bpf_usdt_readarg(1, ctx, &c.id);
+    bpf_usdt_readarg_p(2, ctx, &c.addr, MAX_PEER_ADDR_LENGTH);
+    bpf_usdt_readarg_p(3, ctx, &c.conn_type, MAX_PEER_CONN_TYPE_LENGTH);
+    bpf_usdt_readarg(4, ctx, &c.network);
+    bpf_usdt_readarg(5, ctx, &c.existing);
+
+    perf_inbound_connections.perf_submit(ctx, &c, sizeof(c));
+    return 0;
+};
+
+int trace_outbound_connection(struct pt_regs *ctx) {
+    struct new_connection c = {};
+
+    bpf_usdt_readarg(1, ctx, &c.id);
+    bpf_usdt_readarg_p(2, ctx, &c.addr, MAX_PEER_ADDR_LENGTH);
+    bpf_usdt_readarg_p(3, ctx, &c.conn_type, MAX_PEER_CONN_TYPE_LENGTH);
+    bpf_usdt_readarg(4, ctx, &c.network);
+    bpf_usdt_readarg(5, ctx, &c.existing);
+
+    perf_outbound_connections.perf_submit(ctx, &c, sizeof(c));
+    return 0;
+};
+
+int trace_closed_connection(struct pt_regs *ctx) {
+    struct closed_connection c = {};
+
+    bpf_usdt_readarg(1, ctx, &c.id);
+    bpf_usdt_readarg_p(2, ctx, &c.addr, MAX_PEER_ADDR_LENGTH);
+    bpf_usdt_readarg_p(3, ctx, &c.conn_type, MAX_PEER_CONN_TYPE_LENGTH);
+    bpf_usdt_readarg(4, ctx, &c.network);
+    bpf_usdt_readarg(5, ctx, &c.time_established);
+
+    perf_closed_connections.perf_submit(ctx, &c, sizeof(c));
+    return 0;
+};
+
+int trace_evicted_inbound_connection(struct pt_regs *ctx) {
+    struct closed_connection c = {};
+
+    bpf_usdt_readarg(1, ctx, &c.id);
+    bpf_usdt_readarg_p(2, ctx, &c.addr, MAX_PEER_ADDR_LENGTH);
+    bpf_usdt_readarg_p(3, ctx, &c.conn_type, MAX_PEER_CONN_TYPE_LENGTH);
+    bpf_usdt_readarg(4, ctx, &c.network);
+    bpf_usdt_readarg(5, ctx, &c.time_established);
+
+    perf_evicted_connections.perf_submit(ctx, &c, sizeof(c));
+    return 0;
+};
+
+int trace_misbehaving_connection(struct pt_regs *ctx) {
+    struct misbehaving_connection m = {};
+
+    bpf_usdt_readarg(1, ctx, &m.id);
+    bpf_usdt_readarg_p(2, ctx, &m.message, MAX_MISBEHAVING_MESSAGE_LENGTH);
+
+    perf_misbehaving_connections.perf_submit(ctx, &m, sizeof(m));
+    return 0;
+};
@@ -1,34 +1,33 @@
 // We don't care about the contents of the utxocache tracepoints here.
 // It's only relevant that the tracepoint is being called so we can
-// increase the respective prometheus counter.
+// increase the respective prometheus counter. The calls are counted in
+// a per-CPU array, which is periodically read from userspace. This avoids
+// submitting millions of events per second to userspace during IBD.
 
-const u8 UTXOCACHE_ADD = 0;
-const u8 UTXOCACHE_SPENT = 1;
-const u8 UTXOCACHE_UNCACHE = 2;
+#define UTXOCACHE_ADD 0
+#define UTXOCACHE_SPENT 1
+#define UTXOCACHE_UNCACHE 2
 
-struct utxo_cache_event {
-  u8 event;
-};
+BPF_PERCPU_ARRAY(utxocache_events, u64, 3);
 
-BPF_PERF_OUTPUT(perf_utxocache_events);
+static inline void count_utxocache_event(int event) {
+    u64 *count = utxocache_events.lookup(&event);
+    if (count) {
+        (*count)++;
+    }
+}
 
 int trace_utxocache_add(struct pt_regs *ctx) {
-    struct utxo_cache_event e = {};
-    e.event = UTXOCACHE_ADD;
-    perf_utxocache_events.perf_submit(ctx, &e, sizeof(e));
+    count_utxocache_event(UTXOCACHE_ADD);
     return 0;
 };
 
 int trace_utxocache_uncache(struct pt_regs *ctx) {
-    struct utxo_cache_event e = {};
-    e.event = UTXOCACHE_UNCACHE;
-    perf_utxocache_events.perf_submit(ctx, &e, sizeof(e));
+    count_utxocache_event(UTXOCACHE_UNCACHE);
     return 0;
 };
 
 int trace_utxocache_spent(struct pt_regs *ctx) {
-    struct utxo_cache_event e = {};
-    e.event = UTXOCACHE_SPENT;
-    perf_utxocache_events.perf_submit(ctx, &e, sizeof(e));
+    count_utxocache_event(UTXOCACHE_SPENT);
     return 0;
 };
@@ -1,5 +1,8 @@
+#define BLOCK_HASH_LENGTH 32
+
 struct block_connected
 {
+    u8      hash[BLOCK_HASH_LENGTH];
     int32_t   height;
     u64     transactions;
     int32_t   inputs;
@@ -12,6 +15,7 @@ BPF_PERF_OUTPUT(perf_block_connected);
 int trace_block_connected(struct pt_regs *ctx) {
     struct block_connected bc = {};
 
+    bpf_usdt_readarg_p(1, ctx, &bc.hash, BLOCK_HASH_LENGTH);
     bpf_usdt_readarg(2, ctx, &bc.height);
     bpf_usdt_readarg(3, ctx, &bc.transactions);
     bpf_usdt_readarg(4, ctx, &bc.inputs);
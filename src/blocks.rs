@@ -0,0 +1,92 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use serde::Serialize;
+
+use crate::types::{self, BlockConnected};
+
+pub const DEFAULT_RECENT_BLOCKS: usize = 100;
+
+lazy_static! {
+    /// The most recently connected blocks of all nodes.
+    pub static ref RECENT_BLOCKS: Mutex<BlockLog> = Mutex::new(BlockLog::new(DEFAULT_RECENT_BLOCKS));
+}
+
+/// A connected block as reported by the validation:block_connected
+/// tracepoint.
+#[derive(Debug, Clone, Serialize)]
+pub struct BlockRecord {
+    pub node: String,
+    /// UNIX epoch timestamp in seconds of when we observed the block.
+    pub timestamp: u64,
+    /// The block hash as hex in the usual (reversed) byte order.
+    pub hash: String,
+    pub height: i32,
+    pub transactions: u64,
+    pub inputs: i32,
+    pub sigops: u64,
+    /// Time it took to connect the block in microseconds (µs).
+    pub connection_time: u64,
+}
+
+impl BlockRecord {
+    pub fn new(node: &str, timestamp: u64, block: &BlockConnected) -> BlockRecord {
+        BlockRecord {
+            node: node.to_string(),
+            timestamp,
+            hash: types::reversed_hex(&block.hash),
+            height: block.height,
+            transactions: block.transactions,
+            inputs: block.inputs,
+            sigops: block.sigops,
+            connection_time: block.connection_time,
+        }
+    }
+}
+
+/// A ring buffer of the last connected blocks.
+pub struct BlockLog {
+    capacity: usize,
+    blocks: VecDeque<BlockRecord>,
+}
+
+impl BlockLog {
+    pub fn new(capacity: usize) -> BlockLog {
+        BlockLog {
+            capacity,
+            blocks: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Sets the number of blocks kept, dropping the oldest blocks if needed.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.blocks.len() > capacity {
+            self.blocks.pop_front();
+        }
+    }
+
+    pub fn push(&mut self, block: BlockRecord) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.blocks.len() == self.capacity {
+            self.blocks.pop_front();
+        }
+        self.blocks.push_back(block);
+    }
+
+    /// Returns the blocks (of a node, if given) with the most recent block
+    /// first.
+    pub fn recent(&self, node: Option<&str>) -> Vec<&BlockRecord> {
+        self.blocks
+            .iter()
+            .rev()
+            .filter(|b| match node {
+                Some(n) => b.node == n,
+                None => true,
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,887 @@
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use log::LevelFilter;
+use serde::Deserialize;
+use structopt::StructOpt;
+
+use crate::blocks::DEFAULT_RECENT_BLOCKS;
+use crate::events::EVENT_KINDS;
+use crate::metrics::DEFAULT_BLOCK_CONNECTION_DURATION_BUCKETS;
+use crate::probes::{ProbeGroup, ALL_PROBE_GROUPS};
+use crate::push::{self, PushTarget, Url};
+use crate::reader::DEFAULT_PERF_BUFFER_PAGE_COUNT;
+use crate::sinks::{Destination, Protocol};
+use crate::target::TargetProcess;
+
+const DEFAULT_LISTEN_ADDRESS: &str = "localhost:8282";
+const DEFAULT_LOG_LEVEL: &str = "info";
+const DEFAULT_NODE_NAME: &str = "bitcoind";
+const DEFAULT_POLL_TIMEOUT_MS: u64 = 1000;
+const DEFAULT_PUSH_INTERVAL_SECS: u64 = 15;
+const DEFAULT_PUSH_JOB: &str = "bitcoind-observer";
+const DEFAULT_SINK_INTERVAL_SECS: u64 = 10;
+
+/// Command-line options. Each option can also be set with an environment
+/// variable or in the TOML config file. Flags take precedence over
+/// environment variables, which take precedence over the config file.
+/// Multiple bitcoind nodes and the output sinks can only be configured in
+/// the config file.
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "bitcoind-observer",
+    about = "A Prometheus metric exporter for Bitcoin Core based on USDT and eBPF."
+)]
+pub struct Opt {
+    /// Path to the bitcoind binary to attach the tracepoints to. Optional
+    /// when attaching by PID.
+    #[structopt(long, env = "BITCOIND_OBSERVER_BITCOIND_PATH", parse(from_os_str))]
+    pub bitcoind_path: Option<PathBuf>,
+
+    /// Only attach to the bitcoind process with this PID.
+    #[structopt(long, env = "BITCOIND_OBSERVER_PID")]
+    pub pid: Option<i32>,
+
+    /// Only attach to the bitcoind process with the PID in this pidfile.
+    /// Re-attaches when bitcoind restarts with a new PID.
+    #[structopt(long, env = "BITCOIND_OBSERVER_PIDFILE", parse(from_os_str))]
+    pub pidfile: Option<PathBuf>,
+
+    /// Name of the bitcoind node used in the `node` metric label
+    /// [default: bitcoind].
+    #[structopt(long, env = "BITCOIND_OBSERVER_NODE_NAME")]
+    pub node_name: Option<String>,
+
+    /// Address the metric server listens on [default: localhost:8282].
+    #[structopt(long, env = "BITCOIND_OBSERVER_LISTEN")]
+    pub listen: Option<String>,
+
+    /// Path to a web config file enabling TLS and authentication for the
+    /// metric server, in the format of the Prometheus exporter-toolkit.
+    /// Reloaded on SIGHUP.
+    #[structopt(long, env = "BITCOIND_OBSERVER_WEB_CONFIG_FILE", parse(from_os_str))]
+    pub web_config_file: Option<PathBuf>,
+
+    /// Periodically push the metrics to the Pushgateway with this base URL,
+    /// e.g. http://pushgateway:9091. Credentials in the URL are sent with
+    /// basic auth.
+    #[structopt(long, env = "BITCOIND_OBSERVER_PUSH_GATEWAY")]
+    pub push_gateway: Option<String>,
+
+    /// Periodically push the metrics to the Prometheus remote-write endpoint
+    /// with this URL, e.g. http://prometheus:9090/api/v1/write. Credentials
+    /// in the URL are sent with basic auth.
+    #[structopt(long, env = "BITCOIND_OBSERVER_REMOTE_WRITE")]
+    pub remote_write: Option<String>,
+
+    /// Seconds between pushes of the metrics [default: 15].
+    #[structopt(long, env = "BITCOIND_OBSERVER_PUSH_INTERVAL")]
+    pub push_interval: Option<u64>,
+
+    /// The job label of pushed metrics [default: bitcoind-observer].
+    #[structopt(long, env = "BITCOIND_OBSERVER_PUSH_JOB")]
+    pub push_job: Option<String>,
+
+    /// The instance label of pushed metrics [default: the hostname].
+    #[structopt(long, env = "BITCOIND_OBSERVER_PUSH_INSTANCE")]
+    pub push_instance: Option<String>,
+
+    /// Log level: off, error, warn, info, debug or trace [default: info].
+    #[structopt(long, env = "BITCOIND_OBSERVER_LOG_LEVEL")]
+    pub log_level: Option<String>,
+
+    /// Comma-separated list of probe groups to enable: p2p, validation,
+    /// utxocache and/or mempool [default: all].
+    #[structopt(long, env = "BITCOIND_OBSERVER_PROBES", use_delimiter = true)]
+    pub probes: Option<Vec<String>>,
+
+    /// Comma-separated list of histogram buckets for the block connection
+    /// duration in seconds [default: 0.001 to 30].
+    #[structopt(
+        long,
+        env = "BITCOIND_OBSERVER_BLOCK_CONNECTION_BUCKETS",
+        use_delimiter = true
+    )]
+    pub block_connection_buckets: Option<Vec<f64>>,
+
+    /// Number of recently connected blocks served on /blocks [default: 100].
+    #[structopt(long, env = "BITCOIND_OBSERVER_RECENT_BLOCKS")]
+    pub recent_blocks: Option<usize>,
+
+    /// Maximum time in milliseconds to wait for new events before checking
+    /// if the traced bitcoind process is still running [default: 1000].
+    #[structopt(long, env = "BITCOIND_OBSERVER_POLL_TIMEOUT")]
+    pub poll_timeout: Option<u64>,
+
+    /// Number of memory pages per CPU for each perf buffer. Must be a power
+    /// of two. Increase it if events are lost, e.g. during IBD [default: 64].
+    #[structopt(long, env = "BITCOIND_OBSERVER_PERF_BUFFER_PAGES")]
+    pub perf_buffer_pages: Option<i32>,
+
+    /// Expose P2P traffic metrics per peer for up to this many peers. Each
+    /// peer adds a time series per metric [default: 0, disabled].
+    #[structopt(long, env = "BITCOIND_OBSERVER_PEER_METRICS")]
+    pub peer_metrics: Option<usize>,
+
+    /// Record the raw events of all nodes to this file. The recording can
+    /// be replayed later with --replay.
+    #[structopt(long, env = "BITCOIND_OBSERVER_RECORD", parse(from_os_str))]
+    pub record: Option<PathBuf>,
+
+    /// Replay the events recorded with --record from this file instead of
+    /// tracing bitcoind. Needs neither root nor a running bitcoind.
+    #[structopt(long, env = "BITCOIND_OBSERVER_REPLAY", parse(from_os_str))]
+    pub replay: Option<PathBuf>,
+
+    /// Speed of the replay relative to the recording, e.g. 10 to replay ten
+    /// times faster. 0 replays as fast as possible [default: 1].
+    #[structopt(long, env = "BITCOIND_OBSERVER_REPLAY_SPEED")]
+    pub replay_speed: Option<f64>,
+
+    /// Path to a TOML config file.
+    #[structopt(short, long, env = "BITCOIND_OBSERVER_CONFIG", parse(from_os_str))]
+    pub config: Option<PathBuf>,
+}
+
+/// Options read from the TOML config file. Keys use the same names as the
+/// command-line flags with underscores instead of dashes. Multiple nodes can
+/// be configured with `[[node]]` tables instead of the top-level
+/// bitcoind_path, pid, pidfile and node_name keys.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    bitcoind_path: Option<PathBuf>,
+    pid: Option<i32>,
+    pidfile: Option<PathBuf>,
+    node_name: Option<String>,
+    listen: Option<String>,
+    web_config_file: Option<PathBuf>,
+    push_gateway: Option<String>,
+    remote_write: Option<String>,
+    push_interval: Option<u64>,
+    push_job: Option<String>,
+    push_instance: Option<String>,
+    log_level: Option<String>,
+    probes: Option<Vec<String>>,
+    block_connection_buckets: Option<Vec<f64>>,
+    recent_blocks: Option<usize>,
+    poll_timeout: Option<u64>,
+    perf_buffer_pages: Option<i32>,
+    peer_metrics: Option<usize>,
+    record: Option<PathBuf>,
+    replay: Option<PathBuf>,
+    replay_speed: Option<f64>,
+    #[serde(rename = "node")]
+    nodes: Option<Vec<FileNodeConfig>>,
+    #[serde(rename = "sink")]
+    sinks: Option<Vec<FileSinkConfig>>,
+}
+
+/// A `[[node]]` table in the TOML config file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileNodeConfig {
+    name: String,
+    bitcoind_path: Option<PathBuf>,
+    pid: Option<i32>,
+    pidfile: Option<PathBuf>,
+}
+
+/// A `[[sink]]` table in the TOML config file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileSinkConfig {
+    name: String,
+    protocol: String,
+    url: String,
+    token: Option<String>,
+    interval: Option<u64>,
+    #[serde(default)]
+    events: Vec<String>,
+}
+
+impl FileConfig {
+    fn read(path: &Path) -> Result<FileConfig, ConfigError> {
+        let contents =
+            fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+        toml::from_str(&contents).map_err(|e| ConfigError::Toml(path.to_path_buf(), e))
+    }
+}
+
+/// A bitcoind node to observe.
+#[derive(Debug, Clone)]
+pub struct NodeConfig {
+    /// The name used in the `node` metric label.
+    pub name: String,
+    pub bitcoind_path: Option<PathBuf>,
+    pub target_process: TargetProcess,
+}
+
+impl NodeConfig {
+    fn new(
+        name: String,
+        bitcoind_path: Option<PathBuf>,
+        pid: Option<i32>,
+        pidfile: Option<PathBuf>,
+    ) -> Result<NodeConfig, ConfigError> {
+        if name.is_empty() {
+            return Err(ConfigError::Invalid {
+                option: "node-name",
+                value: name,
+                reason: String::from("must not be empty"),
+            });
+        }
+
+        if let Some(ref path) = bitcoind_path {
+            if !path.is_file() {
+                return Err(ConfigError::Invalid {
+                    option: "bitcoind-path",
+                    value: path.display().to_string(),
+                    reason: String::from("not a file"),
+                });
+            }
+        }
+
+        let target_process = match (pid, pidfile) {
+            (Some(_), Some(_)) => return Err(ConfigError::Conflict("pid", "pidfile")),
+            (Some(pid), None) => TargetProcess::Pid(pid),
+            (None, Some(pidfile)) => TargetProcess::PidFile(pidfile),
+            (None, None) => {
+                if bitcoind_path.is_none() {
+                    return Err(ConfigError::Missing("bitcoind-path"));
+                }
+                TargetProcess::All
+            }
+        };
+
+        Ok(NodeConfig {
+            name,
+            bitcoind_path,
+            target_process,
+        })
+    }
+}
+
+/// How the bitcoind nodes are traced. The same for all nodes.
+#[derive(Debug, Clone)]
+pub struct TracerConfig {
+    pub probe_groups: Vec<ProbeGroup>,
+    pub poll_timeout: Duration,
+    pub perf_buffer_pages: i32,
+    /// Maximum number of peers with per-peer metrics. 0 if disabled.
+    pub peer_metrics: usize,
+}
+
+/// A recording to replay instead of tracing bitcoind.
+#[derive(Debug, Clone)]
+pub struct ReplayConfig {
+    pub path: PathBuf,
+    /// Factor the replay is sped up by. 0 if as fast as possible.
+    pub speed: f64,
+}
+
+/// An output sink emitting metric snapshots and/or events.
+#[derive(Debug, Clone)]
+pub struct SinkConfig {
+    pub name: String,
+    pub protocol: Protocol,
+    pub destination: Destination,
+    /// The interval of the metric snapshots. None if no metrics are emitted.
+    pub interval: Option<Duration>,
+    /// The kinds of the emitted events.
+    pub events: Vec<String>,
+}
+
+impl SinkConfig {
+    fn new(file: FileSinkConfig) -> Result<SinkConfig, ConfigError> {
+        if file.name.is_empty() {
+            return Err(ConfigError::Invalid {
+                option: "sink name",
+                value: file.name,
+                reason: String::from("must not be empty"),
+            });
+        }
+
+        let protocol = match Protocol::from_str(&file.protocol) {
+            Ok(protocol) => protocol,
+            Err(reason) => {
+                return Err(ConfigError::Invalid {
+                    option: "sink protocol",
+                    value: file.protocol,
+                    reason,
+                })
+            }
+        };
+
+        let destination = if let Some(address) = file.url.strip_prefix("udp://") {
+            if let Err(e) = address.to_socket_addrs() {
+                return Err(ConfigError::Invalid {
+                    option: "sink url",
+                    value: file.url,
+                    reason: e.to_string(),
+                });
+            }
+            if file.token.is_some() {
+                return Err(ConfigError::Conflict("sink token", "udp:// sink url"));
+            }
+            Destination::Udp(address.to_string())
+        } else {
+            if protocol == Protocol::Statsd {
+                return Err(ConfigError::Invalid {
+                    option: "sink url",
+                    value: file.url,
+                    reason: String::from("StatsD is only sent over UDP (udp://host:port)"),
+                });
+            }
+            Destination::Http {
+                url: parse_url("sink url", file.url)?,
+                token: file.token,
+            }
+        };
+
+        for kind in file.events.iter() {
+            if !EVENT_KINDS.contains(&kind.as_str()) {
+                return Err(ConfigError::Invalid {
+                    option: "sink events",
+                    value: kind.clone(),
+                    reason: format!("expected one of {}", EVENT_KINDS.join(", ")),
+                });
+            }
+        }
+
+        let interval = file.interval.unwrap_or(DEFAULT_SINK_INTERVAL_SECS);
+        if interval == 0 && file.events.is_empty() {
+            return Err(ConfigError::Invalid {
+                option: "sink",
+                value: file.name,
+                reason: String::from("emits neither metrics (interval 0) nor events"),
+            });
+        }
+
+        Ok(SinkConfig {
+            name: file.name,
+            protocol,
+            destination,
+            interval: match interval {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            events: file.events,
+        })
+    }
+}
+
+/// Where and how often the metrics are pushed.
+#[derive(Debug, Clone)]
+pub struct PushConfig {
+    pub targets: Vec<PushTarget>,
+    pub interval: Duration,
+    pub job: String,
+    pub instance: String,
+}
+
+/// The validated bitcoind-observer configuration.
+#[derive(Debug)]
+pub struct Config {
+    /// The nodes to trace. Empty when replaying a recording.
+    pub nodes: Vec<NodeConfig>,
+    pub listen: String,
+    /// The exporter-toolkit web config file of the metric server.
+    pub web_config_file: Option<PathBuf>,
+    /// None if the metrics aren't pushed.
+    pub push: Option<PushConfig>,
+    pub sinks: Vec<SinkConfig>,
+    pub log_level: LevelFilter,
+    pub tracer: TracerConfig,
+    pub block_connection_buckets: Vec<f64>,
+    pub recent_blocks: usize,
+    /// File to record the raw events to.
+    pub record: Option<PathBuf>,
+    pub replay: Option<ReplayConfig>,
+}
+
+impl Config {
+    /// Loads the configuration from the command-line arguments, the
+    /// environment and the config file (if one is specified).
+    pub fn load() -> Result<Config, ConfigError> {
+        Config::from_opt(Opt::from_args())
+    }
+
+    fn from_opt(opt: Opt) -> Result<Config, ConfigError> {
+        let file = match opt.config {
+            Some(ref path) => FileConfig::read(path)?,
+            None => FileConfig::default(),
+        };
+
+        let record = opt.record.or(file.record);
+        let replay = match opt.replay.or(file.replay) {
+            Some(path) => {
+                if record.is_some() {
+                    return Err(ConfigError::Conflict("record", "replay"));
+                }
+                let speed = opt.replay_speed.or(file.replay_speed).unwrap_or(1.0);
+                if !speed.is_finite() || speed < 0.0 {
+                    return Err(ConfigError::Invalid {
+                        option: "replay-speed",
+                        value: speed.to_string(),
+                        reason: String::from("must be 0 or a positive number"),
+                    });
+                }
+                Some(ReplayConfig { path, speed })
+            }
+            None => None,
+        };
+
+        let opt_has_target =
+            opt.bitcoind_path.is_some() || opt.pid.is_some() || opt.pidfile.is_some();
+        let file_has_target =
+            file.bitcoind_path.is_some() || file.pid.is_some() || file.pidfile.is_some();
+        let nodes = match file.nodes {
+            // The nodes are taken from the recording.
+            _ if replay.is_some() => vec![],
+            Some(file_nodes) if !opt_has_target => {
+                if file_has_target {
+                    return Err(ConfigError::Conflict(
+                        "bitcoind_path, pid or pidfile",
+                        "[[node]] tables",
+                    ));
+                }
+                let mut nodes: Vec<NodeConfig> = vec![];
+                for n in file_nodes {
+                    if nodes.iter().any(|other| other.name == n.name) {
+                        return Err(ConfigError::Invalid {
+                            option: "node name",
+                            value: n.name,
+                            reason: String::from("used for more than one node"),
+                        });
+                    }
+                    nodes.push(NodeConfig::new(n.name, n.bitcoind_path, n.pid, n.pidfile)?);
+                }
+                if nodes.is_empty() {
+                    return Err(ConfigError::Missing("bitcoind-path"));
+                }
+                nodes
+            }
+            _ => vec![NodeConfig::new(
+                opt.node_name
+                    .or(file.node_name)
+                    .unwrap_or_else(|| String::from(DEFAULT_NODE_NAME)),
+                opt.bitcoind_path.or(file.bitcoind_path),
+                opt.pid.or(file.pid),
+                opt.pidfile.or(file.pidfile),
+            )?],
+        };
+
+        let listen = opt
+            .listen
+            .or(file.listen)
+            .unwrap_or_else(|| String::from(DEFAULT_LISTEN_ADDRESS));
+        if let Err(e) = listen.to_socket_addrs() {
+            return Err(ConfigError::Invalid {
+                option: "listen",
+                value: listen,
+                reason: e.to_string(),
+            });
+        }
+
+        let mut push_targets = vec![];
+        if let Some(url) = opt.push_gateway.or(file.push_gateway) {
+            push_targets.push(PushTarget::Pushgateway(parse_url("push-gateway", url)?));
+        }
+        if let Some(url) = opt.remote_write.or(file.remote_write) {
+            push_targets.push(PushTarget::RemoteWrite(parse_url("remote-write", url)?));
+        }
+        let push = if push_targets.is_empty() {
+            None
+        } else {
+            let interval = opt
+                .push_interval
+                .or(file.push_interval)
+                .unwrap_or(DEFAULT_PUSH_INTERVAL_SECS);
+            if interval == 0 {
+                return Err(ConfigError::Invalid {
+                    option: "push-interval",
+                    value: interval.to_string(),
+                    reason: String::from("must be greater than 0"),
+                });
+            }
+            let job = opt
+                .push_job
+                .or(file.push_job)
+                .unwrap_or_else(|| String::from(DEFAULT_PUSH_JOB));
+            if job.is_empty() {
+                return Err(ConfigError::Invalid {
+                    option: "push-job",
+                    value: job,
+                    reason: String::from("must not be empty"),
+                });
+            }
+            let instance = match opt.push_instance.or(file.push_instance) {
+                Some(instance) => instance,
+                None => push::hostname().ok_or(ConfigError::Missing("push-instance"))?,
+            };
+            Some(PushConfig {
+                targets: push_targets,
+                interval: Duration::from_secs(interval),
+                job,
+                instance,
+            })
+        };
+
+        let mut sinks: Vec<SinkConfig> = vec![];
+        for sink in file.sinks.unwrap_or_default() {
+            if sinks.iter().any(|other| other.name == sink.name) {
+                return Err(ConfigError::Invalid {
+                    option: "sink name",
+                    value: sink.name,
+                    reason: String::from("used for more than one sink"),
+                });
+            }
+            sinks.push(SinkConfig::new(sink)?);
+        }
+
+        let log_level_str = opt
+            .log_level
+            .or(file.log_level)
+            .unwrap_or_else(|| String::from(DEFAULT_LOG_LEVEL));
+        let log_level = match LevelFilter::from_str(&log_level_str) {
+            Ok(level) => level,
+            Err(_) => {
+                return Err(ConfigError::Invalid {
+                    option: "log-level",
+                    value: log_level_str,
+                    reason: String::from("expected one of off, error, warn, info, debug or trace"),
+                })
+            }
+        };
+
+        let probe_groups = match opt.probes.or(file.probes) {
+            Some(names) => {
+                let mut groups = vec![];
+                for name in names.iter() {
+                    match ProbeGroup::from_str(name.trim()) {
+                        Ok(group) => groups.push(group),
+                        Err(reason) => {
+                            return Err(ConfigError::Invalid {
+                                option: "probes",
+                                value: name.clone(),
+                                reason,
+                            })
+                        }
+                    }
+                }
+                groups
+            }
+            None => ALL_PROBE_GROUPS.to_vec(),
+        };
+
+        let block_connection_buckets = opt
+            .block_connection_buckets
+            .or(file.block_connection_buckets)
+            .unwrap_or_else(|| DEFAULT_BLOCK_CONNECTION_DURATION_BUCKETS.to_vec());
+        if block_connection_buckets.is_empty()
+            || block_connection_buckets.iter().any(|b| !b.is_finite())
+            || block_connection_buckets.windows(2).any(|w| w[0] >= w[1])
+        {
+            return Err(ConfigError::Invalid {
+                option: "block-connection-buckets",
+                value: format!("{:?}", block_connection_buckets),
+                reason: String::from(
+                    "expected a non-empty list of increasing, finite bucket bounds",
+                ),
+            });
+        }
+
+        let poll_timeout = opt
+            .poll_timeout
+            .or(file.poll_timeout)
+            .unwrap_or(DEFAULT_POLL_TIMEOUT_MS);
+        if poll_timeout == 0 {
+            return Err(ConfigError::Invalid {
+                option: "poll-timeout",
+                value: poll_timeout.to_string(),
+                reason: String::from("must be greater than 0"),
+            });
+        }
+
+        let perf_buffer_pages = opt
+            .perf_buffer_pages
+            .or(file.perf_buffer_pages)
+            .unwrap_or(DEFAULT_PERF_BUFFER_PAGE_COUNT);
+        if perf_buffer_pages <= 0 || perf_buffer_pages & (perf_buffer_pages - 1) != 0 {
+            return Err(ConfigError::Invalid {
+                option: "perf-buffer-pages",
+                value: perf_buffer_pages.to_string(),
+                reason: String::from("must be a power of two"),
+            });
+        }
+
+        Ok(Config {
+            nodes,
+            listen,
+            web_config_file: opt.web_config_file.or(file.web_config_file),
+            push,
+            sinks,
+            log_level,
+            tracer: TracerConfig {
+                probe_groups,
+                poll_timeout: Duration::from_millis(poll_timeout),
+                perf_buffer_pages,
+                peer_metrics: opt.peer_metrics.or(file.peer_metrics).unwrap_or(0),
+            },
+            block_connection_buckets,
+            recent_blocks: opt
+                .recent_blocks
+                .or(file.recent_blocks)
+                .unwrap_or(DEFAULT_RECENT_BLOCKS),
+            record,
+            replay,
+        })
+    }
+}
+
+fn parse_url(option: &'static str, url: String) -> Result<Url, ConfigError> {
+    match url.parse() {
+        Ok(url) => Ok(url),
+        Err(reason) => Err(ConfigError::Invalid {
+            option,
+            value: url,
+            reason,
+        }),
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Toml(PathBuf, toml::de::Error),
+    Missing(&'static str),
+    Conflict(&'static str, &'static str),
+    Invalid {
+        option: &'static str,
+        value: String,
+        reason: String,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => {
+                write!(f, "could not read config file {}: {}", path.display(), e)
+            }
+            ConfigError::Toml(path, e) => {
+                write!(f, "could not parse config file {}: {}", path.display(), e)
+            }
+            ConfigError::Missing(option) => write!(
+                f,
+                "no {} provided (set it with --{} or in the config file)",
+                option, option
+            ),
+            ConfigError::Conflict(a, b) => write!(f, "{} and {} can't be used together", a, b),
+            ConfigError::Invalid {
+                option,
+                value,
+                reason,
+            } => write!(f, "invalid {} '{}': {}", option, value, reason),
+        }
+    }
+}
+
+impl error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            ConfigError::Io(_, ref e) => Some(e),
+            ConfigError::Toml(_, ref e) => Some(e),
+            ConfigError::Missing(_) => None,
+            ConfigError::Conflict(_, _) => None,
+            ConfigError::Invalid { .. } => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::sync::Mutex;
+
+    /// Serializes the tests, as the options are also read from the
+    /// environment.
+    static ENV: Mutex<()> = Mutex::new(());
+
+    fn load(args: &[&str]) -> Result<Config, ConfigError> {
+        let args = std::iter::once("bitcoind-observer").chain(args.iter().copied());
+        Config::from_opt(Opt::from_iter_safe(args).unwrap())
+    }
+
+    fn write_config(name: &str, contents: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!(
+            "bitcoind-observer-test-config-{}-{}.toml",
+            name,
+            std::process::id()
+        ));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn invalid_option(result: Result<Config, ConfigError>) -> &'static str {
+        match result {
+            Err(ConfigError::Invalid { option, .. }) => option,
+            result => panic!("expected an invalid option, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn flags_take_precedence_over_env_over_file() {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        let path = write_config(
+            "precedence",
+            "pid = 1\n\
+             listen = \"127.0.0.1:1\"\n\
+             log_level = \"error\"\n\
+             recent_blocks = 5\n",
+        );
+        env::set_var("BITCOIND_OBSERVER_LISTEN", "127.0.0.1:2");
+        env::set_var("BITCOIND_OBSERVER_LOG_LEVEL", "warn");
+        let config = load(&[
+            "--config",
+            path.to_str().unwrap(),
+            "--listen",
+            "127.0.0.1:3",
+        ]);
+        env::remove_var("BITCOIND_OBSERVER_LISTEN");
+        env::remove_var("BITCOIND_OBSERVER_LOG_LEVEL");
+        fs::remove_file(&path).unwrap();
+
+        let config = config.unwrap();
+        assert_eq!(config.listen, "127.0.0.1:3");
+        assert_eq!(config.log_level, LevelFilter::Warn);
+        assert_eq!(config.recent_blocks, 5);
+        assert!(matches!(
+            config.nodes[0].target_process,
+            TargetProcess::Pid(1)
+        ));
+        // Not set anywhere.
+        assert_eq!(config.tracer.poll_timeout, Duration::from_millis(1000));
+    }
+
+    #[test]
+    fn rejects_invalid_buckets() {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        for buckets in ["nan", "0.1,nan,1", "1,inf", "-inf,1", "1,0.5", "1,1"].iter() {
+            let flag = format!("--block-connection-buckets={}", buckets);
+            let result = load(&["--pid", "1", &flag]);
+            assert_eq!(invalid_option(result), "block-connection-buckets");
+        }
+        let path = write_config("buckets", "pid = 1\nblock_connection_buckets = []\n");
+        let result = load(&["--config", path.to_str().unwrap()]);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(invalid_option(result), "block-connection-buckets");
+
+        let config = load(&["--pid", "1", "--block-connection-buckets", "0.5,1,2"]).unwrap();
+        assert_eq!(config.block_connection_buckets, vec![0.5, 1.0, 2.0]);
+    }
+
+    #[test]
+    fn rejects_invalid_options() {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        let cases: &[(&[&str], &str)] = &[
+            (&["--pid", "1", "--node-name", ""], "node-name"),
+            (&["--pid", "1", "--listen", "nowhere"], "listen"),
+            (&["--pid", "1", "--log-level", "loud"], "log-level"),
+            (&["--pid", "1", "--probes", "p2p,net"], "probes"),
+            (&["--pid", "1", "--poll-timeout", "0"], "poll-timeout"),
+            (
+                &["--pid", "1", "--perf-buffer-pages", "3"],
+                "perf-buffer-pages",
+            ),
+            (
+                &["--pid", "1", "--push-gateway", "ftp://gw"],
+                "push-gateway",
+            ),
+            (
+                &[
+                    "--pid",
+                    "1",
+                    "--push-gateway",
+                    "http://gw",
+                    "--push-interval",
+                    "0",
+                ],
+                "push-interval",
+            ),
+            (&["--replay", "r", "--replay-speed=-1"], "replay-speed"),
+            (
+                &["--bitcoind-path", "/nonexistent/bitcoind"],
+                "bitcoind-path",
+            ),
+        ];
+        for (args, option) in cases.iter() {
+            assert_eq!(invalid_option(load(args)), *option, "{:?}", args);
+        }
+
+        assert!(matches!(
+            load(&[]),
+            Err(ConfigError::Missing("bitcoind-path"))
+        ));
+        assert!(matches!(
+            load(&["--pid", "1", "--pidfile", "bitcoind.pid"]),
+            Err(ConfigError::Conflict("pid", "pidfile"))
+        ));
+        assert!(matches!(
+            load(&["--record", "a", "--replay", "b"]),
+            Err(ConfigError::Conflict("record", "replay"))
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_config_files() {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        assert!(matches!(
+            load(&["--config", "/nonexistent/bitcoind-observer.toml"]),
+            Err(ConfigError::Io(_, _))
+        ));
+
+        let cases = [
+            ("unknown", "pid = 1\nunknown_option = true\n", None),
+            (
+                "nodes",
+                "[[node]]\nname = \"a\"\npid = 1\n[[node]]\nname = \"a\"\npid = 2\n",
+                Some("node name"),
+            ),
+            (
+                "conflict",
+                "pid = 1\n[[node]]\nname = \"a\"\npid = 2\n",
+                None,
+            ),
+            (
+                "statsd",
+                "pid = 1\n[[sink]]\nname = \"s\"\nprotocol = \"statsd\"\nurl = \"http://s\"\n",
+                Some("sink url"),
+            ),
+            (
+                "sink",
+                "pid = 1\n[[sink]]\nname = \"s\"\nprotocol = \"influx\"\nurl = \"udp://127.0.0.1:8089\"\ninterval = 0\n",
+                Some("sink"),
+            ),
+        ];
+        for (name, contents, option) in cases.iter() {
+            let path = write_config(name, contents);
+            let result = load(&["--config", path.to_str().unwrap()]);
+            fs::remove_file(&path).unwrap();
+            match (result, option) {
+                (result, Some(option)) => assert_eq!(invalid_option(result), *option),
+                (Err(ConfigError::Toml(_, _)), None) | (Err(ConfigError::Conflict(_, _)), None) => {
+                }
+                (result, None) => panic!("{}: unexpected result {:?}", name, result),
+            }
+        }
+    }
+}
@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use crate::metrics;
+
+struct OpenConnection {
+    conn_type: String,
+    network: &'static str,
+    opened: Instant,
+}
+
+/// Tracks the open P2P connections of a node by peer id. Connections are
+/// matched with their close event to maintain the open-since-attach gauge and
+/// the connection lifetime histogram. Connections that were already open
+/// before we attached are ignored: their close events only count as closed
+/// connections. The open-since-attach gauge thus only counts the connections
+/// opened since attaching.
+///
+/// The exact number of open connections per direction is taken from the
+/// number of existing connections bitcoind reports with each new connection,
+/// and decremented on close.
+pub struct ConnectionTracker {
+    node: String,
+    open: HashMap<u64, OpenConnection>,
+    open_per_direction: HashMap<&'static str, u64>,
+}
+
+impl ConnectionTracker {
+    pub fn new(node: String) -> ConnectionTracker {
+        ConnectionTracker {
+            node,
+            open: HashMap::new(),
+            open_per_direction: HashMap::new(),
+        }
+    }
+
+    pub fn opened(&mut self, id: u64, conn_type: String, network: &'static str, existing: u64) {
+        let direction = direction(&conn_type);
+        self.set_open(direction, existing + 1);
+        let labels = [self.node.as_str(), conn_type.as_str(), network];
+        metrics::P2P_CONNECTIONS_OPENED
+            .with_label_values(&labels)
+            .inc();
+        metrics::P2P_CONNECTIONS_OPEN_SINCE_ATTACH
+            .with_label_values(&labels)
+            .inc();
+        let previous = self.open.insert(
+            id,
+            OpenConnection {
+                conn_type,
+                network,
+                opened: Instant::now(),
+            },
+        );
+        if let Some(previous) = previous {
+            self.remove_from_gauge(&previous);
+        }
+    }
+
+    pub fn closed(&mut self, id: u64, conn_type: &str, network: &'static str) {
+        metrics::P2P_CONNECTIONS_CLOSED
+            .with_label_values(&[&self.node, conn_type, network])
+            .inc();
+        let direction = direction(conn_type);
+        if let Some(&open) = self.open_per_direction.get(direction) {
+            self.set_open(direction, open.saturating_sub(1));
+        }
+        if let Some(conn) = self.open.remove(&id) {
+            self.remove_from_gauge(&conn);
+            metrics::P2P_CONNECTION_LIFETIME
+                .with_label_values(&[&self.node, &conn.conn_type, conn.network])
+                .observe(conn.opened.elapsed().as_secs_f64());
+        }
+    }
+
+    /// Forgets about all open connections. Used when detaching from bitcoind,
+    /// as we won't see the close events for the still open connections.
+    pub fn clear(&mut self) {
+        for conn in self.open.values() {
+            self.remove_from_gauge(conn);
+        }
+        self.open.clear();
+        for direction in self.open_per_direction.keys() {
+            let _ = metrics::P2P_CONNECTIONS_OPEN.remove_label_values(&[&self.node, direction]);
+        }
+        self.open_per_direction.clear();
+    }
+
+    fn set_open(&mut self, direction: &'static str, open: u64) {
+        self.open_per_direction.insert(direction, open);
+        metrics::P2P_CONNECTIONS_OPEN
+            .with_label_values(&[&self.node, direction])
+            .set(open as i64);
+    }
+
+    fn remove_from_gauge(&self, conn: &OpenConnection) {
+        metrics::P2P_CONNECTIONS_OPEN_SINCE_ATTACH
+            .with_label_values(&[&self.node, &conn.conn_type, conn.network])
+            .dec();
+    }
+}
+
+/// The direction of a connection by its connection type.
+fn direction(conn_type: &str) -> &'static str {
+    if conn_type == "inbound" {
+        "inbound"
+    } else {
+        "outbound"
+    }
+}
@@ -0,0 +1,81 @@
+use std::fs;
+use std::io;
+use std::os::raw::{c_int, c_void};
+
+use bcc::table::Table;
+use bcc::BccError;
+use bcc_sys::bccapi::bpf_lookup_elem;
+
+const CPU_POSSIBLE: &str = "/sys/devices/system/cpu/possible";
+
+/// Counters aggregated in the kernel in a BPF_PERCPU_ARRAY with u64 values.
+/// Each CPU increments its own copy of the counters, which avoids atomic
+/// operations in the eBPF programs. Userspace sums up the copies when
+/// reading the counters.
+pub struct PerCpuCounters {
+    fd: c_int,
+    possible_cpus: usize,
+    /// The sums from the last read, used to calculate the increments.
+    last: Vec<u64>,
+}
+
+impl PerCpuCounters {
+    pub fn new(mut table: Table, len: usize) -> Result<PerCpuCounters, BccError> {
+        if table.key_size() != 4 || table.leaf_size() != 8 {
+            return Err(BccError::TableInvalidSize);
+        }
+        Ok(PerCpuCounters {
+            fd: table.fd(),
+            possible_cpus: possible_cpus()?,
+            last: vec![0; len],
+        })
+    }
+
+    /// Returns how much each counter increased since the last read.
+    pub fn increments(&mut self) -> io::Result<Vec<u64>> {
+        let mut increments = Vec::with_capacity(self.last.len());
+        for index in 0..self.last.len() {
+            let sum = self.sum(index as u32)?;
+            increments.push(sum.saturating_sub(self.last[index]));
+            self.last[index] = sum;
+        }
+        Ok(increments)
+    }
+
+    /// Sums up the per-CPU values of a counter. A lookup in a per-CPU map
+    /// returns one value for each possible CPU.
+    fn sum(&self, index: u32) -> io::Result<u64> {
+        let mut key = index;
+        let mut values = vec![0u64; self.possible_cpus];
+        let res = unsafe {
+            bpf_lookup_elem(
+                self.fd,
+                &mut key as *mut u32 as *mut c_void,
+                values.as_mut_ptr() as *mut c_void,
+            )
+        };
+        if res != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(values.iter().sum())
+    }
+}
+
+/// Returns the number of possible CPUs, read from a range list like `0-7`
+/// or `0,2-3`.
+fn possible_cpus() -> io::Result<usize> {
+    let ranges = fs::read_to_string(CPU_POSSIBLE)?;
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid CPU range '{}' in {}", ranges.trim(), CPU_POSSIBLE),
+        )
+    };
+    let mut max = 0;
+    for range in ranges.trim().split(',') {
+        let last = range.rsplit('-').next().ok_or_else(invalid)?;
+        let last: usize = last.parse().map_err(|_| invalid())?;
+        max = max.max(last);
+    }
+    Ok(max + 1)
+}
@@ -0,0 +1,464 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::time;
+
+use lazy_static::lazy_static;
+use serde::Serialize;
+
+use crate::http;
+use crate::metrics;
+use crate::types::{
+    self, BlockConnected, ClosedConnection, MempoolAdded, MempoolRejected, MempoolRemoved,
+    MempoolReplaced, MisbehavingConnection, NewConnection, P2PMessage, UTXOCacheFlush,
+};
+
+/// Maximum number of concurrent event stream subscribers.
+pub const MAX_SUBSCRIBERS: usize = 16;
+
+/// Number of events buffered per subscriber. Events are dropped for slow
+/// subscribers once their buffer is full.
+const SUBSCRIBER_BUFFER: usize = 4096;
+
+/// The event kinds as used in the `kind` field and filter.
+pub const EVENT_KINDS: [&str; 13] = [
+    "inbound_message",
+    "outbound_message",
+    "inbound_connection",
+    "outbound_connection",
+    "closed_connection",
+    "evicted_connection",
+    "misbehaving_connection",
+    "block_connected",
+    "utxocache_flush",
+    "mempool_added",
+    "mempool_removed",
+    "mempool_replaced",
+    "mempool_rejected",
+];
+
+lazy_static! {
+    static ref SUBSCRIBERS: Mutex<Vec<Subscriber>> = Mutex::new(vec![]);
+}
+
+/// Number of subscribers. Checked before building an event, so that events
+/// are only serialized if someone is listening.
+static SUBSCRIBER_COUNT: AtomicUsize = AtomicUsize::new(0);
+static NEXT_SUBSCRIBER_ID: AtomicU64 = AtomicU64::new(0);
+
+/// A decoded tracepoint event of a node.
+#[derive(Debug, Clone, Serialize)]
+pub struct EventRecord {
+    pub node: String,
+    /// UNIX epoch timestamp in milliseconds of when we received the event.
+    pub timestamp_ms: u64,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Event {
+    InboundMessage(Message),
+    OutboundMessage(Message),
+    InboundConnection(Connection),
+    OutboundConnection(Connection),
+    ClosedConnection(Connection),
+    EvictedConnection(Connection),
+    MisbehavingConnection(Misbehaving),
+    BlockConnected(Block),
+    UtxocacheFlush(Flush),
+    MempoolAdded(MempoolTransaction),
+    MempoolRemoved(MempoolTransaction),
+    MempoolReplaced(MempoolReplacement),
+    MempoolRejected(MempoolTransaction),
+}
+
+impl Event {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Event::InboundMessage(_) => EVENT_KINDS[0],
+            Event::OutboundMessage(_) => EVENT_KINDS[1],
+            Event::InboundConnection(_) => EVENT_KINDS[2],
+            Event::OutboundConnection(_) => EVENT_KINDS[3],
+            Event::ClosedConnection(_) => EVENT_KINDS[4],
+            Event::EvictedConnection(_) => EVENT_KINDS[5],
+            Event::MisbehavingConnection(_) => EVENT_KINDS[6],
+            Event::BlockConnected(_) => EVENT_KINDS[7],
+            Event::UtxocacheFlush(_) => EVENT_KINDS[8],
+            Event::MempoolAdded(_) => EVENT_KINDS[9],
+            Event::MempoolRemoved(_) => EVENT_KINDS[10],
+            Event::MempoolReplaced(_) => EVENT_KINDS[11],
+            Event::MempoolRejected(_) => EVENT_KINDS[12],
+        }
+    }
+
+    fn msg_type(&self) -> Option<&str> {
+        match self {
+            Event::InboundMessage(m) | Event::OutboundMessage(m) => Some(&m.msg_type),
+            _ => None,
+        }
+    }
+
+    /// The peer id and, if known, the peer address of P2P events.
+    fn peer(&self) -> Option<(u64, Option<&str>)> {
+        match self {
+            Event::InboundMessage(m) | Event::OutboundMessage(m) => {
+                Some((m.peer_id, Some(&m.peer_addr)))
+            }
+            Event::InboundConnection(c)
+            | Event::OutboundConnection(c)
+            | Event::ClosedConnection(c)
+            | Event::EvictedConnection(c) => Some((c.peer_id, Some(&c.peer_addr))),
+            Event::MisbehavingConnection(m) => Some((m.peer_id, None)),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Message {
+    pub peer_id: u64,
+    pub peer_addr: String,
+    pub connection_type: String,
+    pub msg_type: String,
+    pub size: u64,
+}
+
+impl From<&P2PMessage> for Message {
+    fn from(msg: &P2PMessage) -> Message {
+        Message {
+            peer_id: msg.peer_id,
+            peer_addr: msg.get_peer_addr(),
+            connection_type: msg.get_peer_conn_type(),
+            msg_type: msg.get_msg_type(),
+            size: msg.msg_size,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Connection {
+    pub peer_id: u64,
+    pub peer_addr: String,
+    pub connection_type: String,
+    pub network: &'static str,
+    /// Number of existing inbound or outbound connections (new connections).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub existing: Option<u64>,
+    /// UNIX epoch timestamp in seconds of when the connection was established
+    /// (closed and evicted connections).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time_established: Option<u64>,
+}
+
+impl From<&NewConnection> for Connection {
+    fn from(conn: &NewConnection) -> Connection {
+        Connection {
+            peer_id: conn.id,
+            peer_addr: conn.get_addr(),
+            connection_type: conn.get_conn_type(),
+            network: types::network_name(conn.network),
+            existing: Some(conn.existing),
+            time_established: None,
+        }
+    }
+}
+
+impl From<&ClosedConnection> for Connection {
+    fn from(conn: &ClosedConnection) -> Connection {
+        Connection {
+            peer_id: conn.id,
+            peer_addr: conn.get_addr(),
+            connection_type: conn.get_conn_type(),
+            network: types::network_name(conn.network),
+            existing: None,
+            time_established: Some(conn.time_established),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Misbehaving {
+    pub peer_id: u64,
+    pub message: String,
+}
+
+impl From<&MisbehavingConnection> for Misbehaving {
+    fn from(m: &MisbehavingConnection) -> Misbehaving {
+        Misbehaving {
+            peer_id: m.id,
+            message: m.get_message(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Block {
+    pub hash: String,
+    pub height: i32,
+    pub transactions: u64,
+    pub inputs: i32,
+    pub sigops: u64,
+    /// Time it took to connect the block in microseconds (µs).
+    pub connection_time: u64,
+}
+
+impl From<&BlockConnected> for Block {
+    fn from(block: &BlockConnected) -> Block {
+        Block {
+            hash: types::reversed_hex(&block.hash),
+            height: block.height,
+            transactions: block.transactions,
+            inputs: block.inputs,
+            sigops: block.sigops,
+            connection_time: block.connection_time,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Flush {
+    /// Duration of the flush in microseconds (µs).
+    pub duration: u64,
+    pub mode: String,
+    pub coins_count: u64,
+    pub coins_memusage: u64,
+    pub for_prune: bool,
+}
+
+impl From<&UTXOCacheFlush> for Flush {
+    fn from(flush: &UTXOCacheFlush) -> Flush {
+        Flush {
+            duration: flush.duration,
+            mode: flush.flush_mode().to_string(),
+            coins_count: flush.coins_count,
+            coins_memusage: flush.coins_memusage,
+            for_prune: flush.flush_for_prune != 0,
+        }
+    }
+}
+
+/// A transaction added to, removed from or rejected from the mempool.
+#[derive(Debug, Clone, Serialize)]
+pub struct MempoolTransaction {
+    pub txid: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vsize: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fee: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl From<&MempoolAdded> for MempoolTransaction {
+    fn from(tx: &MempoolAdded) -> MempoolTransaction {
+        MempoolTransaction {
+            txid: types::reversed_hex(&tx.txid),
+            vsize: Some(tx.vsize),
+            fee: Some(tx.fee),
+            reason: None,
+        }
+    }
+}
+
+impl From<&MempoolRemoved> for MempoolTransaction {
+    fn from(tx: &MempoolRemoved) -> MempoolTransaction {
+        MempoolTransaction {
+            txid: types::reversed_hex(&tx.txid),
+            vsize: Some(tx.vsize),
+            fee: Some(tx.fee),
+            reason: Some(tx.get_reason()),
+        }
+    }
+}
+
+impl From<&MempoolRejected> for MempoolTransaction {
+    fn from(tx: &MempoolRejected) -> MempoolTransaction {
+        MempoolTransaction {
+            txid: types::reversed_hex(&tx.txid),
+            vsize: None,
+            fee: None,
+            reason: Some(tx.get_reason()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MempoolReplacement {
+    pub replaced_txid: String,
+    pub replaced_vsize: i32,
+    pub replaced_fee: i64,
+    pub replacement_txid: String,
+    pub replacement_vsize: i32,
+    pub replacement_fee: i64,
+}
+
+impl From<&MempoolReplaced> for MempoolReplacement {
+    fn from(r: &MempoolReplaced) -> MempoolReplacement {
+        MempoolReplacement {
+            replaced_txid: types::reversed_hex(&r.replaced_txid),
+            replaced_vsize: r.replaced_vsize,
+            replaced_fee: r.replaced_fee,
+            replacement_txid: types::reversed_hex(&r.replacement_txid),
+            replacement_vsize: r.replacement_vsize,
+            replacement_fee: r.replacement_fee,
+        }
+    }
+}
+
+/// Server-side filter of an event stream. Each field is a list of accepted
+/// values, an empty list accepts all values. Events without a message type
+/// or peer don't match a message type or peer filter.
+#[derive(Debug, Default)]
+pub struct EventFilter {
+    nodes: Vec<String>,
+    kinds: Vec<String>,
+    msg_types: Vec<String>,
+    /// Peer ids or addresses.
+    peers: Vec<String>,
+}
+
+impl EventFilter {
+    /// Parses a filter from a query string like
+    /// `kind=inbound_message,outbound_message&msg_type=tx&peer=12`. Values
+    /// can be comma-separated or repeated. Keys and values are
+    /// percent-decoded, e.g. `peer=%5B2001%3Adb8%3A%3A1%5D%3A8333`.
+    pub fn from_query(query: &str) -> Result<EventFilter, String> {
+        let mut filter = EventFilter::default();
+        for param in query.split('&').filter(|p| !p.is_empty()) {
+            let (key, values) = match param.split_once('=') {
+                Some((key, values)) => match (
+                    http::decode_query_component(key),
+                    http::decode_query_component(values),
+                ) {
+                    (Some(key), Some(values)) => (key, values),
+                    _ => return Err(format!("invalid percent-encoding in '{}'", param)),
+                },
+                None => return Err(format!("invalid query parameter '{}'", param)),
+            };
+            let list = match key.as_str() {
+                "node" => &mut filter.nodes,
+                "kind" => &mut filter.kinds,
+                "msg_type" => &mut filter.msg_types,
+                "peer" => &mut filter.peers,
+                _ => return Err(format!("unknown filter '{}'", key)),
+            };
+            for value in values.split(',').filter(|v| !v.is_empty()) {
+                if key == "kind" && !EVENT_KINDS.contains(&value) {
+                    return Err(format!(
+                        "unknown event kind '{}', expected one of {}",
+                        value,
+                        EVENT_KINDS.join(", ")
+                    ));
+                }
+                list.push(value.to_string());
+            }
+        }
+        Ok(filter)
+    }
+
+    pub fn matches(&self, record: &EventRecord) -> bool {
+        let event = &record.event;
+        if !self.nodes.is_empty() && !self.nodes.contains(&record.node) {
+            return false;
+        }
+        if !self.kinds.is_empty() && !self.kinds.iter().any(|k| k == event.kind()) {
+            return false;
+        }
+        if !self.msg_types.is_empty() {
+            match event.msg_type() {
+                Some(msg_type) if self.msg_types.iter().any(|m| m == msg_type) => (),
+                _ => return false,
+            }
+        }
+        if !self.peers.is_empty() {
+            match event.peer() {
+                Some((id, addr)) => {
+                    let id = id.to_string();
+                    if !self
+                        .peers
+                        .iter()
+                        .any(|p| *p == id || Some(p.as_str()) == addr)
+                    {
+                        return false;
+                    }
+                }
+                None => return false,
+            }
+        }
+        true
+    }
+}
+
+struct Subscriber {
+    id: u64,
+    filter: EventFilter,
+    sender: SyncSender<String>,
+}
+
+/// A subscription to the event stream. Unsubscribes when dropped.
+pub struct Subscription {
+    id: u64,
+    pub receiver: Receiver<String>,
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let mut subscribers = SUBSCRIBERS.lock().unwrap();
+        subscribers.retain(|s| s.id != self.id);
+        SUBSCRIBER_COUNT.store(subscribers.len(), Ordering::Relaxed);
+    }
+}
+
+/// Subscribes to the events matching the filter. The events are received as
+/// JSON. Returns None if there are already MAX_SUBSCRIBERS subscribers.
+pub fn subscribe(filter: EventFilter) -> Option<Subscription> {
+    let mut subscribers = SUBSCRIBERS.lock().unwrap();
+    if subscribers.len() >= MAX_SUBSCRIBERS {
+        return None;
+    }
+    let id = NEXT_SUBSCRIBER_ID.fetch_add(1, Ordering::Relaxed);
+    let (sender, receiver) = mpsc::sync_channel(SUBSCRIBER_BUFFER);
+    subscribers.push(Subscriber { id, filter, sender });
+    SUBSCRIBER_COUNT.store(subscribers.len(), Ordering::Relaxed);
+    Some(Subscription { id, receiver })
+}
+
+/// Sends an event to the matching subscribers. The event is only built if
+/// there are subscribers and only serialized if one of them matches.
+pub fn publish<F: FnOnce() -> Event>(node: &str, event: F) {
+    if SUBSCRIBER_COUNT.load(Ordering::Relaxed) == 0 {
+        return;
+    }
+    let record = EventRecord {
+        node: node.to_string(),
+        timestamp_ms: unix_timestamp_ms(),
+        event: event(),
+    };
+
+    let subscribers = SUBSCRIBERS.lock().unwrap();
+    let mut matching = subscribers
+        .iter()
+        .filter(|s| s.filter.matches(&record))
+        .peekable();
+    if matching.peek().is_none() {
+        return;
+    }
+    let json = match serde_json::to_string(&record) {
+        Ok(json) => json,
+        Err(_) => return,
+    };
+    for subscriber in matching {
+        if let Err(TrySendError::Full(_)) = subscriber.sender.try_send(json.clone()) {
+            metrics::RUNTIME_STREAM_EVENTS_DROPPED.inc();
+        }
+    }
+}
+
+fn unix_timestamp_ms() -> u64 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
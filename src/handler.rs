@@ -0,0 +1,356 @@
+use std::collections::HashMap;
+use std::time;
+
+use crate::blocks::{self, BlockRecord};
+use crate::connections::ConnectionTracker;
+use crate::events::{
+    self, Block, Connection, Event, Flush, MempoolReplacement, MempoolTransaction, Message,
+    Misbehaving,
+};
+use crate::metrics;
+use crate::openmetrics;
+use crate::peers::PeerTracker;
+use crate::source::TracedEvent;
+use crate::types::{
+    self, BlockConnected, ClosedConnection, MempoolAdded, MempoolRejected, MempoolRemoved,
+    MempoolReplaced, MisbehavingConnection, NewConnection, P2PMessage, UTXOCacheEvents,
+    UTXOCacheFlush,
+};
+
+const LOG_TARGET: &str = "handler";
+
+/// Updates the metrics, the recently connected blocks and the event streams
+/// with the events of a node.
+pub struct EventHandler {
+    node: String,
+    connections: ConnectionTracker,
+    /// The per-peer traffic tracker, if per-peer metrics are enabled.
+    peers: Option<PeerTracker>,
+}
+
+impl EventHandler {
+    /// Creates the handler of a node. Per-peer metrics are kept for up to
+    /// peer_metrics peers, or disabled if 0.
+    pub fn new(node: String, peer_metrics: usize) -> EventHandler {
+        let peers = match peer_metrics {
+            0 => None,
+            limit => Some(PeerTracker::new(node.clone(), limit)),
+        };
+        EventHandler {
+            connections: ConnectionTracker::new(node.clone()),
+            node,
+            peers,
+        }
+    }
+
+    pub fn handle(&mut self, event: TracedEvent) {
+        match event {
+            TracedEvent::InboundMessage(msg) => self.inbound_message(msg),
+            TracedEvent::OutboundMessage(msg) => self.outbound_message(msg),
+            TracedEvent::InboundConnection(conn) => {
+                self.new_connection(conn, Event::InboundConnection)
+            }
+            TracedEvent::OutboundConnection(conn) => {
+                self.new_connection(conn, Event::OutboundConnection)
+            }
+            TracedEvent::ClosedConnection(conn) => self.closed_connection(conn),
+            TracedEvent::EvictedConnection(conn) => self.evicted_connection(conn),
+            TracedEvent::MisbehavingConnection(m) => self.misbehaving_connection(m),
+            TracedEvent::BlockConnected(block) => self.block_connected(block),
+            TracedEvent::UTXOCacheEvents(utxocache_events) => {
+                self.utxocache_events(utxocache_events)
+            }
+            TracedEvent::UTXOCacheFlush(flush) => self.utxocache_flush(flush),
+            TracedEvent::MempoolAdded(added) => self.mempool_added(added),
+            TracedEvent::MempoolRemoved(removed) => self.mempool_removed(removed),
+            TracedEvent::MempoolReplaced(replaced) => self.mempool_replaced(replaced),
+            TracedEvent::MempoolRejected(rejected) => self.mempool_rejected(rejected),
+        }
+    }
+
+    /// Removes the metrics of peers that disconnected a while ago. Called
+    /// periodically.
+    pub fn expire(&mut self) {
+        if let Some(ref mut peers) = self.peers {
+            peers.expire();
+        }
+    }
+
+    /// Forgets the open connections and removes the per-peer metrics. Used
+    /// when detaching from bitcoind.
+    pub fn clear(&mut self) {
+        self.connections.clear();
+        if let Some(ref mut peers) = self.peers {
+            peers.clear();
+        }
+    }
+
+    fn inbound_message(&mut self, inbound_msg: P2PMessage) {
+        let node = &self.node;
+        let msg_type = inbound_msg.get_msg_type();
+        let conn_type = inbound_msg.get_peer_conn_type();
+        let mut labels = HashMap::<&str, &str>::new();
+        labels.insert(metrics::LABEL_NODE, node);
+        labels.insert(metrics::LABEL_P2P_MSG_TYPE, &msg_type);
+        labels.insert(metrics::LABEL_P2P_CONNECTION_TYPE, &conn_type);
+        labels.insert(
+            metrics::LABEL_P2P_NETWORK,
+            types::network_from_addr(&inbound_msg.get_peer_addr()),
+        );
+        metrics::P2P_MESSAGE_INBOUND_COUNT.with(&labels).inc();
+        metrics::P2P_MESSAGE_INBOUND_BYTE
+            .with(&labels)
+            .inc_by(inbound_msg.msg_size);
+        metrics::P2P_MESSAGE_INBOUND_SIZE.observe(node, &msg_type, inbound_msg.msg_size);
+        if let Some(ref mut peers) = self.peers {
+            peers.inbound_message(&inbound_msg);
+        }
+        events::publish(node, || Event::InboundMessage(Message::from(&inbound_msg)));
+    }
+
+    fn outbound_message(&mut self, outbound_msg: P2PMessage) {
+        let node = &self.node;
+        let msg_type = outbound_msg.get_msg_type();
+        let conn_type = outbound_msg.get_peer_conn_type();
+        let mut labels = HashMap::<&str, &str>::new();
+        labels.insert(metrics::LABEL_NODE, node);
+        labels.insert(metrics::LABEL_P2P_MSG_TYPE, &msg_type);
+        labels.insert(metrics::LABEL_P2P_CONNECTION_TYPE, &conn_type);
+        labels.insert(
+            metrics::LABEL_P2P_NETWORK,
+            types::network_from_addr(&outbound_msg.get_peer_addr()),
+        );
+        metrics::P2P_MESSAGE_OUTBOUND_COUNT.with(&labels).inc();
+        metrics::P2P_MESSAGE_OUTBOUND_BYTE
+            .with(&labels)
+            .inc_by(outbound_msg.msg_size);
+        metrics::P2P_MESSAGE_OUTBOUND_SIZE.observe(node, &msg_type, outbound_msg.msg_size);
+        if let Some(ref mut peers) = self.peers {
+            peers.outbound_message(&outbound_msg);
+        }
+        events::publish(node, || {
+            Event::OutboundMessage(Message::from(&outbound_msg))
+        });
+    }
+
+    fn new_connection(&mut self, conn: NewConnection, event: fn(Connection) -> Event) {
+        let network = types::network_name(conn.network);
+        self.connections
+            .opened(conn.id, conn.get_conn_type(), network, conn.existing);
+        if let Some(ref mut peers) = self.peers {
+            peers.connected(conn.id, network);
+        }
+        events::publish(&self.node, || event(Connection::from(&conn)));
+    }
+
+    fn closed_connection(&mut self, conn: ClosedConnection) {
+        self.connections.closed(
+            conn.id,
+            &conn.get_conn_type(),
+            types::network_name(conn.network),
+        );
+        if let Some(ref mut peers) = self.peers {
+            peers.disconnected(conn.id);
+        }
+        events::publish(&self.node, || {
+            Event::ClosedConnection(Connection::from(&conn))
+        });
+    }
+
+    fn evicted_connection(&mut self, conn: ClosedConnection) {
+        let conn_type = conn.get_conn_type();
+        metrics::P2P_CONNECTIONS_EVICTED
+            .with_label_values(&[&self.node, &conn_type, types::network_name(conn.network)])
+            .inc();
+        events::publish(&self.node, || {
+            Event::EvictedConnection(Connection::from(&conn))
+        });
+    }
+
+    fn misbehaving_connection(&mut self, misbehaving: MisbehavingConnection) {
+        log::debug!(target: LOG_TARGET, "Node {}: {}", self.node, misbehaving);
+        metrics::P2P_CONNECTIONS_MISBEHAVING
+            .with_label_values(&[&self.node])
+            .inc();
+        events::publish(&self.node, || {
+            Event::MisbehavingConnection(Misbehaving::from(&misbehaving))
+        });
+    }
+
+    fn block_connected(&mut self, block_connected: BlockConnected) {
+        let node = &self.node;
+        log::debug!(target: LOG_TARGET, "Node {}: {}", node, block_connected);
+        let record = BlockRecord::new(node, unix_timestamp(), &block_connected);
+        let hash = record.hash.clone();
+        blocks::RECENT_BLOCKS.lock().unwrap().push(record);
+        let labels = [node.as_str()];
+        metrics::VALIDATION_BLOCK_CONNECTED_HEIGHT_LAST
+            .with_label_values(&labels)
+            .set(block_connected.height as i64);
+        metrics::VALIDATION_BLOCK_CONNECTED_COUNT
+            .with_label_values(&labels)
+            .inc();
+        metrics::VALIDATION_BLOCK_CONNECTED_TRANSACTION_COUNT
+            .with_label_values(&labels)
+            .inc_by(block_connected.transactions);
+        metrics::VALIDATION_BLOCK_CONNECTED_INPUT_COUNT
+            .with_label_values(&labels)
+            .inc_by(block_connected.inputs as u64);
+        metrics::VALIDATION_BLOCK_CONNECTED_SIGOP_COUNT
+            .with_label_values(&labels)
+            .inc_by(block_connected.sigops);
+        // Links the bucket of slow block connections to the block.
+        openmetrics::observe_with_exemplar(
+            &metrics::VALIDATION_BLOCK_CONNECTION_DURATION.with_label_values(&labels),
+            block_connected.connection_time as f64 / 1_000_000.0,
+            &[("block_hash", &hash)],
+        );
+        metrics::VALIDATION_BLOCK_TRANSACTIONS
+            .with_label_values(&labels)
+            .observe(block_connected.transactions as f64);
+        metrics::VALIDATION_BLOCK_INPUTS
+            .with_label_values(&labels)
+            .observe(block_connected.inputs as f64);
+        metrics::VALIDATION_BLOCK_SIGOPS
+            .with_label_values(&labels)
+            .observe(block_connected.sigops as f64);
+        events::publish(node, || {
+            Event::BlockConnected(Block::from(&block_connected))
+        });
+    }
+
+    fn utxocache_events(&mut self, utxocache_events: UTXOCacheEvents) {
+        let labels = [self.node.as_str()];
+        metrics::UTXOCACHE_ADD
+            .with_label_values(&labels)
+            .inc_by(utxocache_events.add);
+        metrics::UTXOCACHE_SPENT
+            .with_label_values(&labels)
+            .inc_by(utxocache_events.spent);
+        metrics::UTXOCACHE_UNCACHE
+            .with_label_values(&labels)
+            .inc_by(utxocache_events.uncache);
+    }
+
+    fn utxocache_flush(&mut self, flush: UTXOCacheFlush) {
+        let mut labels = HashMap::<&str, &str>::new();
+        labels.insert(metrics::LABEL_NODE, &self.node);
+        labels.insert(metrics::LABEL_UTXOCACHE_FLUSH_MODE, flush.flush_mode());
+        labels.insert(
+            metrics::LABEL_UTXOCACHE_FLUSH_FORPRUNE,
+            flush.flush_for_prune(),
+        );
+        metrics::UTXOCACHE_FLUSH.with(&labels).inc();
+        metrics::UTXOCACHE_FLUSH_DURATION
+            .with(&labels)
+            .inc_by(flush.duration);
+        metrics::UTXOCACHE_FLUSH_COINS_COUNT
+            .with(&labels)
+            .inc_by(flush.coins_count);
+        metrics::UTXOCACHE_FLUSH_COINS_MEMUSAGE
+            .with(&labels)
+            .inc_by(flush.coins_memusage);
+        events::publish(&self.node, || Event::UtxocacheFlush(Flush::from(&flush)));
+    }
+
+    fn mempool_added(&mut self, added: MempoolAdded) {
+        let labels = [self.node.as_str()];
+        metrics::MEMPOOL_ADDED.with_label_values(&labels).inc();
+        metrics::MEMPOOL_ADDED_VSIZE
+            .with_label_values(&labels)
+            .inc_by(added.vsize.max(0) as u64);
+        metrics::MEMPOOL_ADDED_FEE
+            .with_label_values(&labels)
+            .inc_by(added.fee.max(0) as u64);
+        events::publish(&self.node, || {
+            Event::MempoolAdded(MempoolTransaction::from(&added))
+        });
+    }
+
+    fn mempool_removed(&mut self, removed: MempoolRemoved) {
+        let reason = removed.get_reason();
+        let labels = [self.node.as_str(), reason.as_str()];
+        metrics::MEMPOOL_REMOVED.with_label_values(&labels).inc();
+        metrics::MEMPOOL_REMOVED_VSIZE
+            .with_label_values(&labels)
+            .inc_by(removed.vsize.max(0) as u64);
+        metrics::MEMPOOL_REMOVED_FEE
+            .with_label_values(&labels)
+            .inc_by(removed.fee.max(0) as u64);
+        events::publish(&self.node, || {
+            Event::MempoolRemoved(MempoolTransaction::from(&removed))
+        });
+    }
+
+    fn mempool_replaced(&mut self, replaced: MempoolReplaced) {
+        let labels = [self.node.as_str()];
+        metrics::MEMPOOL_REPLACED.with_label_values(&labels).inc();
+        metrics::MEMPOOL_REPLACED_VSIZE
+            .with_label_values(&labels)
+            .inc_by(replaced.replaced_vsize.max(0) as u64);
+        metrics::MEMPOOL_REPLACED_FEE
+            .with_label_values(&labels)
+            .inc_by(replaced.replaced_fee.max(0) as u64);
+        metrics::MEMPOOL_REPLACEMENT_VSIZE
+            .with_label_values(&labels)
+            .inc_by(replaced.replacement_vsize.max(0) as u64);
+        metrics::MEMPOOL_REPLACEMENT_FEE
+            .with_label_values(&labels)
+            .inc_by(replaced.replacement_fee.max(0) as u64);
+        events::publish(&self.node, || {
+            Event::MempoolReplaced(MempoolReplacement::from(&replaced))
+        });
+    }
+
+    fn mempool_rejected(&mut self, rejected: MempoolRejected) {
+        let reason = rejected.get_reason();
+        metrics::MEMPOOL_REJECTED
+            .with_label_values(&[&self.node, &reason])
+            .inc();
+        events::publish(&self.node, || {
+            Event::MempoolRejected(MempoolTransaction::from(&rejected))
+        });
+    }
+}
+
+/// The event handlers of all nodes of a multi-node event source, e.g. a
+/// recording. Each node gets its handler on its first event.
+pub struct EventHandlers {
+    peer_metrics: usize,
+    handlers: HashMap<String, EventHandler>,
+}
+
+impl EventHandlers {
+    pub fn new(peer_metrics: usize) -> EventHandlers {
+        EventHandlers {
+            peer_metrics,
+            handlers: HashMap::new(),
+        }
+    }
+
+    pub fn handle(&mut self, node: &str, event: TracedEvent) {
+        if !self.handlers.contains_key(node) {
+            log::info!(target: LOG_TARGET, "Node {}: handling events.", node);
+            self.handlers.insert(
+                node.to_string(),
+                EventHandler::new(node.to_string(), self.peer_metrics),
+            );
+        }
+        self.handlers.get_mut(node).unwrap().handle(event);
+    }
+
+    /// Removes the metrics of peers that disconnected a while ago on all
+    /// nodes.
+    pub fn expire(&mut self) {
+        for handler in self.handlers.values_mut() {
+            handler.expire();
+        }
+    }
+}
+
+fn unix_timestamp() -> u64 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
@@ -0,0 +1,538 @@
+use std::cell::Cell;
+use std::error;
+use std::fmt;
+use std::io::{self, BufRead, Read, Write};
+use std::net::TcpStream;
+use std::time::{self, Duration, Instant};
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use rustls::{ServerConnection, StreamOwned};
+
+/// Maximum size of the request line and headers.
+const MAX_HEAD_BYTES: usize = 8 * 1024;
+/// Maximum number of request headers.
+const MAX_HEADERS: usize = 100;
+/// Maximum size of a request body. Bodies are read and discarded, as no
+/// route expects one.
+const MAX_BODY_BYTES: u64 = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Version {
+    Http10,
+    Http11,
+}
+
+/// A parsed HTTP request without its body.
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    /// The path of the request target, e.g. `/metrics`.
+    pub path: String,
+    /// The query of the request target without the `?`. Empty if none.
+    pub query: String,
+    pub version: Version,
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    /// Returns the value of the first header with the name, ignoring case.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Whether the client wants to keep the connection open after the
+    /// response. The default for HTTP/1.1 but not for HTTP/1.0.
+    pub fn keep_alive(&self) -> bool {
+        let connection = self.header("Connection").unwrap_or("");
+        let has_option = |option: &str| {
+            connection
+                .split(',')
+                .any(|o| o.trim().eq_ignore_ascii_case(option))
+        };
+        match self.version {
+            Version::Http11 => !has_option("close"),
+            Version::Http10 => has_option("keep-alive"),
+        }
+    }
+
+    /// Whether the client accepts a gzip compressed response.
+    pub fn accepts_gzip(&self) -> bool {
+        let values = quality_values(self.header("Accept-Encoding").unwrap_or(""));
+        let quality = |coding: &str| {
+            values
+                .iter()
+                .find(|v| v.value.eq_ignore_ascii_case(coding))
+                .map(|v| v.quality)
+        };
+        quality("gzip")
+            .or_else(|| quality("x-gzip"))
+            .or_else(|| quality("*"))
+            .unwrap_or(0.0)
+            > 0.0
+    }
+}
+
+/// A value of a header like Accept or Accept-Encoding, with its parameters
+/// and its quality from the `q` parameter.
+#[derive(Debug, PartialEq)]
+pub struct QualityValue<'a> {
+    pub value: &'a str,
+    params: Vec<(&'a str, &'a str)>,
+    pub quality: f32,
+}
+
+impl<'a> QualityValue<'a> {
+    /// Returns the value of the parameter with the name, ignoring case.
+    pub fn param(&self, name: &str) -> Option<&'a str> {
+        self.params
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| *v)
+    }
+}
+
+/// Parses the comma-separated values of a header like Accept (e.g.
+/// `text/plain;version=0.0.4;q=0.5,*/*;q=0.1`). Values without a valid `q`
+/// parameter have the quality 1.
+pub fn quality_values(header: &str) -> Vec<QualityValue<'_>> {
+    header
+        .split(',')
+        .filter_map(|element| {
+            let mut parts = element.split(';').map(str::trim);
+            let value = parts.next().filter(|v| !v.is_empty())?;
+            let params: Vec<(&str, &str)> = parts
+                .filter_map(|p| p.split_once('='))
+                .map(|(n, v)| (n.trim(), v.trim().trim_matches('"')))
+                .collect();
+            let quality = params
+                .iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case("q"))
+                .and_then(|(_, q)| q.parse::<f32>().ok())
+                .filter(|q| (0.0..=1.0).contains(q))
+                .unwrap_or(1.0);
+            Some(QualityValue {
+                value,
+                params,
+                quality,
+            })
+        })
+        .collect()
+}
+
+/// Decodes a name or value in a query string: percent-encoded bytes are
+/// decoded and `+` is decoded as a space. Returns None if the encoding is
+/// invalid or the result isn't UTF-8.
+pub fn decode_query_component(component: &str) -> Option<String> {
+    let mut bytes = vec![];
+    let mut rest = component.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        rest = tail;
+        match b {
+            b'%' => {
+                let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+                bytes.push(u8::from_str_radix(hex, 16).ok()?);
+                rest = &tail[2..];
+            }
+            b'+' => bytes.push(b' '),
+            _ => bytes.push(b),
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// Reads the next request from a connection. Returns None if the connection
+/// was closed before a request started. The request body, if any, is read
+/// and discarded.
+pub fn read_request<R: BufRead>(reader: &mut R) -> Result<Option<Request>, HttpError> {
+    let mut head_bytes = 0;
+
+    // Empty lines before the request line are ignored (RFC 7230, 3.5).
+    let request_line = loop {
+        match read_line(reader, &mut head_bytes)? {
+            None => return Ok(None),
+            Some(line) if line.is_empty() => continue,
+            Some(line) => break line,
+        }
+    };
+
+    let mut parts = request_line.split(' ');
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version), None) if !method.is_empty() => {
+            (method, target, version)
+        }
+        _ => return Err(HttpError::BadRequest("malformed request line")),
+    };
+    let version = match version {
+        "HTTP/1.1" => Version::Http11,
+        "HTTP/1.0" => Version::Http10,
+        _ if version.starts_with("HTTP/") => return Err(HttpError::VersionNotSupported),
+        _ => return Err(HttpError::BadRequest("malformed request line")),
+    };
+
+    // Absolute-form targets (`http://host/path`) are reduced to their path.
+    let target = match target.find("://") {
+        Some(i) => match target[i + 3..].find('/') {
+            Some(j) => &target[i + 3 + j..],
+            None => "/",
+        },
+        None => target,
+    };
+    if !target.starts_with('/') {
+        return Err(HttpError::BadRequest("unsupported request target"));
+    }
+    let (path, query) = match target.find('?') {
+        Some(i) => (&target[..i], &target[i + 1..]),
+        None => (target, ""),
+    };
+
+    let mut headers = vec![];
+    loop {
+        let line = match read_line(reader, &mut head_bytes)? {
+            Some(line) => line,
+            None => return Err(HttpError::BadRequest("incomplete request")),
+        };
+        if line.is_empty() {
+            break;
+        }
+        if headers.len() == MAX_HEADERS {
+            return Err(HttpError::HeadersTooLarge);
+        }
+        match line.split_once(':') {
+            Some((name, value)) if !name.is_empty() && !name.ends_with(char::is_whitespace) => {
+                headers.push((name.to_string(), value.trim().to_string()))
+            }
+            _ => return Err(HttpError::BadRequest("malformed header")),
+        }
+    }
+
+    let request = Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        query: query.to_string(),
+        version,
+        headers,
+    };
+
+    if request.version == Version::Http11 && request.header("Host").is_none() {
+        return Err(HttpError::BadRequest("missing Host header"));
+    }
+    if request.header("Transfer-Encoding").is_some() {
+        return Err(HttpError::NotImplemented);
+    }
+    if let Some(length) = request.header("Content-Length") {
+        let length: u64 = length
+            .parse()
+            .map_err(|_| HttpError::BadRequest("invalid Content-Length"))?;
+        if length > MAX_BODY_BYTES {
+            return Err(HttpError::PayloadTooLarge);
+        }
+        let discarded = io::copy(&mut reader.take(length), &mut io::sink())?;
+        if discarded < length {
+            return Err(HttpError::BadRequest("incomplete request body"));
+        }
+    }
+
+    Ok(Some(request))
+}
+
+/// Reads a CRLF (or LF) terminated line of the request head. Returns None
+/// on EOF before the first byte of the line.
+fn read_line<R: BufRead>(
+    reader: &mut R,
+    head_bytes: &mut usize,
+) -> Result<Option<String>, HttpError> {
+    let mut line = vec![];
+    let limit = (MAX_HEAD_BYTES - *head_bytes) as u64 + 1;
+    let n = reader.take(limit).read_until(b'\n', &mut line)?;
+    *head_bytes += n;
+    if n == 0 {
+        return Ok(None);
+    }
+    if *head_bytes > MAX_HEAD_BYTES {
+        return Err(HttpError::HeadersTooLarge);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(HttpError::BadRequest("incomplete request"));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| HttpError::BadRequest("request head is not UTF-8"))
+}
+
+/// An HTTP response with a body of known length.
+pub struct Response {
+    pub status: u16,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &str, body: Vec<u8>) -> Response {
+        Response {
+            status,
+            headers: vec![("Content-Type", content_type.to_string())],
+            body,
+        }
+    }
+
+    /// A plain text response.
+    pub fn text(status: u16, body: &str) -> Response {
+        Response::new(
+            status,
+            "text/plain; charset=utf-8",
+            body.as_bytes().to_vec(),
+        )
+    }
+
+    pub fn header(mut self, name: &'static str, value: &str) -> Response {
+        self.headers.push((name, value.to_string()));
+        self
+    }
+
+    /// Compresses the body with gzip.
+    pub fn gzip(mut self) -> io::Result<Response> {
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(&self.body)?;
+        self.body = encoder.finish()?;
+        Ok(self.header("Content-Encoding", "gzip"))
+    }
+
+    /// Writes the response. The body is left out for HEAD requests.
+    pub fn write_to<W: Write>(
+        &self,
+        writer: &mut W,
+        head: bool,
+        keep_alive: bool,
+    ) -> io::Result<()> {
+        let mut response = status_line(self.status);
+        for (name, value) in self.headers.iter() {
+            response.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        response.push_str(&format!(
+            "Content-Length: {}\r\nConnection: {}\r\n\r\n",
+            self.body.len(),
+            if keep_alive { "keep-alive" } else { "close" }
+        ));
+        writer.write_all(response.as_bytes())?;
+        if !head {
+            writer.write_all(&self.body)?;
+        }
+        writer.flush()
+    }
+}
+
+/// The status line and the Date header of a response.
+pub fn status_line(status: u16) -> String {
+    format!(
+        "HTTP/1.1 {} {}\r\nDate: {}\r\n",
+        status,
+        reason_phrase(status),
+        http_date(
+            time::SystemTime::now()
+                .duration_since(time::UNIX_EPOCH)
+                .unwrap()
+                .as_secs()
+        )
+    )
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        406 => "Not Acceptable",
+        408 => "Request Timeout",
+        413 => "Payload Too Large",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        505 => "HTTP Version Not Supported",
+        _ => "",
+    }
+}
+
+/// Formats a UNIX timestamp as IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37
+/// GMT`, as used in the Date header.
+pub fn http_date(timestamp: u64) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let days = timestamp / 86400;
+    let seconds = timestamp % 86400;
+
+    // Converts days since 1970-01-01 into a date in the proleptic Gregorian
+    // calendar with eras of 400 years starting on March 1st.
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let day_of_era = z % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153; // March is 0
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+/// A TCP connection whose reads fail with a timeout once a deadline passed,
+/// so slow clients can't hold on to a worker by trickling in a request.
+pub struct TimedStream {
+    stream: TcpStream,
+    deadline: Cell<Option<Instant>>,
+}
+
+impl TimedStream {
+    pub fn new(stream: TcpStream) -> TimedStream {
+        TimedStream {
+            stream,
+            deadline: Cell::new(None),
+        }
+    }
+
+    pub fn set_write_timeout(&self, timeout: Duration) -> io::Result<()> {
+        self.stream.set_write_timeout(Some(timeout))
+    }
+}
+
+impl Read for TimedStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(deadline) = self.deadline.get() {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "read deadline passed",
+                ));
+            }
+            self.stream.set_read_timeout(Some(deadline - now))?;
+        }
+        self.stream.read(buf)
+    }
+}
+
+impl Write for TimedStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+/// A connection to a client, either plain or TLS.
+pub trait Connection: Read + Write {
+    /// Reads fail with a timeout error after the timeout.
+    fn set_read_deadline(&self, timeout: Duration);
+
+    /// Closes the connection gracefully. For TLS, the client is notified.
+    fn close(&mut self) {}
+}
+
+impl Connection for TimedStream {
+    fn set_read_deadline(&self, timeout: Duration) {
+        self.deadline.set(Some(Instant::now() + timeout));
+    }
+}
+
+impl Connection for StreamOwned<ServerConnection, TimedStream> {
+    fn set_read_deadline(&self, timeout: Duration) {
+        self.sock.set_read_deadline(timeout);
+    }
+
+    fn close(&mut self) {
+        self.conn.send_close_notify();
+        let _ = self.conn.complete_io(&mut self.sock);
+    }
+}
+
+/// Returns whether the error is a read or write timeout.
+pub fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+    )
+}
+
+/// An error reading a request. Except for IO errors, the client is answered
+/// with the status() before the connection is closed.
+#[derive(Debug)]
+pub enum HttpError {
+    Io(io::Error),
+    BadRequest(&'static str),
+    HeadersTooLarge,
+    PayloadTooLarge,
+    /// Transfer codings like chunked request bodies aren't supported.
+    NotImplemented,
+    VersionNotSupported,
+}
+
+impl HttpError {
+    pub fn status(&self) -> u16 {
+        match self {
+            HttpError::Io(e) if is_timeout(e) => 408,
+            HttpError::Io(_) => 400,
+            HttpError::BadRequest(_) => 400,
+            HttpError::HeadersTooLarge => 431,
+            HttpError::PayloadTooLarge => 413,
+            HttpError::NotImplemented => 501,
+            HttpError::VersionNotSupported => 505,
+        }
+    }
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HttpError::Io(e) => write!(f, "IO error: {}", e),
+            HttpError::BadRequest(reason) => write!(f, "bad request: {}", reason),
+            HttpError::HeadersTooLarge => write!(f, "request head too large"),
+            HttpError::PayloadTooLarge => write!(f, "request body too large"),
+            HttpError::NotImplemented => write!(f, "transfer coding not implemented"),
+            HttpError::VersionNotSupported => write!(f, "HTTP version not supported"),
+        }
+    }
+}
+
+impl error::Error for HttpError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            HttpError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for HttpError {
+    fn from(err: io::Error) -> HttpError {
+        HttpError::Io(err)
+    }
+}
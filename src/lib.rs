@@ -0,0 +1,26 @@
+//! The bitcoind-observer as a library: the event sources, the decoding of the
+//! events and the handlers updating the metrics. Used by the binary and by
+//! the integration tests.
+
+pub mod blocks;
+pub mod config;
+pub mod connections;
+pub mod counters;
+pub mod events;
+pub mod handler;
+pub mod http;
+pub mod message_sizes;
+pub mod metrics;
+pub mod metricserver;
+pub mod openmetrics;
+pub mod peers;
+pub mod probes;
+pub mod push;
+pub mod reader;
+pub mod recording;
+pub mod sinks;
+pub mod source;
+pub mod target;
+pub mod tracer;
+pub mod types;
+pub mod webconfig;
@@ -1,187 +1,303 @@
-use bcc::perf_event::PerfMapBuilder;
-use bcc::{BPFBuilder, USDTContext};
-use std::collections::HashMap;
-use std::env;
+use std::process;
+use std::thread;
 use std::time;
 
-mod metrics;
-mod metricserver;
-mod types;
-
-use types::{BlockConnected, P2PMessage, UTXOCacheEvent, UTXOCacheFlush};
+use bitcoind_observer::config::{self, NodeConfig, ReplayConfig, TracerConfig};
+use bitcoind_observer::handler::{EventHandler, EventHandlers};
+use bitcoind_observer::recording::{self, Replay};
+use bitcoind_observer::source::EventSource;
+use bitcoind_observer::target::TargetProcess;
+use bitcoind_observer::tracer::Tracer;
+use bitcoind_observer::webconfig::WebConfig;
+use bitcoind_observer::{blocks, metrics, metricserver, push, sinks};
 
 use simple_logger::SimpleLogger;
 
 const LOG_TARGET: &str = "main";
 
+/// Interval in which we check if the traced bitcoind process is still running,
+/// expire the metrics of disconnected peers and re-read the pidfile while
+/// waiting for bitcoind to start.
+const PROCESS_CHECK_INTERVAL: time::Duration = time::Duration::from_secs(1);
+
 fn main() {
-    let bitcoind_path = env::args().nth(1).expect("No bitcoind path provided.");
-    let metricserver_address = env::args()
-        .nth(2)
-        .expect("No metric server address to bind on provided (.e.g. 'localhost:8282').");
+    let config = match config::Config::load() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+    };
+
+    metrics::set_block_connection_duration_buckets(config.block_connection_buckets.clone());
+    blocks::RECENT_BLOCKS
+        .lock()
+        .unwrap()
+        .set_capacity(config.recent_blocks);
 
     SimpleLogger::new()
+        .with_level(config.log_level)
         .init()
         .expect("Could not setup logging.");
 
-    log::info!(
-        target: LOG_TARGET,
-        "Starting bitcoind-observer using {} ...",
-        bitcoind_path,
-    );
+    log::info!(target: LOG_TARGET, "Starting bitcoind-observer ...");
 
-    metrics::RUNTIME_START_TIMESTAMP.set(
-        time::SystemTime::now()
-            .duration_since(time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as i64,
-    );
+    metrics::RUNTIME_START_TIMESTAMP.set(unix_timestamp() as i64);
 
-    let mut usdt_ctx = USDTContext::from_binary_path(bitcoind_path).unwrap();
-    usdt_ctx
-        .enable_probe("net:inbound_message", "trace_inbound_message")
-        .unwrap();
-    usdt_ctx
-        .enable_probe("net:outbound_message", "trace_outbound_message")
-        .unwrap();
-    usdt_ctx
-        .enable_probe("validation:block_connected", "trace_block_connected")
-        .unwrap();
-    usdt_ctx
-        .enable_probe("utxocache:add", "trace_utxocache_add")
-        .unwrap();
-    usdt_ctx
-        .enable_probe("utxocache:spent", "trace_utxocache_spent")
-        .unwrap();
-    usdt_ctx
-        .enable_probe("utxocache:uncache", "trace_utxocache_uncache")
-        .unwrap();
-    usdt_ctx
-        .enable_probe("utxocache:flush", "trace_utxocache_flush")
-        .unwrap();
-
-    let code = concat!(
-        "#include <uapi/linux/ptrace.h>",
-        "\n\n",
-        include_str!("../ebpf-programs/p2p_in_and_outbound.c"),
-        include_str!("../ebpf-programs/validation_block_connected.c"),
-        include_str!("../ebpf-programs/utxo_set_cache_changes.c"),
-        include_str!("../ebpf-programs/utxo_set_cache_flushes.c"),
-    );
-    let bpf = BPFBuilder::new(code)
-        .unwrap()
-        .add_usdt_context(usdt_ctx)
-        .unwrap()
-        .build()
-        .unwrap();
-
-    let table_inbound_messages = bpf.table("inbound_messages").unwrap();
-    let table_outbound_messages = bpf.table("outbound_messages").unwrap();
-    let table_block_connected = bpf.table("perf_block_connected").unwrap();
-    let table_utxocache_events = bpf.table("perf_utxocache_events").unwrap();
-    let table_utxocache_flushes = bpf.table("perf_utxocache_flushes").unwrap();
-
-    let mut perf_map_inbound_msg =
-        PerfMapBuilder::new(table_inbound_messages, callback_inbound_message)
-            .build()
-            .unwrap();
-    let mut perf_map_outbound_msg =
-        PerfMapBuilder::new(table_outbound_messages, callback_outbound_message)
-            .build()
-            .unwrap();
-    let mut perf_map_block_connected =
-        PerfMapBuilder::new(table_block_connected, callback_block_connected)
-            .build()
-            .unwrap();
-    let mut perf_map_utxocache_events =
-        PerfMapBuilder::new(table_utxocache_events, callback_utxocache_event)
-            .build()
-            .unwrap();
-    let mut perf_map_utxocache_flushes =
-        PerfMapBuilder::new(table_utxocache_flushes, callback_utxocache_flush)
-            .build()
-            .unwrap();
-
-    metricserver::start(&metricserver_address).unwrap();
-
-    log::info!(target: LOG_TARGET, "Started bitcoind-observer.");
+    let web_config = match config.web_config_file {
+        Some(ref path) => match WebConfig::load(path) {
+            Ok(web_config) => Some(web_config),
+            Err(e) => {
+                log::error!(
+                    target: LOG_TARGET,
+                    "Could not load the web config {}: {}",
+                    path.display(),
+                    e
+                );
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+    if let Err(e) = metricserver::start(&config.listen, web_config) {
+        log::error!(
+            target: LOG_TARGET,
+            "Could not start the metric server on {}: {}",
+            config.listen,
+            e
+        );
+        process::exit(1);
+    }
+    if let Some(ref push_config) = config.push {
+        push::start(
+            &push_config.targets,
+            push_config.interval,
+            &push_config.job,
+            &push_config.instance,
+        );
+    }
+    if let Err(e) = sinks::start(&config.sinks) {
+        log::error!(target: LOG_TARGET, "Could not start the output sinks: {}", e);
+        process::exit(1);
+    }
 
-    loop {
-        perf_map_inbound_msg.poll(1);
-        perf_map_outbound_msg.poll(1);
-        perf_map_block_connected.poll(1);
-        perf_map_utxocache_events.poll(1);
-        perf_map_utxocache_flushes.poll(1);
+    if let Some(ref path) = config.record {
+        if let Err(e) = recording::start(path) {
+            log::error!(
+                target: LOG_TARGET,
+                "Could not start recording to {}: {}",
+                path.display(),
+                e
+            );
+            process::exit(1);
+        }
+        log::info!(target: LOG_TARGET, "Recording events to {} ...", path.display());
     }
-}
 
-fn callback_inbound_message() -> Box<dyn FnMut(&[u8]) + Send> {
-    Box::new(|x| {
-        let inbound_msg = P2PMessage::from_bytes(x);
-        let msg_type = inbound_msg.get_msg_type();
-        let conn_type = inbound_msg.get_peer_conn_type();
-        let mut labels = HashMap::<&str, &str>::new();
-        labels.insert(metrics::LABEL_P2P_MSG_TYPE, &msg_type);
-        labels.insert(metrics::LABEL_P2P_CONNECTION_TYPE, &conn_type);
-        metrics::P2P_MESSAGE_INBOUND_COUNT.with(&labels).inc();
-        metrics::P2P_MESSAGE_INBOUND_BYTE
-            .with(&labels)
-            .inc_by(inbound_msg.msg_size);
-    })
-}
+    if let Some(ref replay_config) = config.replay {
+        replay(replay_config, &config.tracer);
+        // Keep serving the replayed metrics until stopped.
+        loop {
+            thread::park();
+        }
+    }
+
+    let observers: Vec<thread::JoinHandle<()>> = config
+        .nodes
+        .iter()
+        .map(|node| {
+            let node = node.clone();
+            let tracer_config = config.tracer.clone();
+            thread::spawn(move || observe(node, tracer_config))
+        })
+        .collect();
 
-fn callback_outbound_message() -> Box<dyn FnMut(&[u8]) + Send> {
-    Box::new(|x| {
-        let outbound_msg = P2PMessage::from_bytes(x);
-        let msg_type = outbound_msg.get_msg_type();
-        let conn_type = outbound_msg.get_peer_conn_type();
-        let mut labels = HashMap::<&str, &str>::new();
-        labels.insert(metrics::LABEL_P2P_MSG_TYPE, &msg_type);
-        labels.insert(metrics::LABEL_P2P_CONNECTION_TYPE, &conn_type);
-        metrics::P2P_MESSAGE_OUTBOUND_COUNT.with(&labels).inc();
-        metrics::P2P_MESSAGE_OUTBOUND_BYTE
-            .with(&labels)
-            .inc_by(outbound_msg.msg_size);
-    })
+    for observer in observers {
+        let _ = observer.join();
+    }
+    recording::flush();
+    log::error!(target: LOG_TARGET, "Stopped observing all bitcoind nodes.");
+    process::exit(1);
 }
 
-fn callback_block_connected() -> Box<dyn FnMut(&[u8]) + Send> {
-    Box::new(|x| {
-        let block_connected = BlockConnected::from_bytes(x);
-        metrics::VALIDATION_BLOCK_CONNECTED_HEIGHT_LAST.set(block_connected.height as i64);
-        metrics::VALIDATION_BLOCK_CONNECTED_COUNT.inc();
-        metrics::VALIDATION_BLOCK_CONNECTED_TRANSACTION_COUNT.inc_by(block_connected.transactions);
-        metrics::VALIDATION_BLOCK_CONNECTED_INPUT_COUNT.inc_by(block_connected.inputs as u64);
-        metrics::VALIDATION_BLOCK_CONNECTED_SIGOP_COUNT.inc_by(block_connected.sigops);
-        metrics::VALIDATION_BLOCK_CONNECTED_TIMING.inc_by(block_connected.connection_time);
-    })
+fn unix_timestamp() -> u64 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
 }
 
-fn callback_utxocache_event() -> Box<dyn FnMut(&[u8]) + Send> {
-    Box::new(|x| {
-        let event = UTXOCacheEvent::from_bytes(x);
-        match event.event {
-            types::UTXOCACHE_ADD => metrics::UTXOCACHE_ADD.inc(),
-            types::UTXOCACHE_SPENT => metrics::UTXOCACHE_SPENT.inc(),
-            types::UTXOCACHE_UNCACHE => metrics::UTXOCACHE_UNCACHE.inc(),
-            _ => log::info!(
+/// Attaches to and traces a bitcoind node. In pidfile mode, the node is
+/// re-attached when bitcoind restarts. Otherwise, this returns when the node
+/// can't be attached to or when the traced process exits.
+fn observe(node: NodeConfig, tracer_config: TracerConfig) {
+    log::info!(
+        target: LOG_TARGET,
+        "Observing node {} using {:?} ...",
+        node.name,
+        node.target_process,
+    );
+
+    loop {
+        let process = match node.target_process {
+            TargetProcess::All => None,
+            TargetProcess::Pid(pid) => match node.target_process.process() {
+                Some(process) => Some(process),
+                None => {
+                    log::error!(
+                        target: LOG_TARGET,
+                        "Node {}: no process with PID {} running.",
+                        node.name,
+                        pid
+                    );
+                    return;
+                }
+            },
+            TargetProcess::PidFile(ref path) => {
+                log::info!(
+                    target: LOG_TARGET,
+                    "Node {}: waiting for a running bitcoind process in pidfile {} ...",
+                    node.name,
+                    path.display()
+                );
+                loop {
+                    if let Some(process) = node.target_process.process() {
+                        break Some(process);
+                    }
+                    thread::sleep(PROCESS_CHECK_INTERVAL);
+                }
+            }
+        };
+        let pid = process.map(|process| process.pid);
+
+        let mut tracer = match Tracer::attach(&node, &tracer_config, pid) {
+            Ok(tracer) => tracer,
+            Err(e) => {
+                log::error!(
+                    target: LOG_TARGET,
+                    "Node {}: could not attach to bitcoind: {}",
+                    node.name,
+                    e
+                );
+                if let TargetProcess::PidFile(_) = node.target_process {
+                    thread::sleep(PROCESS_CHECK_INTERVAL);
+                    continue;
+                }
+                return;
+            }
+        };
+        metrics::RUNTIME_BITCOIND_PID
+            .with_label_values(&[&node.name])
+            .set(pid.unwrap_or(0) as i64);
+        metrics::RUNTIME_BITCOIND_ATTACHED
+            .with_label_values(&[&node.name, node.target_process.mode()])
+            .set(1);
+        metricserver::source_attached();
+
+        match pid {
+            Some(pid) => log::info!(
                 target: LOG_TARGET,
-                "UTXO cache event callback: unknown event {:?}",
-                event.event
+                "Node {}: attached to bitcoind with PID {}.",
+                node.name,
+                pid
             ),
+            None => log::info!(target: LOG_TARGET, "Node {}: attached to bitcoind.", node.name),
+        }
+
+        let mut handler = EventHandler::new(node.name.clone(), tracer_config.peer_metrics);
+        let mut last_process_check = time::Instant::now();
+        loop {
+            let result = tracer.poll(tracer_config.poll_timeout, &mut |_, event| {
+                handler.handle(event)
+            });
+            if let Err(e) = result {
+                log::error!(
+                    target: LOG_TARGET,
+                    "Node {}: could not read events: {}",
+                    node.name,
+                    e
+                );
+                thread::sleep(tracer_config.poll_timeout);
+            }
+            if last_process_check.elapsed() >= PROCESS_CHECK_INTERVAL {
+                last_process_check = time::Instant::now();
+                handler.expire();
+                recording::flush();
+                if let Some(process) = process {
+                    if !process.is_running() {
+                        log::warn!(
+                            target: LOG_TARGET,
+                            "Node {}: bitcoind with PID {} exited.",
+                            node.name,
+                            process.pid
+                        );
+                        break;
+                    }
+                }
+            }
+        }
+
+        tracer.read_counters(&mut |_, event| handler.handle(event));
+        drop(tracer);
+        handler.clear();
+        metricserver::source_detached();
+        metrics::RUNTIME_BITCOIND_PID
+            .with_label_values(&[&node.name])
+            .set(0);
+        metrics::RUNTIME_BITCOIND_ATTACHED
+            .with_label_values(&[&node.name, node.target_process.mode()])
+            .set(0);
+        if let TargetProcess::Pid(_) = node.target_process {
+            return;
         }
-    })
+    }
 }
 
-fn callback_utxocache_flush() -> Box<dyn FnMut(&[u8]) + Send> {
-    Box::new(|x| {
-        let flush = UTXOCacheFlush::from_bytes(x);
-        let mut labels = HashMap::<&str, &str>::new();
-        labels.insert(metrics::LABEL_UTXOCACHE_FLUSH_MODE, flush.flush_mode());
-        labels.insert(metrics::LABEL_UTXOCACHE_FLUSH_FORPRUNE, flush.flush_for_prune());
-        metrics::UTXOCACHE_FLUSH.with(&labels).inc();
-        metrics::UTXOCACHE_FLUSH_DURATION.with(&labels).inc_by(flush.duration);
-        metrics::UTXOCACHE_FLUSH_COINS_COUNT.with(&labels).inc_by(flush.coins_count);
-        metrics::UTXOCACHE_FLUSH_COINS_MEMUSAGE.with(&labels).inc_by(flush.coins_memusage);
-    })
+/// Replays a recording through the same handlers that handle the events when
+/// tracing. Each node in the recording gets its handler on its first event.
+fn replay(replay_config: &ReplayConfig, tracer_config: &TracerConfig) {
+    log::info!(
+        target: LOG_TARGET,
+        "Replaying recording {} ...",
+        replay_config.path.display()
+    );
+    let mut replay = match Replay::open(&replay_config.path, replay_config.speed) {
+        Ok(replay) => replay,
+        Err(e) => {
+            log::error!(
+                target: LOG_TARGET,
+                "Could not open recording {}: {}",
+                replay_config.path.display(),
+                e
+            );
+            return;
+        }
+    };
+    // The replayed metrics are served after the replay finished, too.
+    metricserver::source_attached();
+    let mut handlers = EventHandlers::new(tracer_config.peer_metrics);
+    loop {
+        let result = replay.poll(PROCESS_CHECK_INTERVAL, &mut |node, event| {
+            handlers.handle(node, event)
+        });
+        match result {
+            Ok(true) => handlers.expire(),
+            Ok(false) => break,
+            Err(e) => {
+                log::error!(
+                    target: LOG_TARGET,
+                    "Could not replay recording {}: {}",
+                    replay_config.path.display(),
+                    e
+                );
+                return;
+            }
+        }
+    }
+    log::info!(
+        target: LOG_TARGET,
+        "Finished replaying {} events from {}.",
+        replay.replayed(),
+        replay_config.path.display()
+    );
 }
@@ -0,0 +1,112 @@
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::{exponential_buckets, HistogramOpts, HistogramVec};
+
+use crate::metrics;
+
+/// P2P message types grouped by their typical size. Each group has its own
+/// histogram bucket layout. The order matches SIZE_CLASSES.
+#[derive(Clone, Copy)]
+enum SizeClass {
+    /// Control messages like version, ping or feefilter of a few bytes.
+    Control,
+    /// Lists of inventory, addresses or headers of up to a few MB.
+    Inventory,
+    /// Transactions.
+    Transaction,
+    /// Full and compact blocks.
+    Block,
+    /// Unknown message types.
+    Other,
+}
+
+const SIZE_CLASSES: [SizeClass; 5] = [
+    SizeClass::Control,
+    SizeClass::Inventory,
+    SizeClass::Transaction,
+    SizeClass::Block,
+    SizeClass::Other,
+];
+
+impl SizeClass {
+    fn of(msg_type: &str) -> SizeClass {
+        match msg_type {
+            "version" | "verack" | "ping" | "pong" | "sendheaders" | "sendcmpct" | "feefilter"
+            | "wtxidrelay" | "sendaddrv2" | "getaddr" | "mempool" | "filterclear" => {
+                SizeClass::Control
+            }
+            "inv" | "getdata" | "notfound" | "addr" | "addrv2" | "headers" | "getheaders"
+            | "getblocks" | "getblocktxn" | "merkleblock" | "filterload" | "filteradd"
+            | "getcfilters" | "cfilter" | "getcfheaders" | "cfheaders" | "getcfcheckpt"
+            | "cfcheckpt" => SizeClass::Inventory,
+            "tx" => SizeClass::Transaction,
+            "block" | "cmpctblock" | "blocktxn" => SizeClass::Block,
+            _ => SizeClass::Other,
+        }
+    }
+
+    fn buckets(self) -> Vec<f64> {
+        match self {
+            SizeClass::Control => vec![0.0, 8.0, 16.0, 32.0, 64.0, 128.0, 256.0, 512.0],
+            // 64 B to 4 MB
+            SizeClass::Inventory => exponential_buckets(64.0, 4.0, 9).unwrap(),
+            // 128 B to 512 kB
+            SizeClass::Transaction => exponential_buckets(128.0, 2.0, 13).unwrap(),
+            // 1 kB to 4 MB
+            SizeClass::Block => exponential_buckets(1024.0, 2.0, 13).unwrap(),
+            // 16 B to 4 MB
+            SizeClass::Other => exponential_buckets(16.0, 4.0, 10).unwrap(),
+        }
+    }
+}
+
+/// Histograms of P2P message sizes by message type. Exposed as a single
+/// metric, but the bucket layout depends on the message type, as e.g. `tx`
+/// and `inv` messages have very different size distributions. The prometheus
+/// crate only supports one bucket layout per HistogramVec, so this holds one
+/// HistogramVec per size class and merges them when collecting.
+#[derive(Clone)]
+pub struct MessageSizeHistograms {
+    desc: Desc,
+    histograms: Vec<HistogramVec>,
+}
+
+impl MessageSizeHistograms {
+    pub fn new(opts: HistogramOpts) -> prometheus::Result<Self> {
+        let mut histograms = vec![];
+        for class in SIZE_CLASSES.iter() {
+            histograms.push(HistogramVec::new(
+                opts.clone().buckets(class.buckets()),
+                &[metrics::LABEL_NODE, metrics::LABEL_P2P_MSG_TYPE],
+            )?);
+        }
+        let desc = histograms[0].desc()[0].clone();
+        Ok(MessageSizeHistograms { desc, histograms })
+    }
+
+    pub fn observe(&self, node: &str, msg_type: &str, size: u64) {
+        self.histograms[SizeClass::of(msg_type) as usize]
+            .with_label_values(&[node, msg_type])
+            .observe(size as f64);
+    }
+}
+
+impl Collector for MessageSizeHistograms {
+    fn desc(&self) -> Vec<&Desc> {
+        vec![&self.desc]
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let mut families = self.histograms.iter().flat_map(|h| h.collect());
+        let mut merged = match families.next() {
+            Some(family) => family,
+            None => return vec![],
+        };
+        for mut family in families {
+            for metric in family.take_metric().into_iter() {
+                merged.mut_metric().push(metric);
+            }
+        }
+        vec![merged]
+    }
+}
@@ -1,6 +1,12 @@
 use lazy_static::lazy_static;
-use prometheus::{self, IntCounter, IntCounterVec, IntGauge};
-use prometheus::{register_int_counter, register_int_counter_vec, register_int_gauge, Opts};
+use prometheus::{self, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec};
+use prometheus::{
+    exponential_buckets, register_histogram_vec, register_int_counter, register_int_counter_vec,
+    register_int_gauge, register_int_gauge_vec, HistogramOpts, Opts,
+};
+use std::sync::RwLock;
+
+use crate::message_sizes::MessageSizeHistograms;
 
 // Prometheus Metrics
 
@@ -10,15 +16,50 @@ const SUBSYSTEM_RUNTIME: &str = "runtime";
 const SUBSYSTEM_P2P: &str = "p2p";
 const SUBSYSTEM_VALIDATION: &str = "validation";
 const SUBSYSTEM_UTXOCACHE: &str = "utxocache";
+const SUBSYSTEM_MEMPOOL: &str = "mempool";
+
+pub const LABEL_NODE: &str = "node";
+
+pub const LABEL_RUNTIME_ATTACH_MODE: &str = "mode";
+pub const LABEL_RUNTIME_PROBE: &str = "probe";
+pub const LABEL_RUNTIME_BUFFER: &str = "buffer";
+pub const LABEL_RUNTIME_PUSH_TARGET: &str = "target";
+pub const LABEL_RUNTIME_SINK: &str = "sink";
 
 pub const LABEL_P2P_MSG_TYPE: &str = "msg_type";
 pub const LABEL_P2P_CONNECTION_TYPE: &str = "connection_type";
+pub const LABEL_P2P_DIRECTION: &str = "direction";
+pub const LABEL_P2P_NETWORK: &str = "network";
+pub const LABEL_P2P_PEER_ID: &str = "peer_id";
+pub const LABEL_P2P_PEER_ADDR: &str = "addr";
 
 pub const LABEL_UTXOCACHE_FLUSH_MODE: &str = "flush_mode";
 pub const LABEL_UTXOCACHE_FLUSH_FORPRUNE: &str = "for_prune";
 
+pub const LABEL_MEMPOOL_REASON: &str = "reason";
+
+/// Default histogram buckets for the block connection duration in seconds.
+pub const DEFAULT_BLOCK_CONNECTION_DURATION_BUCKETS: [f64; 14] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+/// Sets the histogram buckets for the block connection duration. Must be
+/// called before the first block connection is observed.
+pub fn set_block_connection_duration_buckets(buckets: Vec<f64>) {
+    *BLOCK_CONNECTION_DURATION_BUCKETS.write().unwrap() = buckets;
+}
+
+fn register_message_size_histograms(opts: HistogramOpts) -> MessageSizeHistograms {
+    let histograms = MessageSizeHistograms::new(opts).unwrap();
+    prometheus::register(Box::new(histograms.clone())).unwrap();
+    histograms
+}
+
 lazy_static! {
 
+    static ref BLOCK_CONNECTION_DURATION_BUCKETS: RwLock<Vec<f64>> =
+        RwLock::new(DEFAULT_BLOCK_CONNECTION_DURATION_BUCKETS.to_vec());
+
     // -------------------- Runtime
 
     /// UNIX epoch timestamp of bitcoind-observer start. Can be used to alert on
@@ -30,6 +71,84 @@ lazy_static! {
                 .subsystem(SUBSYSTEM_RUNTIME)
         ).unwrap();
 
+    /// PID of the traced bitcoind process. 0 when attached to all processes
+    /// running the bitcoind binary or when not attached.
+    pub static ref RUNTIME_BITCOIND_PID: IntGaugeVec =
+        register_int_gauge_vec!(
+            Opts::new("bitcoind_pid", "PID of the traced bitcoind process (0 if not attached by PID).")
+                .namespace(NAMESPACE)
+                .subsystem(SUBSYSTEM_RUNTIME),
+            &[LABEL_NODE]
+        ).unwrap();
+
+    /// Whether the node is attached to (1) or not (0), with how the traced
+    /// process is selected: all processes running the binary, by PID or by
+    /// pidfile.
+    pub static ref RUNTIME_BITCOIND_ATTACHED: IntGaugeVec =
+        register_int_gauge_vec!(
+            Opts::new("bitcoind_attached", "Whether the node is attached to (1) or not (0), by mode of process selection (all, pid or pidfile).")
+                .namespace(NAMESPACE)
+                .subsystem(SUBSYSTEM_RUNTIME),
+            &[LABEL_NODE, LABEL_RUNTIME_ATTACH_MODE]
+        ).unwrap();
+
+    /// Whether a tracepoint probe is attached (1) or was skipped (0), e.g.
+    /// because the tracepoint is missing in the bitcoind binary.
+    pub static ref RUNTIME_PROBE_ATTACHED: IntGaugeVec =
+        register_int_gauge_vec!(
+            Opts::new("probe_attached", "Whether a tracepoint probe is attached (1) or was skipped (0).")
+                .namespace(NAMESPACE)
+                .subsystem(SUBSYSTEM_RUNTIME),
+            &[LABEL_NODE, LABEL_RUNTIME_PROBE]
+        ).unwrap();
+
+    /// Events lost because a perf buffer was full.
+    pub static ref RUNTIME_EVENTS_LOST: IntCounterVec =
+        register_int_counter_vec!(
+            Opts::new("events_lost_total", "Events lost because a perf buffer was full.")
+                .namespace(NAMESPACE)
+                .subsystem(SUBSYSTEM_RUNTIME),
+            &[LABEL_NODE, LABEL_RUNTIME_BUFFER]
+        ).unwrap();
+
+    /// Events that couldn't be decoded, e.g. because their size doesn't
+    /// match the expected struct.
+    pub static ref RUNTIME_DECODE_FAILURES: IntCounterVec =
+        register_int_counter_vec!(
+            Opts::new("decode_failures_total", "Events that could not be decoded.")
+                .namespace(NAMESPACE)
+                .subsystem(SUBSYSTEM_RUNTIME),
+            &[LABEL_NODE, LABEL_RUNTIME_BUFFER]
+        ).unwrap();
+
+    /// Events not sent to an event stream subscriber because the subscriber
+    /// was too slow.
+    pub static ref RUNTIME_STREAM_EVENTS_DROPPED: IntCounter =
+        register_int_counter!(
+            Opts::new("stream_events_dropped_total", "Events dropped for slow event stream subscribers.")
+                .namespace(NAMESPACE)
+                .subsystem(SUBSYSTEM_RUNTIME)
+        ).unwrap();
+
+    /// Pushes to a Pushgateway or remote-write endpoint that failed after
+    /// all retries.
+    pub static ref RUNTIME_PUSH_FAILURES: IntCounterVec =
+        register_int_counter_vec!(
+            Opts::new("push_failures_total", "Pushes of the metrics that failed after all retries.")
+                .namespace(NAMESPACE)
+                .subsystem(SUBSYSTEM_RUNTIME),
+            &[LABEL_RUNTIME_PUSH_TARGET]
+        ).unwrap();
+
+    /// Sends of an output sink that failed. The lines are dropped.
+    pub static ref RUNTIME_SINK_FAILURES: IntCounterVec =
+        register_int_counter_vec!(
+            Opts::new("sink_failures_total", "Sends of an output sink that failed.")
+                .namespace(NAMESPACE)
+                .subsystem(SUBSYSTEM_RUNTIME),
+            &[LABEL_RUNTIME_SINK]
+        ).unwrap();
+
     // -------------------- P2P
 
     /// Number of inbound P2P network messages received.
@@ -38,7 +157,7 @@ lazy_static! {
             Opts::new("message_inbound_count", "Number of inbound P2P network messages received.")
                 .namespace(NAMESPACE)
                 .subsystem(SUBSYSTEM_P2P),
-            &[LABEL_P2P_MSG_TYPE, LABEL_P2P_CONNECTION_TYPE]
+            &[LABEL_NODE, LABEL_P2P_MSG_TYPE, LABEL_P2P_CONNECTION_TYPE, LABEL_P2P_NETWORK]
         ).unwrap();
 
     /// Number of outbound P2P network messages send.
@@ -47,7 +166,7 @@ lazy_static! {
             Opts::new("message_outbound_count", "Number of outbound P2P network messages send.")
                 .namespace(NAMESPACE)
                 .subsystem(SUBSYSTEM_P2P),
-            &[LABEL_P2P_MSG_TYPE, LABEL_P2P_CONNECTION_TYPE]
+            &[LABEL_NODE, LABEL_P2P_MSG_TYPE, LABEL_P2P_CONNECTION_TYPE, LABEL_P2P_NETWORK]
         ).unwrap();
 
     /// Number of inbound P2P network messages bytes received.
@@ -56,7 +175,7 @@ lazy_static! {
         Opts::new("message_inbound_bytes", "Number of inbound P2P network messages bytes received.")
             .namespace(NAMESPACE)
             .subsystem(SUBSYSTEM_P2P),
-        &[LABEL_P2P_MSG_TYPE, LABEL_P2P_CONNECTION_TYPE]
+        &[LABEL_NODE, LABEL_P2P_MSG_TYPE, LABEL_P2P_CONNECTION_TYPE, LABEL_P2P_NETWORK]
     ).unwrap();
 
     /// Number of outbound P2P network messages bytes send.
@@ -65,83 +184,249 @@ lazy_static! {
             Opts::new("message_outbound_bytes", "Number of outbound P2P network messages bytes send..")
                 .namespace(NAMESPACE)
                 .subsystem(SUBSYSTEM_P2P),
-            &[LABEL_P2P_MSG_TYPE, LABEL_P2P_CONNECTION_TYPE]
+            &[LABEL_NODE, LABEL_P2P_MSG_TYPE, LABEL_P2P_CONNECTION_TYPE, LABEL_P2P_NETWORK]
+        ).unwrap();
+
+    /// Number of inbound P2P network messages received per peer. Only exposed with per-peer metrics enabled.
+    pub static ref P2P_PEER_MESSAGE_INBOUND_COUNT: IntCounterVec =
+        register_int_counter_vec!(
+            Opts::new("peer_message_inbound_count", "Number of inbound P2P network messages received per peer.")
+                .namespace(NAMESPACE)
+                .subsystem(SUBSYSTEM_P2P),
+            &[LABEL_NODE, LABEL_P2P_PEER_ID, LABEL_P2P_PEER_ADDR, LABEL_P2P_NETWORK, LABEL_P2P_CONNECTION_TYPE]
         ).unwrap();
 
+    /// Number of outbound P2P network messages send per peer. Only exposed with per-peer metrics enabled.
+    pub static ref P2P_PEER_MESSAGE_OUTBOUND_COUNT: IntCounterVec =
+        register_int_counter_vec!(
+            Opts::new("peer_message_outbound_count", "Number of outbound P2P network messages send per peer.")
+                .namespace(NAMESPACE)
+                .subsystem(SUBSYSTEM_P2P),
+            &[LABEL_NODE, LABEL_P2P_PEER_ID, LABEL_P2P_PEER_ADDR, LABEL_P2P_NETWORK, LABEL_P2P_CONNECTION_TYPE]
+        ).unwrap();
+
+    /// Number of inbound P2P network message bytes received per peer. Only exposed with per-peer metrics enabled.
+    pub static ref P2P_PEER_MESSAGE_INBOUND_BYTE: IntCounterVec =
+        register_int_counter_vec!(
+            Opts::new("peer_message_inbound_bytes", "Number of inbound P2P network message bytes received per peer.")
+                .namespace(NAMESPACE)
+                .subsystem(SUBSYSTEM_P2P),
+            &[LABEL_NODE, LABEL_P2P_PEER_ID, LABEL_P2P_PEER_ADDR, LABEL_P2P_NETWORK, LABEL_P2P_CONNECTION_TYPE]
+        ).unwrap();
+
+    /// Number of outbound P2P network message bytes send per peer. Only exposed with per-peer metrics enabled.
+    pub static ref P2P_PEER_MESSAGE_OUTBOUND_BYTE: IntCounterVec =
+        register_int_counter_vec!(
+            Opts::new("peer_message_outbound_bytes", "Number of outbound P2P network message bytes send per peer.")
+                .namespace(NAMESPACE)
+                .subsystem(SUBSYSTEM_P2P),
+            &[LABEL_NODE, LABEL_P2P_PEER_ID, LABEL_P2P_PEER_ADDR, LABEL_P2P_NETWORK, LABEL_P2P_CONNECTION_TYPE]
+        ).unwrap();
+
+    /// Size of inbound P2P messages in bytes by message type.
+    pub static ref P2P_MESSAGE_INBOUND_SIZE: MessageSizeHistograms =
+        register_message_size_histograms(
+            HistogramOpts::new("message_inbound_size_bytes", "Size of inbound P2P messages in bytes.")
+                .namespace(NAMESPACE)
+                .subsystem(SUBSYSTEM_P2P)
+        );
+
+    /// Size of outbound P2P messages in bytes by message type.
+    pub static ref P2P_MESSAGE_OUTBOUND_SIZE: MessageSizeHistograms =
+        register_message_size_histograms(
+            HistogramOpts::new("message_outbound_size_bytes", "Size of outbound P2P messages in bytes.")
+                .namespace(NAMESPACE)
+                .subsystem(SUBSYSTEM_P2P)
+        );
+
+    /// Number of currently open P2P connections per direction. bitcoind
+    /// reports the number of existing connections in the direction when a
+    /// connection is opened, so the gauge is exact from the first connection
+    /// opened in a direction since attaching.
+    pub static ref P2P_CONNECTIONS_OPEN: IntGaugeVec =
+    register_int_gauge_vec!(
+        Opts::new("connections_open", "Number of currently open P2P connections per direction. Exact, as reported by bitcoind when a connection is opened.")
+            .namespace(NAMESPACE)
+            .subsystem(SUBSYSTEM_P2P),
+        &[LABEL_NODE, LABEL_P2P_DIRECTION]
+    ).unwrap();
+
+    /// Number of currently open P2P connections that were opened since
+    /// attaching to bitcoind. Connections opened before aren't known, as
+    /// there is no tracepoint listing the existing connections.
+    pub static ref P2P_CONNECTIONS_OPEN_SINCE_ATTACH: IntGaugeVec =
+    register_int_gauge_vec!(
+        Opts::new("connections_open_since_attach", "Number of currently open P2P connections that were opened since attaching to bitcoind. Not exact: misses connections opened before attaching, see connections_open.")
+            .namespace(NAMESPACE)
+            .subsystem(SUBSYSTEM_P2P),
+        &[LABEL_NODE, LABEL_P2P_CONNECTION_TYPE, LABEL_P2P_NETWORK]
+    ).unwrap();
+
+    /// Number of opened P2P connections.
+    pub static ref P2P_CONNECTIONS_OPENED: IntCounterVec =
+    register_int_counter_vec!(
+        Opts::new("connections_opened", "Number of opened P2P connections.")
+            .namespace(NAMESPACE)
+            .subsystem(SUBSYSTEM_P2P),
+        &[LABEL_NODE, LABEL_P2P_CONNECTION_TYPE, LABEL_P2P_NETWORK]
+    ).unwrap();
+
+    /// Number of closed P2P connections.
+    pub static ref P2P_CONNECTIONS_CLOSED: IntCounterVec =
+    register_int_counter_vec!(
+        Opts::new("connections_closed", "Number of closed P2P connections.")
+            .namespace(NAMESPACE)
+            .subsystem(SUBSYSTEM_P2P),
+        &[LABEL_NODE, LABEL_P2P_CONNECTION_TYPE, LABEL_P2P_NETWORK]
+    ).unwrap();
+
+    /// Number of evicted inbound P2P connections.
+    pub static ref P2P_CONNECTIONS_EVICTED: IntCounterVec =
+    register_int_counter_vec!(
+        Opts::new("connections_evicted", "Number of evicted inbound P2P connections.")
+            .namespace(NAMESPACE)
+            .subsystem(SUBSYSTEM_P2P),
+        &[LABEL_NODE, LABEL_P2P_CONNECTION_TYPE, LABEL_P2P_NETWORK]
+    ).unwrap();
+
+    /// Number of times a peer was punished for misbehaving.
+    pub static ref P2P_CONNECTIONS_MISBEHAVING: IntCounterVec =
+    register_int_counter_vec!(
+        Opts::new("connections_misbehaving", "Number of times a peer was punished for misbehaving.")
+            .namespace(NAMESPACE)
+            .subsystem(SUBSYSTEM_P2P),
+        &[LABEL_NODE]
+    ).unwrap();
+
+    /// Lifetime of closed P2P connections in seconds. Only includes
+    /// connections opened while being observed.
+    pub static ref P2P_CONNECTION_LIFETIME: HistogramVec =
+    register_histogram_vec!(
+        HistogramOpts::new("connection_lifetime_seconds", "Lifetime of closed P2P connections in seconds.")
+            .namespace(NAMESPACE)
+            .subsystem(SUBSYSTEM_P2P)
+            // 1s, 4s, 16s, ~1m, ~4m, ~17m, ~1h, ~4.5h, ~18h, ~3d
+            .buckets(exponential_buckets(1.0, 4.0, 10).unwrap()),
+        &[LABEL_NODE, LABEL_P2P_CONNECTION_TYPE, LABEL_P2P_NETWORK]
+    ).unwrap();
+
     // -------------------- VALIDATION
 
     /// Last block height connected
-    pub static ref VALIDATION_BLOCK_CONNECTED_HEIGHT_LAST: IntGauge =
-    register_int_gauge!(
+    pub static ref VALIDATION_BLOCK_CONNECTED_HEIGHT_LAST: IntGaugeVec =
+    register_int_gauge_vec!(
         Opts::new("block_connected_height_last", "Last block height connected.")
             .namespace(NAMESPACE)
-            .subsystem(SUBSYSTEM_VALIDATION)
+            .subsystem(SUBSYSTEM_VALIDATION),
+        &[LABEL_NODE]
     ).unwrap();
 
     /// Number of connected blocks
-    pub static ref VALIDATION_BLOCK_CONNECTED_COUNT: IntCounter =
-    register_int_counter!(
+    pub static ref VALIDATION_BLOCK_CONNECTED_COUNT: IntCounterVec =
+    register_int_counter_vec!(
         Opts::new("block_connected_count", "Number of connected blocks.")
             .namespace(NAMESPACE)
-            .subsystem(SUBSYSTEM_VALIDATION)
+            .subsystem(SUBSYSTEM_VALIDATION),
+        &[LABEL_NODE]
     ).unwrap();
 
     /// Number of transactions in the connected blocks
-    pub static ref VALIDATION_BLOCK_CONNECTED_TRANSACTION_COUNT: IntCounter =
-    register_int_counter!(
+    pub static ref VALIDATION_BLOCK_CONNECTED_TRANSACTION_COUNT: IntCounterVec =
+    register_int_counter_vec!(
         Opts::new("block_connected_transaction_count", "Number of transactions in the connected blocks.")
             .namespace(NAMESPACE)
-            .subsystem(SUBSYSTEM_VALIDATION)
+            .subsystem(SUBSYSTEM_VALIDATION),
+        &[LABEL_NODE]
     ).unwrap();
 
     /// Number of inputs in the connected blocks
-    pub static ref VALIDATION_BLOCK_CONNECTED_INPUT_COUNT: IntCounter =
-    register_int_counter!(
+    pub static ref VALIDATION_BLOCK_CONNECTED_INPUT_COUNT: IntCounterVec =
+    register_int_counter_vec!(
         Opts::new("block_connected_input_count", "Number of inputs in the connected blocks.")
             .namespace(NAMESPACE)
-            .subsystem(SUBSYSTEM_VALIDATION)
+            .subsystem(SUBSYSTEM_VALIDATION),
+        &[LABEL_NODE]
     ).unwrap();
 
     /// Number of sigops in the connected blocks
-    pub static ref VALIDATION_BLOCK_CONNECTED_SIGOP_COUNT: IntCounter =
-    register_int_counter!(
+    pub static ref VALIDATION_BLOCK_CONNECTED_SIGOP_COUNT: IntCounterVec =
+    register_int_counter_vec!(
         Opts::new("block_connected_sigops_count", "Number of sigops in the connected blocks.")
+            .namespace(NAMESPACE)
+            .subsystem(SUBSYSTEM_VALIDATION),
+        &[LABEL_NODE]
+    ).unwrap();
+
+    /// Time it took to connect a block in seconds
+    pub static ref VALIDATION_BLOCK_CONNECTION_DURATION: HistogramVec =
+    register_histogram_vec!(
+        HistogramOpts::new("block_connection_duration_seconds", "Time it took to connect a block in seconds.")
             .namespace(NAMESPACE)
             .subsystem(SUBSYSTEM_VALIDATION)
+            .buckets(BLOCK_CONNECTION_DURATION_BUCKETS.read().unwrap().clone()),
+        &[LABEL_NODE]
     ).unwrap();
 
-    /// Time block connection took in microseconds (µs)
-    pub static ref VALIDATION_BLOCK_CONNECTED_TIMING: IntCounter =
-    register_int_counter!(
-        Opts::new("block_connected_timing", "Time block connection took in microseconds (µs).")
+    /// Number of transactions per connected block
+    pub static ref VALIDATION_BLOCK_TRANSACTIONS: HistogramVec =
+    register_histogram_vec!(
+        HistogramOpts::new("block_transactions", "Number of transactions per connected block.")
             .namespace(NAMESPACE)
             .subsystem(SUBSYSTEM_VALIDATION)
+            // 1 to 16384
+            .buckets(exponential_buckets(1.0, 2.0, 15).unwrap()),
+        &[LABEL_NODE]
+    ).unwrap();
+
+    /// Number of inputs per connected block
+    pub static ref VALIDATION_BLOCK_INPUTS: HistogramVec =
+    register_histogram_vec!(
+        HistogramOpts::new("block_inputs", "Number of inputs per connected block.")
+            .namespace(NAMESPACE)
+            .subsystem(SUBSYSTEM_VALIDATION)
+            // 1 to 65536
+            .buckets(exponential_buckets(1.0, 2.0, 17).unwrap()),
+        &[LABEL_NODE]
+    ).unwrap();
+
+    /// Number of sigops per connected block
+    pub static ref VALIDATION_BLOCK_SIGOPS: HistogramVec =
+    register_histogram_vec!(
+        HistogramOpts::new("block_sigops", "Number of sigops per connected block.")
+            .namespace(NAMESPACE)
+            .subsystem(SUBSYSTEM_VALIDATION)
+            // 1 to 65536, the block sigop limit is 80000
+            .buckets(exponential_buckets(1.0, 2.0, 17).unwrap()),
+        &[LABEL_NODE]
     ).unwrap();
 
     // -------------------- UTXO Cache
 
     /// Additions to the UTXO set cache.
-    pub static ref UTXOCACHE_ADD: IntCounter =
-    register_int_counter!(
+    pub static ref UTXOCACHE_ADD: IntCounterVec =
+    register_int_counter_vec!(
         Opts::new("add", "Additions to the UTXO set cache.")
             .namespace(NAMESPACE)
-            .subsystem(SUBSYSTEM_UTXOCACHE)
+            .subsystem(SUBSYSTEM_UTXOCACHE),
+        &[LABEL_NODE]
     ).unwrap();
 
     /// Spents from the UTXO set cache.
-    pub static ref UTXOCACHE_SPENT: IntCounter =
-    register_int_counter!(
+    pub static ref UTXOCACHE_SPENT: IntCounterVec =
+    register_int_counter_vec!(
         Opts::new("spent", "Spents from the UTXO set cache.")
             .namespace(NAMESPACE)
-            .subsystem(SUBSYSTEM_UTXOCACHE)
+            .subsystem(SUBSYSTEM_UTXOCACHE),
+        &[LABEL_NODE]
     ).unwrap();
 
     /// Uncaches from the UTXO set cache.
-    pub static ref UTXOCACHE_UNCACHE: IntCounter =
-    register_int_counter!(
+    pub static ref UTXOCACHE_UNCACHE: IntCounterVec =
+    register_int_counter_vec!(
         Opts::new("uncache", "Uncaches from the UTXO set cache.")
             .namespace(NAMESPACE)
-            .subsystem(SUBSYSTEM_UTXOCACHE)
+            .subsystem(SUBSYSTEM_UTXOCACHE),
+        &[LABEL_NODE]
     ).unwrap();
 
     /// UTXO set cache flush.
@@ -150,7 +435,7 @@ lazy_static! {
         Opts::new("flush", "UTXO set cache flush.")
             .namespace(NAMESPACE)
             .subsystem(SUBSYSTEM_UTXOCACHE),
-            &[LABEL_UTXOCACHE_FLUSH_MODE, LABEL_UTXOCACHE_FLUSH_FORPRUNE]
+            &[LABEL_NODE, LABEL_UTXOCACHE_FLUSH_MODE, LABEL_UTXOCACHE_FLUSH_FORPRUNE]
     ).unwrap();
 
     /// Total UTXO set cache flush duration.
@@ -159,7 +444,7 @@ lazy_static! {
         Opts::new("flush_duration", "Total UTXO set cache flush duration.")
             .namespace(NAMESPACE)
             .subsystem(SUBSYSTEM_UTXOCACHE),
-            &[LABEL_UTXOCACHE_FLUSH_MODE, LABEL_UTXOCACHE_FLUSH_FORPRUNE]
+            &[LABEL_NODE, LABEL_UTXOCACHE_FLUSH_MODE, LABEL_UTXOCACHE_FLUSH_FORPRUNE]
     ).unwrap();
 
     /// Total UTXO set cache coins flushed.
@@ -168,7 +453,7 @@ lazy_static! {
         Opts::new("flush_coins_count", "Total UTXO set cache coins flushed.")
             .namespace(NAMESPACE)
             .subsystem(SUBSYSTEM_UTXOCACHE),
-            &[LABEL_UTXOCACHE_FLUSH_MODE, LABEL_UTXOCACHE_FLUSH_FORPRUNE]
+            &[LABEL_NODE, LABEL_UTXOCACHE_FLUSH_MODE, LABEL_UTXOCACHE_FLUSH_FORPRUNE]
     ).unwrap();
 
     /// Total UTXO set cache memory flushed.
@@ -177,6 +462,116 @@ lazy_static! {
         Opts::new("flush_coins_memusage", "Total UTXO set cache memory flushed.")
             .namespace(NAMESPACE)
             .subsystem(SUBSYSTEM_UTXOCACHE),
-            &[LABEL_UTXOCACHE_FLUSH_MODE, LABEL_UTXOCACHE_FLUSH_FORPRUNE]
+            &[LABEL_NODE, LABEL_UTXOCACHE_FLUSH_MODE, LABEL_UTXOCACHE_FLUSH_FORPRUNE]
+    ).unwrap();
+
+    // -------------------- Mempool
+
+    /// Transactions added to the mempool.
+    pub static ref MEMPOOL_ADDED: IntCounterVec =
+    register_int_counter_vec!(
+        Opts::new("added", "Transactions added to the mempool.")
+            .namespace(NAMESPACE)
+            .subsystem(SUBSYSTEM_MEMPOOL),
+        &[LABEL_NODE]
+    ).unwrap();
+
+    /// Total virtual size (vbyte) of transactions added to the mempool.
+    pub static ref MEMPOOL_ADDED_VSIZE: IntCounterVec =
+    register_int_counter_vec!(
+        Opts::new("added_vsize", "Total virtual size (vbyte) of transactions added to the mempool.")
+            .namespace(NAMESPACE)
+            .subsystem(SUBSYSTEM_MEMPOOL),
+        &[LABEL_NODE]
+    ).unwrap();
+
+    /// Total fees (sat) of transactions added to the mempool.
+    pub static ref MEMPOOL_ADDED_FEE: IntCounterVec =
+    register_int_counter_vec!(
+        Opts::new("added_fee", "Total fees (sat) of transactions added to the mempool.")
+            .namespace(NAMESPACE)
+            .subsystem(SUBSYSTEM_MEMPOOL),
+        &[LABEL_NODE]
+    ).unwrap();
+
+    /// Transactions removed from the mempool.
+    pub static ref MEMPOOL_REMOVED: IntCounterVec =
+    register_int_counter_vec!(
+        Opts::new("removed", "Transactions removed from the mempool.")
+            .namespace(NAMESPACE)
+            .subsystem(SUBSYSTEM_MEMPOOL),
+        &[LABEL_NODE, LABEL_MEMPOOL_REASON]
+    ).unwrap();
+
+    /// Total virtual size (vbyte) of transactions removed from the mempool.
+    pub static ref MEMPOOL_REMOVED_VSIZE: IntCounterVec =
+    register_int_counter_vec!(
+        Opts::new("removed_vsize", "Total virtual size (vbyte) of transactions removed from the mempool.")
+            .namespace(NAMESPACE)
+            .subsystem(SUBSYSTEM_MEMPOOL),
+        &[LABEL_NODE, LABEL_MEMPOOL_REASON]
+    ).unwrap();
+
+    /// Total fees (sat) of transactions removed from the mempool.
+    pub static ref MEMPOOL_REMOVED_FEE: IntCounterVec =
+    register_int_counter_vec!(
+        Opts::new("removed_fee", "Total fees (sat) of transactions removed from the mempool.")
+            .namespace(NAMESPACE)
+            .subsystem(SUBSYSTEM_MEMPOOL),
+        &[LABEL_NODE, LABEL_MEMPOOL_REASON]
+    ).unwrap();
+
+    /// Mempool transactions replaced by another transaction.
+    pub static ref MEMPOOL_REPLACED: IntCounterVec =
+    register_int_counter_vec!(
+        Opts::new("replaced", "Mempool transactions replaced by another transaction.")
+            .namespace(NAMESPACE)
+            .subsystem(SUBSYSTEM_MEMPOOL),
+        &[LABEL_NODE]
+    ).unwrap();
+
+    /// Total virtual size (vbyte) of replaced mempool transactions.
+    pub static ref MEMPOOL_REPLACED_VSIZE: IntCounterVec =
+    register_int_counter_vec!(
+        Opts::new("replaced_vsize", "Total virtual size (vbyte) of replaced mempool transactions.")
+            .namespace(NAMESPACE)
+            .subsystem(SUBSYSTEM_MEMPOOL),
+        &[LABEL_NODE]
+    ).unwrap();
+
+    /// Total fees (sat) of replaced mempool transactions.
+    pub static ref MEMPOOL_REPLACED_FEE: IntCounterVec =
+    register_int_counter_vec!(
+        Opts::new("replaced_fee", "Total fees (sat) of replaced mempool transactions.")
+            .namespace(NAMESPACE)
+            .subsystem(SUBSYSTEM_MEMPOOL),
+        &[LABEL_NODE]
+    ).unwrap();
+
+    /// Total virtual size (vbyte) of replacement transactions.
+    pub static ref MEMPOOL_REPLACEMENT_VSIZE: IntCounterVec =
+    register_int_counter_vec!(
+        Opts::new("replacement_vsize", "Total virtual size (vbyte) of replacement transactions.")
+            .namespace(NAMESPACE)
+            .subsystem(SUBSYSTEM_MEMPOOL),
+        &[LABEL_NODE]
+    ).unwrap();
+
+    /// Total fees (sat) of replacement transactions.
+    pub static ref MEMPOOL_REPLACEMENT_FEE: IntCounterVec =
+    register_int_counter_vec!(
+        Opts::new("replacement_fee", "Total fees (sat) of replacement transactions.")
+            .namespace(NAMESPACE)
+            .subsystem(SUBSYSTEM_MEMPOOL),
+        &[LABEL_NODE]
+    ).unwrap();
+
+    /// Transactions rejected from the mempool.
+    pub static ref MEMPOOL_REJECTED: IntCounterVec =
+    register_int_counter_vec!(
+        Opts::new("rejected", "Transactions rejected from the mempool.")
+            .namespace(NAMESPACE)
+            .subsystem(SUBSYSTEM_MEMPOOL),
+        &[LABEL_NODE, LABEL_MEMPOOL_REASON]
     ).unwrap();
 }
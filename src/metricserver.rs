@@ -1,26 +1,103 @@
 use std::error;
 use std::fmt;
 use std::io;
-use std::io::{Read, Write};
-use std::net::TcpListener;
-use std::net::TcpStream;
-use std::string::FromUtf8Error;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, TrySendError};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
+use std::time::Duration;
 
 use prometheus::Encoder;
+use rustls::{ServerConnection, StreamOwned};
+use signal_hook::consts::SIGHUP;
+use signal_hook::iterator::Signals;
+
+use crate::blocks;
+use crate::events::{self, EventFilter};
+use crate::http::{self, Connection, HttpError, Request, Response, TimedStream};
+use crate::openmetrics;
+use crate::webconfig::WebConfig;
 
 const LOG_TARGET: &str = "metricserver";
 
-// This is a minimal, per request thread spawning, and incorrect HTTP server
-// which answers on all request methods with prometheus formatted metrics.
+/// Interval in which a comment is sent on idle event streams to detect
+/// closed connections and to keep proxies from timing out the stream.
+const EVENT_STREAM_KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// Number of threads handling connections.
+pub const WORKERS: usize = 4;
+/// Number of accepted connections waiting for a worker. Further connections
+/// are answered with 503 Service Unavailable.
+pub const QUEUE_LENGTH: usize = 32;
+/// Time a client has to send a complete request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time a kept-alive connection may be idle before the next request. Idle
+/// connections occupy a worker, so this is kept short.
+const KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(2);
+/// Time a write to a client may block.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Number of requests served on a kept-alive connection before it's closed,
+/// so a single client can't keep a worker busy.
+const MAX_KEEP_ALIVE_REQUESTS: usize = 100;
+
+/// Number of attached event sources. The server reports ready on /ready once
+/// at least one is attached.
+static ATTACHED_SOURCES: AtomicUsize = AtomicUsize::new(0);
+
+// An HTTP/1.1 server with keep-alive, answering GET and HEAD requests to
+// /metrics with the metrics in the Prometheus text or the OpenMetrics format,
+// depending on the Accept header and optionally gzip compressed, and to
+// /blocks with the recently connected blocks as JSON. /health and /ready are
+// meant for liveness and readiness checks. Connections are handled by a fixed
+// number of workers. GET requests to /events are answered with a Server-Sent
+// Events stream of the traced events, each streamed from its own thread. TLS
+// and authentication are configured with a web config (see webconfig.rs).
+
+/// The web config, if any, shared by the connections and replaced on
+/// reload.
+type SharedWebConfig = Arc<RwLock<Option<Arc<WebConfig>>>>;
+
+/// An accepted connection waiting for a worker, with the web config at the
+/// time it was accepted.
+type Accepted = (TcpStream, Option<Arc<WebConfig>>);
 
-pub fn start(prometheus_address: &str) -> Result<(), io::Error> {
+/// Starts the server and returns the address it's listening on. With a web
+/// config, the server uses TLS and requires authentication as configured.
+/// The web config is reloaded on SIGHUP.
+pub fn start(
+    prometheus_address: &str,
+    web_config: Option<WebConfig>,
+) -> Result<SocketAddr, io::Error> {
     let listener = TcpListener::bind(prometheus_address)?;
+    let address = listener.local_addr()?;
+    let tls = web_config.as_ref().and_then(|c| c.tls()).is_some();
     log::info!(
         target: LOG_TARGET,
-        "Started Prometheus metric server listening on {}.",
-        prometheus_address
+        "Started Prometheus metric server listening on {}{}.",
+        address,
+        if tls { " with TLS" } else { "" }
     );
+
+    let web_config: SharedWebConfig = Arc::new(RwLock::new(web_config.map(Arc::new)));
+    if web_config.read().unwrap().is_some() {
+        let mut signals = Signals::new([SIGHUP])?;
+        let web_config = web_config.clone();
+        thread::spawn(move || {
+            for _ in signals.forever() {
+                reload(&web_config);
+            }
+        });
+    }
+
+    let (sender, receiver) = mpsc::sync_channel(QUEUE_LENGTH);
+    let receiver = Arc::new(Mutex::new(receiver));
+    for _ in 0..WORKERS {
+        let receiver = receiver.clone();
+        thread::spawn(move || work(receiver));
+    }
+
     thread::spawn(move || {
         for incoming_request in listener.incoming() {
             let stream = match incoming_request {
@@ -34,52 +111,334 @@ pub fn start(prometheus_address: &str) -> Result<(), io::Error> {
                     continue;
                 }
             };
-            if let Err(e) = handle_request(stream) {
-                log::error!(target: LOG_TARGET, "Could not handle request {}.", e);
-                continue;
-            };
+            let web_config = web_config.read().unwrap().clone();
+            match sender.try_send((stream, web_config)) {
+                Ok(()) => (),
+                Err(TrySendError::Full((mut stream, web_config))) => {
+                    log::warn!(
+                        target: LOG_TARGET,
+                        "All workers busy, rejecting a connection."
+                    );
+                    // TLS clients are disconnected without a response. The
+                    // response is written with a single non-blocking write,
+                    // so a client that doesn't read can't stall accepting.
+                    if web_config.and_then(|c| c.tls()).is_none() {
+                        let mut response = vec![];
+                        let _ = Response::text(503, "server busy").write_to(
+                            &mut response,
+                            false,
+                            false,
+                        );
+                        if stream.set_nonblocking(true).is_ok() {
+                            let _ = stream.write(&response);
+                        }
+                    }
+                }
+                Err(TrySendError::Disconnected(_)) => return,
+            }
         }
     });
+    Ok(address)
+}
+
+/// Marks an event source as attached. Until one is, /ready is answered with
+/// 503 Service Unavailable.
+pub fn source_attached() {
+    ATTACHED_SOURCES.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Marks a previously attached event source as detached.
+pub fn source_detached() {
+    ATTACHED_SOURCES.fetch_sub(1, Ordering::SeqCst);
+}
+
+/// Reloads the web config, e.g. to pick up renewed certificates. Keeps the
+/// current one if the reloaded one is invalid. Established connections keep
+/// the web config they were accepted with.
+fn reload(web_config: &SharedWebConfig) {
+    let current = match *web_config.read().unwrap() {
+        Some(ref current) => current.clone(),
+        None => return,
+    };
+    match current.reload() {
+        Ok(reloaded) => {
+            *web_config.write().unwrap() = Some(Arc::new(reloaded));
+            log::info!(
+                target: LOG_TARGET,
+                "Reloaded the web config {}.",
+                current.path().display()
+            );
+        }
+        Err(e) => log::error!(
+            target: LOG_TARGET,
+            "Could not reload the web config {}, keeping the current one: {}",
+            current.path().display(),
+            e
+        ),
+    }
+}
+
+fn work(receiver: Arc<Mutex<Receiver<Accepted>>>) {
+    loop {
+        let (stream, web_config) = match receiver.lock().unwrap().recv() {
+            Ok(connection) => connection,
+            Err(_) => return,
+        };
+        let stream = TimedStream::new(stream);
+        let result = stream
+            .set_write_timeout(WRITE_TIMEOUT)
+            .map_err(RequestHandlingError::from)
+            .and_then(|_| match web_config.as_ref().and_then(|c| c.tls()) {
+                Some(tls) => {
+                    let connection = ServerConnection::new(tls)?;
+                    let stream = StreamOwned::new(connection, stream);
+                    handle_connection(stream, web_config.as_deref())
+                }
+                None => handle_connection(stream, web_config.as_deref()),
+            });
+        if let Err(e) = result {
+            log::debug!(target: LOG_TARGET, "Could not handle connection: {}", e);
+        }
+    }
+}
+
+/// Serves the requests on a connection until the client or the server closes
+/// it, or the connection is handed off to an event stream.
+fn handle_connection<C>(
+    stream: C,
+    web_config: Option<&WebConfig>,
+) -> Result<(), RequestHandlingError>
+where
+    C: Connection + Send + 'static,
+{
+    let mut reader = BufReader::new(stream);
+
+    for served in 1..=MAX_KEEP_ALIVE_REQUESTS {
+        if served > 1 {
+            // Wait for the next request on a kept-alive connection.
+            reader.get_ref().set_read_deadline(KEEP_ALIVE_TIMEOUT);
+            match reader.fill_buf() {
+                Ok(_) => {}
+                Err(e) if http::is_timeout(&e) => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        reader.get_ref().set_read_deadline(REQUEST_TIMEOUT);
+        let request = match http::read_request(&mut reader) {
+            Ok(Some(request)) => request,
+            Ok(None) => break,
+            Err(HttpError::Io(e)) if !http::is_timeout(&e) => return Err(e.into()),
+            Err(e) => {
+                log::debug!(target: LOG_TARGET, "Could not read request: {}", e);
+                Response::text(e.status(), &e.to_string()).write_to(
+                    reader.get_mut(),
+                    false,
+                    false,
+                )?;
+                break;
+            }
+        };
+
+        let unauthorized = web_config.and_then(|c| c.authorize(&request));
+        if unauthorized.is_none() && request.path == "/events" && request.method == "GET" {
+            return events(reader.into_inner(), &request.query);
+        }
+
+        let response = match unauthorized {
+            Some(response) => response,
+            None => handle_request(&request).unwrap_or_else(|e| {
+                log::error!(target: LOG_TARGET, "Could not handle request {}.", e);
+                Response::text(500, "internal server error")
+            }),
+        };
+        let keep_alive = request.keep_alive() && served < MAX_KEEP_ALIVE_REQUESTS;
+        response.write_to(reader.get_mut(), request.method == "HEAD", keep_alive)?;
+        if !keep_alive {
+            break;
+        }
+    }
+    reader.get_mut().close();
     Ok(())
 }
 
-fn handle_request(mut stream: TcpStream) -> Result<(), RequestHandlingError> {
-    let mut buffer = [0; 1024];
-    let _ = stream.read(&mut buffer)?;
+fn handle_request(request: &Request) -> Result<Response, RequestHandlingError> {
+    let allowed = match request.path.as_str() {
+        "/metrics" | "/blocks" | "/health" | "/ready" => "GET, HEAD",
+        "/events" => "GET",
+        _ => return Ok(Response::text(404, "not found")),
+    };
+    if !allowed.split(", ").any(|method| method == request.method) {
+        return Ok(Response::text(405, "method not allowed").header("Allow", allowed));
+    }
+
+    let response = match request.path.as_str() {
+        "/metrics" => {
+            let response = match negotiate_format(request.header("Accept")) {
+                Some(format) => Response::new(200, format.content_type(), metrics(format)?),
+                None => Response::text(
+                    406,
+                    "supported formats: text/plain; version=0.0.4, application/openmetrics-text",
+                ),
+            };
+            response.header("Vary", "Accept, Accept-Encoding")
+        }
+        "/blocks" => blocks(&request.query)?,
+        "/health" => Response::text(200, "OK"),
+        "/ready" if ATTACHED_SOURCES.load(Ordering::SeqCst) > 0 => Response::text(200, "OK"),
+        "/ready" => Response::text(503, "no event source attached"),
+        _ => Response::text(404, "not found"),
+    };
+
+    if response.status == 200 && request.path != "/health" && request.accepts_gzip() {
+        return Ok(response.gzip()?);
+    }
+    Ok(response)
+}
+
+/// The formats the metrics can be served in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MetricsFormat {
+    Text,
+    OpenMetrics,
+    /// OpenMetrics for scrapers only accepting version 0.0.1.
+    OpenMetrics001,
+}
 
-    let mut output_buffer = vec![];
-    let encoder = prometheus::TextEncoder::new();
-    let metric_families = prometheus::gather();
-    if let Err(e) = encoder.encode(&metric_families, &mut output_buffer) {
-        return Err(RequestHandlingError::Encoding(e));
+impl MetricsFormat {
+    fn content_type(&self) -> &'static str {
+        match self {
+            MetricsFormat::Text => prometheus::TEXT_FORMAT,
+            MetricsFormat::OpenMetrics => openmetrics::CONTENT_TYPE,
+            MetricsFormat::OpenMetrics001 => openmetrics::CONTENT_TYPE_0_0_1,
+        }
+    }
+}
+
+/// Picks the format with the highest quality in the Accept header. Without
+/// an Accept header, the Prometheus text format is used. Returns None if no
+/// format is acceptable.
+fn negotiate_format(accept: Option<&str>) -> Option<MetricsFormat> {
+    let accept = match accept {
+        Some(accept) => accept,
+        None => return Some(MetricsFormat::Text),
     };
-    let contents = String::from_utf8(output_buffer.clone())?;
-    output_buffer.clear();
+    let mut best: Option<(f32, MetricsFormat)> = None;
+    for value in http::quality_values(accept) {
+        let version = value.param("version");
+        let format = match value.value.to_ascii_lowercase().as_str() {
+            "application/openmetrics-text" => match version {
+                None | Some("1.0.0") => MetricsFormat::OpenMetrics,
+                Some("0.0.1") => MetricsFormat::OpenMetrics001,
+                Some(_) => continue,
+            },
+            "text/plain" => match version {
+                None | Some("0.0.4") => MetricsFormat::Text,
+                Some(_) => continue,
+            },
+            "text/*" | "*/*" => MetricsFormat::Text,
+            _ => continue,
+        };
+        if value.quality > 0.0 && best.is_none_or(|(quality, _)| value.quality > quality) {
+            best = Some((value.quality, format));
+        }
+    }
+    best.map(|(_, format)| format)
+}
 
-    let response = format!(
-        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
-        contents.len(),
-        contents
-    );
+/// Streams the events matching the filter in the query (e.g.
+/// `/events?kind=inbound_message&msg_type=tx`) as Server-Sent Events with
+/// one JSON encoded event per message.
+fn events<C>(mut stream: C, query: &str) -> Result<(), RequestHandlingError>
+where
+    C: Connection + Send + 'static,
+{
+    let filter = match EventFilter::from_query(query) {
+        Ok(filter) => filter,
+        Err(reason) => {
+            Response::text(400, &reason).write_to(&mut stream, false, false)?;
+            stream.close();
+            return Ok(());
+        }
+    };
+    let subscription = match events::subscribe(filter) {
+        Some(subscription) => subscription,
+        None => {
+            Response::text(503, "too many event stream subscribers").write_to(
+                &mut stream,
+                false,
+                false,
+            )?;
+            stream.close();
+            return Ok(());
+        }
+    };
 
-    stream.write_all(response.as_bytes())?;
+    // Without a Content-Length, the stream ends when the connection closes.
+    write!(
+        stream,
+        "{}Content-Type: text/event-stream\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n",
+        http::status_line(200)
+    )?;
     stream.flush()?;
+
+    thread::spawn(move || loop {
+        let result = match subscription.receiver.recv_timeout(EVENT_STREAM_KEEPALIVE) {
+            Ok(json) => write!(stream, "data: {}\n\n", json),
+            Err(RecvTimeoutError::Timeout) => stream.write_all(b": keepalive\n\n"),
+            Err(RecvTimeoutError::Disconnected) => {
+                stream.close();
+                return;
+            }
+        };
+        if result.and_then(|_| stream.flush()).is_err() {
+            // The client went away. Dropping the subscription unsubscribes.
+            return;
+        }
+    });
     Ok(())
 }
 
+fn metrics(format: MetricsFormat) -> Result<Vec<u8>, RequestHandlingError> {
+    let metric_families = prometheus::gather();
+    if format != MetricsFormat::Text {
+        return Ok(openmetrics::encode(&metric_families).into_bytes());
+    }
+    let mut output_buffer = vec![];
+    let encoder = prometheus::TextEncoder::new();
+    encoder.encode(&metric_families, &mut output_buffer)?;
+    Ok(output_buffer)
+}
+
+/// The recently connected blocks as JSON, most recent first. Can be filtered
+/// by node with the `node` query parameter (e.g. `/blocks?node=mainnet`).
+fn blocks(query: &str) -> Result<Response, RequestHandlingError> {
+    let node = match query.split('&').find_map(|p| p.strip_prefix("node=")) {
+        Some(node) => match http::decode_query_component(node) {
+            Some(node) => Some(node),
+            None => return Ok(Response::text(400, "invalid percent-encoding in node")),
+        },
+        None => None,
+    };
+    let recent_blocks = blocks::RECENT_BLOCKS.lock().unwrap();
+    let body = serde_json::to_vec(&recent_blocks.recent(node.as_deref()))?;
+    Ok(Response::new(200, "application/json", body).header("Vary", "Accept-Encoding"))
+}
+
 #[derive(Debug)]
 enum RequestHandlingError {
     Io(io::Error),
-    Utf8(FromUtf8Error),
+    Tls(rustls::Error),
     Encoding(prometheus::Error),
+    Json(serde_json::Error),
 }
 
 impl fmt::Display for RequestHandlingError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            RequestHandlingError::Utf8(e) => write!(f, "UTF-8 error: {}", e),
             RequestHandlingError::Io(e) => write!(f, "IO error: {}", e),
+            RequestHandlingError::Tls(e) => write!(f, "TLS error: {}", e),
             RequestHandlingError::Encoding(e) => write!(f, "encoding error: {}", e),
+            RequestHandlingError::Json(e) => write!(f, "JSON error: {}", e),
         }
     }
 }
@@ -87,9 +446,10 @@ impl fmt::Display for RequestHandlingError {
 impl error::Error for RequestHandlingError {
     fn source(&self) -> Option<&(dyn error::Error + 'static)> {
         match *self {
-            RequestHandlingError::Utf8(ref e) => Some(e),
             RequestHandlingError::Io(ref e) => Some(e),
+            RequestHandlingError::Tls(ref e) => Some(e),
             RequestHandlingError::Encoding(ref e) => Some(e),
+            RequestHandlingError::Json(ref e) => Some(e),
         }
     }
 }
@@ -100,9 +460,9 @@ impl From<io::Error> for RequestHandlingError {
     }
 }
 
-impl From<FromUtf8Error> for RequestHandlingError {
-    fn from(err: FromUtf8Error) -> RequestHandlingError {
-        RequestHandlingError::Utf8(err)
+impl From<rustls::Error> for RequestHandlingError {
+    fn from(err: rustls::Error) -> RequestHandlingError {
+        RequestHandlingError::Tls(err)
     }
 }
 
@@ -111,3 +471,9 @@ impl From<prometheus::Error> for RequestHandlingError {
         RequestHandlingError::Encoding(err)
     }
 }
+
+impl From<serde_json::Error> for RequestHandlingError {
+    fn from(err: serde_json::Error) -> RequestHandlingError {
+        RequestHandlingError::Json(err)
+    }
+}
@@ -0,0 +1,340 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time;
+
+use lazy_static::lazy_static;
+use prometheus::core::{Collector, Metric as _};
+use prometheus::proto::{LabelPair, Metric, MetricFamily, MetricType};
+use prometheus::Histogram;
+
+use crate::metrics;
+
+// An encoder for the OpenMetrics text format (https://openmetrics.io), which
+// the prometheus crate doesn't provide. Compared to the Prometheus text
+// format, it adds `_created` timestamps to counters and histograms and
+// exemplars to histogram buckets. Counter samples get a `_total` suffix, as
+// OpenMetrics requires, so counters not already ending in `_total` have
+// differently named samples than in the Prometheus text format.
+
+pub const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+/// The content type for scrapers only accepting the previous version, which
+/// doesn't differ in what's used here.
+pub const CONTENT_TYPE_0_0_1: &str = "application/openmetrics-text; version=0.0.1; charset=utf-8";
+
+/// The total length of the names and values of the exemplar labels may not
+/// exceed 128 characters.
+const MAX_EXEMPLAR_LABELS_LENGTH: usize = 128;
+
+struct Exemplar {
+    labels: Vec<(String, String)>,
+    value: f64,
+    timestamp: f64,
+}
+
+/// The created timestamps aren't tracked by the prometheus crate. Instead, a
+/// series is assumed to be created after the last encoding it wasn't part
+/// of, or at startup. These lower bounds are good enough to detect counter
+/// resets, e.g. when the metrics of a disconnected peer are removed and later
+/// recreated.
+struct CreatedTimestamps {
+    series: HashMap<String, f64>,
+    last_encoding: Option<f64>,
+}
+
+lazy_static! {
+    static ref CREATED: Mutex<CreatedTimestamps> = Mutex::new(CreatedTimestamps {
+        series: HashMap::new(),
+        last_encoding: None,
+    });
+
+    /// The latest exemplar per histogram series and bucket index.
+    static ref EXEMPLARS: Mutex<HashMap<String, HashMap<usize, Exemplar>>> =
+        Mutex::new(HashMap::new());
+}
+
+/// Observes a value and attaches the labels as exemplar to the bucket it
+/// falls into, e.g. to link a slow block connection to its block hash. The
+/// exemplar replaces the previous one of the bucket.
+pub fn observe_with_exemplar(histogram: &Histogram, value: f64, labels: &[(&str, &str)]) {
+    histogram.observe(value);
+
+    let length: usize = labels.iter().map(|(n, v)| n.len() + v.len()).sum();
+    if length > MAX_EXEMPLAR_LABELS_LENGTH {
+        return;
+    }
+    let name = match histogram.desc().first() {
+        Some(desc) => desc.fq_name.clone(),
+        None => return,
+    };
+    let metric = histogram.metric();
+    let buckets = metric.get_histogram().get_bucket();
+    // Values above the largest bucket fall into the implicit +Inf bucket.
+    let bucket = buckets
+        .iter()
+        .position(|b| value <= b.get_upper_bound())
+        .unwrap_or(buckets.len());
+
+    EXEMPLARS
+        .lock()
+        .unwrap()
+        .entry(series_key(&name, metric.get_label()))
+        .or_default()
+        .insert(
+            bucket,
+            Exemplar {
+                labels: labels
+                    .iter()
+                    .map(|(n, v)| (n.to_string(), v.to_string()))
+                    .collect(),
+                value,
+                timestamp: unix_timestamp(),
+            },
+        );
+}
+
+/// Encodes the metric families in the OpenMetrics text format.
+pub fn encode(metric_families: &[MetricFamily]) -> String {
+    let now = unix_timestamp();
+    let mut created = CREATED.lock().unwrap();
+    let created_default = created
+        .last_encoding
+        .unwrap_or(metrics::RUNTIME_START_TIMESTAMP.get() as f64);
+    let mut created_series = HashMap::new();
+    let mut exemplars = EXEMPLARS.lock().unwrap();
+
+    let mut output = String::new();
+    for family in metric_families {
+        let name = family.get_name();
+        let (family_name, metric_type) = match family.get_field_type() {
+            MetricType::COUNTER => (name.strip_suffix("_total").unwrap_or(name), "counter"),
+            MetricType::GAUGE => (name, "gauge"),
+            MetricType::HISTOGRAM => (name, "histogram"),
+            MetricType::SUMMARY => (name, "summary"),
+            MetricType::UNTYPED => (name, "unknown"),
+        };
+        let _ = writeln!(output, "# TYPE {} {}", family_name, metric_type);
+        let _ = writeln!(
+            output,
+            "# HELP {} {}",
+            family_name,
+            escape(family.get_help())
+        );
+
+        for metric in family.get_metric() {
+            let key = series_key(family_name, metric.get_label());
+            let labels = metric.get_label();
+            match family.get_field_type() {
+                MetricType::COUNTER => {
+                    let sample = format!("{}_total", family_name);
+                    write_sample(
+                        &mut output,
+                        &sample,
+                        labels,
+                        None,
+                        metric.get_counter().get_value(),
+                    );
+                }
+                MetricType::GAUGE => {
+                    write_sample(
+                        &mut output,
+                        family_name,
+                        labels,
+                        None,
+                        metric.get_gauge().get_value(),
+                    );
+                    continue;
+                }
+                MetricType::HISTOGRAM => {
+                    write_histogram(&mut output, family_name, metric, exemplars.get(&key));
+                }
+                MetricType::SUMMARY => {
+                    let summary = metric.get_summary();
+                    for quantile in summary.get_quantile() {
+                        let value = format_value(quantile.get_quantile());
+                        let label = ("quantile", value.as_str());
+                        write_sample(
+                            &mut output,
+                            family_name,
+                            labels,
+                            Some(label),
+                            quantile.get_value(),
+                        );
+                    }
+                    let sum = format!("{}_sum", family_name);
+                    write_sample(&mut output, &sum, labels, None, summary.get_sample_sum());
+                    let count = format!("{}_count", family_name);
+                    write_sample(
+                        &mut output,
+                        &count,
+                        labels,
+                        None,
+                        summary.get_sample_count() as f64,
+                    );
+                }
+                MetricType::UNTYPED => {
+                    write_sample(
+                        &mut output,
+                        family_name,
+                        labels,
+                        None,
+                        metric.get_untyped().get_value(),
+                    );
+                    continue;
+                }
+            }
+
+            let timestamp = *created.series.get(&key).unwrap_or(&created_default);
+            let sample = format!("{}_created", family_name);
+            write_sample(&mut output, &sample, labels, None, timestamp);
+            created_series.insert(key, timestamp);
+        }
+    }
+    output.push_str("# EOF\n");
+
+    // Forget removed series, so they get a new created timestamp and don't
+    // keep their exemplars if they are recreated.
+    exemplars.retain(|key, _| created_series.contains_key(key));
+    created.series = created_series;
+    created.last_encoding = Some(now);
+    output
+}
+
+fn write_histogram(
+    output: &mut String,
+    name: &str,
+    metric: &Metric,
+    exemplars: Option<&HashMap<usize, Exemplar>>,
+) {
+    let histogram = metric.get_histogram();
+    let labels = metric.get_label();
+    let sample = format!("{}_bucket", name);
+
+    let buckets = histogram.get_bucket();
+    let mut inf_written = false;
+    for (i, bucket) in buckets.iter().enumerate() {
+        let le = format_le(bucket.get_upper_bound());
+        write_sample(
+            output,
+            &sample,
+            labels,
+            Some(("le", &le)),
+            bucket.get_cumulative_count() as f64,
+        );
+        write_exemplar(output, exemplars.and_then(|e| e.get(&i)));
+        inf_written = bucket.get_upper_bound() == f64::INFINITY;
+    }
+    if !inf_written {
+        let count = histogram.get_sample_count() as f64;
+        write_sample(output, &sample, labels, Some(("le", "+Inf")), count);
+        write_exemplar(output, exemplars.and_then(|e| e.get(&buckets.len())));
+    }
+
+    let count = format!("{}_count", name);
+    write_sample(
+        output,
+        &count,
+        labels,
+        None,
+        histogram.get_sample_count() as f64,
+    );
+    let sum = format!("{}_sum", name);
+    write_sample(output, &sum, labels, None, histogram.get_sample_sum());
+}
+
+/// Writes a sample line, without the line break if the sample has an
+/// exemplar.
+fn write_sample(
+    output: &mut String,
+    name: &str,
+    labels: &[LabelPair],
+    extra_label: Option<(&str, &str)>,
+    value: f64,
+) {
+    output.push_str(name);
+    let labels = labels
+        .iter()
+        .map(|l| (l.get_name(), l.get_value()))
+        .chain(extra_label);
+    write_labels(output, labels);
+    let _ = writeln!(output, " {}", format_value(value));
+}
+
+/// Appends an exemplar to the previously written sample.
+fn write_exemplar(output: &mut String, exemplar: Option<&Exemplar>) {
+    if let Some(exemplar) = exemplar {
+        output.pop(); // the line break of the sample
+        output.push_str(" # ");
+        let labels = exemplar
+            .labels
+            .iter()
+            .map(|(n, v)| (n.as_str(), v.as_str()));
+        write_labels(output, labels);
+        if exemplar.labels.is_empty() {
+            output.push_str("{}");
+        }
+        let _ = writeln!(
+            output,
+            " {} {:.3}",
+            format_value(exemplar.value),
+            exemplar.timestamp
+        );
+    }
+}
+
+fn write_labels<'a>(output: &mut String, labels: impl Iterator<Item = (&'a str, &'a str)>) {
+    let mut first = true;
+    for (name, value) in labels {
+        output.push(if first { '{' } else { ',' });
+        let _ = write!(output, "{}=\"{}\"", name, escape(value));
+        first = false;
+    }
+    if !first {
+        output.push('}');
+    }
+}
+
+/// Identifies a series by its name and labels.
+fn series_key(name: &str, labels: &[LabelPair]) -> String {
+    let mut key = name.to_string();
+    for label in labels {
+        let _ = write!(key, ",{}={:?}", label.get_name(), label.get_value());
+    }
+    key
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value == f64::INFINITY {
+        "+Inf".to_string()
+    } else if value == f64::NEG_INFINITY {
+        "-Inf".to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+/// Formats a bucket bound. Whole numbers get a fractional part, as the
+/// OpenMetrics format requires the canonical float representation.
+fn format_le(bound: f64) -> String {
+    if bound.is_finite() && bound.fract() == 0.0 {
+        format!("{:.1}", bound)
+    } else {
+        format_value(bound)
+    }
+}
+
+fn unix_timestamp() -> f64 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs_f64()
+}
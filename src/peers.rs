@@ -0,0 +1,270 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::metrics;
+use crate::types::{self, P2PMessage};
+
+/// Time after which the metrics of a disconnected peer are removed. Long
+/// enough for Prometheus to scrape the final values.
+const DISCONNECTED_PEER_EXPIRY: Duration = Duration::from_secs(10 * 60);
+
+/// Time without messages after which the metrics of a peer are removed even
+/// if its disconnection wasn't seen, e.g. because the net:closed_connection
+/// tracepoint isn't attached or its event was lost. bitcoind pings its peers
+/// every two minutes, so connected peers are never inactive for this long.
+const INACTIVE_PEER_EXPIRY: Duration = Duration::from_secs(10 * 60);
+
+struct Peer {
+    id: String,
+    addr: String,
+    conn_type: String,
+    network: &'static str,
+    last_message: Instant,
+    disconnected: Option<Instant>,
+}
+
+impl Peer {
+    fn labels<'a>(&'a self, node: &'a str) -> [&'a str; 5] {
+        [
+            node,
+            self.id.as_str(),
+            self.addr.as_str(),
+            self.network,
+            self.conn_type.as_str(),
+        ]
+    }
+}
+
+/// Tracks the P2P traffic of individual peers for the opt-in per-peer
+/// metrics. At most `limit` peers are tracked. When the limit is reached,
+/// the least recently active disconnected peer is dropped to make room for
+/// a new peer, or the least recently active peer if none disconnected.
+/// Disconnected peers expire after DISCONNECTED_PEER_EXPIRY and peers
+/// without messages after INACTIVE_PEER_EXPIRY.
+pub struct PeerTracker {
+    node: String,
+    limit: usize,
+    /// Networks of the open connections as reported by the connection
+    /// tracepoints, as the message tracepoints don't include the network.
+    networks: HashMap<u64, &'static str>,
+    peers: HashMap<u64, Peer>,
+}
+
+impl PeerTracker {
+    pub fn new(node: String, limit: usize) -> PeerTracker {
+        PeerTracker {
+            node,
+            limit,
+            networks: HashMap::new(),
+            peers: HashMap::new(),
+        }
+    }
+
+    pub fn connected(&mut self, id: u64, network: &'static str) {
+        self.networks.insert(id, network);
+    }
+
+    pub fn disconnected(&mut self, id: u64) {
+        self.networks.remove(&id);
+        if let Some(peer) = self.peers.get_mut(&id) {
+            peer.disconnected = Some(Instant::now());
+        }
+    }
+
+    pub fn inbound_message(&mut self, msg: &P2PMessage) {
+        if self.track(msg) {
+            let labels = self.peers[&msg.peer_id].labels(&self.node);
+            metrics::P2P_PEER_MESSAGE_INBOUND_COUNT
+                .with_label_values(&labels)
+                .inc();
+            metrics::P2P_PEER_MESSAGE_INBOUND_BYTE
+                .with_label_values(&labels)
+                .inc_by(msg.msg_size);
+        }
+    }
+
+    pub fn outbound_message(&mut self, msg: &P2PMessage) {
+        if self.track(msg) {
+            let labels = self.peers[&msg.peer_id].labels(&self.node);
+            metrics::P2P_PEER_MESSAGE_OUTBOUND_COUNT
+                .with_label_values(&labels)
+                .inc();
+            metrics::P2P_PEER_MESSAGE_OUTBOUND_BYTE
+                .with_label_values(&labels)
+                .inc_by(msg.msg_size);
+        }
+    }
+
+    /// Removes the metrics of peers that disconnected longer than
+    /// DISCONNECTED_PEER_EXPIRY ago or sent or received no message in the
+    /// last INACTIVE_PEER_EXPIRY.
+    pub fn expire(&mut self) {
+        self.expire_at(Instant::now());
+    }
+
+    fn expire_at(&mut self, now: Instant) {
+        let expired: Vec<u64> = self
+            .peers
+            .iter()
+            .filter(|(_, peer)| {
+                let disconnected = match peer.disconnected {
+                    Some(disconnected) => now - disconnected >= DISCONNECTED_PEER_EXPIRY,
+                    None => false,
+                };
+                disconnected || now - peer.last_message >= INACTIVE_PEER_EXPIRY
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            self.remove(id);
+        }
+    }
+
+    /// Removes the metrics of all peers. Used when detaching from bitcoind.
+    pub fn clear(&mut self) {
+        let ids: Vec<u64> = self.peers.keys().cloned().collect();
+        for id in ids {
+            self.remove(id);
+        }
+        self.networks.clear();
+    }
+
+    /// Returns whether the peer the message belongs to is tracked. Starts
+    /// tracking the peer if it's unknown and there is room for it.
+    fn track(&mut self, msg: &P2PMessage) -> bool {
+        if let Some(peer) = self.peers.get_mut(&msg.peer_id) {
+            peer.last_message = Instant::now();
+            return true;
+        }
+        if self.peers.len() >= self.limit {
+            self.evict();
+        }
+        // Connections opened before we attached are classified by address.
+        let addr = msg.get_peer_addr();
+        let network = match self.networks.get(&msg.peer_id) {
+            Some(network) => *network,
+            None => types::network_from_addr(&addr),
+        };
+        self.peers.insert(
+            msg.peer_id,
+            Peer {
+                id: msg.peer_id.to_string(),
+                addr,
+                conn_type: msg.get_peer_conn_type(),
+                network,
+                last_message: Instant::now(),
+                disconnected: None,
+            },
+        );
+        true
+    }
+
+    /// Drops the least recently active disconnected peer, or the least
+    /// recently active peer if all tracked peers are connected.
+    fn evict(&mut self) {
+        let lru = self
+            .peers
+            .iter()
+            .min_by_key(|(_, peer)| (peer.disconnected.is_none(), peer.last_message))
+            .map(|(id, _)| *id);
+        if let Some(id) = lru {
+            self.remove(id);
+        }
+    }
+
+    fn remove(&mut self, id: u64) {
+        if let Some(peer) = self.peers.remove(&id) {
+            let labels = peer.labels(&self.node);
+            // A peer might only have inbound or only outbound series.
+            let _ = metrics::P2P_PEER_MESSAGE_INBOUND_COUNT.remove_label_values(&labels);
+            let _ = metrics::P2P_PEER_MESSAGE_INBOUND_BYTE.remove_label_values(&labels);
+            let _ = metrics::P2P_PEER_MESSAGE_OUTBOUND_COUNT.remove_label_values(&labels);
+            let _ = metrics::P2P_PEER_MESSAGE_OUTBOUND_BYTE.remove_label_values(&labels);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(peer_id: u64) -> P2PMessage {
+        let mut msg = P2PMessage {
+            peer_id,
+            peer_addr: [0; 68],
+            peer_conn_type: [0; 20],
+            msg_type: [0; 20],
+            msg_size: 100,
+        };
+        let addr = format!("1.1.1.{}:8333", peer_id);
+        msg.peer_addr[..addr.len()].copy_from_slice(addr.as_bytes());
+        msg.peer_conn_type[..7].copy_from_slice(b"inbound");
+        msg.msg_type[..4].copy_from_slice(b"ping");
+        msg
+    }
+
+    fn tracked(tracker: &PeerTracker) -> Vec<u64> {
+        let mut ids: Vec<u64> = tracker.peers.keys().cloned().collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    #[test]
+    fn evicts_the_least_recently_active_peer_at_the_limit() {
+        let mut tracker = PeerTracker::new(String::from("test-peers-limit"), 3);
+        for id in 1..=3 {
+            tracker.inbound_message(&message(id));
+        }
+        tracker.outbound_message(&message(1));
+        assert_eq!(tracked(&tracker), vec![1, 2, 3]);
+
+        // All peers are connected, peer 2 was least recently active.
+        tracker.inbound_message(&message(4));
+        assert_eq!(tracked(&tracker), vec![1, 3, 4]);
+
+        // Disconnected peers are dropped first.
+        tracker.disconnected(4);
+        tracker.inbound_message(&message(5));
+        assert_eq!(tracked(&tracker), vec![1, 3, 5]);
+    }
+
+    #[test]
+    fn expires_disconnected_and_inactive_peers() {
+        let mut tracker = PeerTracker::new(String::from("test-peers-expiry"), 10);
+        for id in 1..=3 {
+            tracker.inbound_message(&message(id));
+        }
+        tracker.disconnected(1);
+        let start = Instant::now();
+
+        tracker.expire_at(start + Duration::from_secs(60));
+        assert_eq!(tracked(&tracker), vec![1, 2, 3]);
+
+        tracker.peers.get_mut(&3).unwrap().last_message = start + Duration::from_secs(5 * 60);
+        tracker.expire_at(start + DISCONNECTED_PEER_EXPIRY);
+        assert_eq!(tracked(&tracker), vec![3]);
+        tracker.expire_at(start + Duration::from_secs(5 * 60) + INACTIVE_PEER_EXPIRY);
+        assert!(tracked(&tracker).is_empty());
+    }
+
+    #[test]
+    fn tracks_new_peers_without_closed_connection_events() {
+        // No connection events at all, e.g. without the net probes.
+        let mut tracker = PeerTracker::new(String::from("test-peers-no-close"), 2);
+        tracker.inbound_message(&message(1));
+        tracker.inbound_message(&message(2));
+
+        // Peers 1 and 2 disconnected without an event, new peers are
+        // still tracked.
+        tracker.inbound_message(&message(3));
+        tracker.inbound_message(&message(4));
+        assert_eq!(tracked(&tracker), vec![3, 4]);
+
+        // Inactive peers are expired, making room without evictions.
+        tracker.expire_at(Instant::now() + INACTIVE_PEER_EXPIRY);
+        assert!(tracked(&tracker).is_empty());
+        tracker.inbound_message(&message(5));
+        assert_eq!(tracked(&tracker), vec![5]);
+        assert_eq!(tracker.peers[&5].network, "ipv4");
+    }
+}
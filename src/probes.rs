@@ -0,0 +1,173 @@
+use std::fmt;
+use std::str::FromStr;
+
+use bcc::USDTContext;
+
+use crate::metrics;
+
+const LOG_TARGET: &str = "probes";
+
+/// A group of tracepoints that can be enabled or disabled together.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProbeGroup {
+    P2P,
+    Validation,
+    UTXOCache,
+    Mempool,
+}
+
+pub const ALL_PROBE_GROUPS: [ProbeGroup; 4] = [
+    ProbeGroup::P2P,
+    ProbeGroup::Validation,
+    ProbeGroup::UTXOCache,
+    ProbeGroup::Mempool,
+];
+
+impl FromStr for ProbeGroup {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "p2p" => Ok(ProbeGroup::P2P),
+            "validation" => Ok(ProbeGroup::Validation),
+            "utxocache" => Ok(ProbeGroup::UTXOCache),
+            "mempool" => Ok(ProbeGroup::Mempool),
+            _ => Err(format!(
+                "unknown probe group '{}' (expected p2p, validation, utxocache or mempool)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ProbeGroup {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProbeGroup::P2P => write!(f, "p2p"),
+            ProbeGroup::Validation => write!(f, "validation"),
+            ProbeGroup::UTXOCache => write!(f, "utxocache"),
+            ProbeGroup::Mempool => write!(f, "mempool"),
+        }
+    }
+}
+
+/// A bitcoind tracepoint and the eBPF function handling it.
+pub struct Probe {
+    pub group: ProbeGroup,
+    pub tracepoint: &'static str,
+    pub function: &'static str,
+}
+
+pub const PROBES: [Probe; 16] = [
+    Probe {
+        group: ProbeGroup::P2P,
+        tracepoint: "net:inbound_message",
+        function: "trace_inbound_message",
+    },
+    Probe {
+        group: ProbeGroup::P2P,
+        tracepoint: "net:outbound_message",
+        function: "trace_outbound_message",
+    },
+    Probe {
+        group: ProbeGroup::P2P,
+        tracepoint: "net:inbound_connection",
+        function: "trace_inbound_connection",
+    },
+    Probe {
+        group: ProbeGroup::P2P,
+        tracepoint: "net:outbound_connection",
+        function: "trace_outbound_connection",
+    },
+    Probe {
+        group: ProbeGroup::P2P,
+        tracepoint: "net:closed_connection",
+        function: "trace_closed_connection",
+    },
+    Probe {
+        group: ProbeGroup::P2P,
+        tracepoint: "net:evicted_inbound_connection",
+        function: "trace_evicted_inbound_connection",
+    },
+    Probe {
+        group: ProbeGroup::P2P,
+        tracepoint: "net:misbehaving_connection",
+        function: "trace_misbehaving_connection",
+    },
+    Probe {
+        group: ProbeGroup::Validation,
+        tracepoint: "validation:block_connected",
+        function: "trace_block_connected",
+    },
+    Probe {
+        group: ProbeGroup::UTXOCache,
+        tracepoint: "utxocache:add",
+        function: "trace_utxocache_add",
+    },
+    Probe {
+        group: ProbeGroup::UTXOCache,
+        tracepoint: "utxocache:spent",
+        function: "trace_utxocache_spent",
+    },
+    Probe {
+        group: ProbeGroup::UTXOCache,
+        tracepoint: "utxocache:uncache",
+        function: "trace_utxocache_uncache",
+    },
+    Probe {
+        group: ProbeGroup::UTXOCache,
+        tracepoint: "utxocache:flush",
+        function: "trace_utxocache_flush",
+    },
+    Probe {
+        group: ProbeGroup::Mempool,
+        tracepoint: "mempool:added",
+        function: "trace_mempool_added",
+    },
+    Probe {
+        group: ProbeGroup::Mempool,
+        tracepoint: "mempool:removed",
+        function: "trace_mempool_removed",
+    },
+    Probe {
+        group: ProbeGroup::Mempool,
+        tracepoint: "mempool:replaced",
+        function: "trace_mempool_replaced",
+    },
+    Probe {
+        group: ProbeGroup::Mempool,
+        tracepoint: "mempool:rejected",
+        function: "trace_mempool_rejected",
+    },
+];
+
+/// Enables the probes of the given groups in the USDT context of a node. Probes that
+/// can't be enabled (e.g. because the tracepoint is not present in the
+/// bitcoind binary) are skipped with a warning. The result is exposed in the
+/// probe_attached metric. Returns the number of enabled probes.
+pub fn enable(usdt_ctx: &mut USDTContext, node: &str, groups: &[ProbeGroup]) -> usize {
+    let mut enabled = 0;
+    for probe in PROBES.iter().filter(|p| groups.contains(&p.group)) {
+        let attached = match usdt_ctx.enable_probe(probe.tracepoint, probe.function) {
+            Ok(()) => {
+                enabled += 1;
+                1
+            }
+            Err(e) => {
+                log::warn!(
+                    target: LOG_TARGET,
+                    "Node {}: skipping tracepoint {} ({} probes): {}",
+                    node,
+                    probe.tracepoint,
+                    probe.group,
+                    e
+                );
+                0
+            }
+        };
+        metrics::RUNTIME_PROBE_ATTACHED
+            .with_label_values(&[node, probe.tracepoint])
+            .set(attached);
+    }
+    enabled
+}
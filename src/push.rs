@@ -0,0 +1,662 @@
+use std::cmp;
+use std::convert::TryFrom;
+use std::error;
+use std::fmt::{self, Write as _};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use base64::Engine;
+use lazy_static::lazy_static;
+use prometheus::proto::{LabelPair, MetricFamily, MetricType};
+use prometheus::{Encoder, TextEncoder};
+use rustls::pki_types::ServerName;
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+
+use crate::metrics;
+
+const LOG_TARGET: &str = "push";
+
+// Pushes the metrics for nodes that can't be scraped, e.g. behind NAT. The
+// metrics of the default registry are periodically sent to a Pushgateway
+// (https://github.com/prometheus/pushgateway) in the Prometheus text format,
+// replacing the group of the job and instance on each push, or to a
+// Prometheus remote-write endpoint
+// (https://prometheus.io/docs/specs/remote_write_spec/) as snappy-compressed
+// protobuf. The protobuf messages are small enough to be encoded by hand:
+//
+//   message WriteRequest { repeated TimeSeries timeseries = 1; }
+//   message TimeSeries { repeated Label labels = 1; repeated Sample samples = 2; }
+//   message Label { string name = 1; string value = 2; }
+//   message Sample { double value = 1; int64 timestamp = 2; }
+
+/// Timeout for connecting and for each read and write.
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Number of bytes of an error response included in the error.
+const MAX_ERROR_MESSAGE_BYTES: u64 = 512;
+const USER_AGENT: &str = concat!("bitcoind-observer/", env!("CARGO_PKG_VERSION"));
+
+const WIRE_TYPE_VARINT: u8 = 0;
+const WIRE_TYPE_FIXED64: u8 = 1;
+const WIRE_TYPE_LENGTH_DELIMITED: u8 = 2;
+
+/// Retries a failed push up to three times, after 1, 2 and 4 seconds.
+pub const DEFAULT_BACKOFF: Backoff = Backoff {
+    initial: Duration::from_secs(1),
+    max: Duration::from_secs(30),
+    attempts: 4,
+};
+
+lazy_static! {
+    static ref TLS_CONFIG: Arc<ClientConfig> = {
+        let mut roots = RootCertStore::empty();
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        Arc::new(
+            ClientConfig::builder_with_provider(provider)
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        )
+    };
+}
+
+/// An http or https URL to push to. Credentials in the URL are sent with
+/// basic auth.
+#[derive(Clone, PartialEq)]
+pub struct Url {
+    tls: bool,
+    host: String,
+    port: u16,
+    /// The path including the query.
+    path: String,
+    credentials: Option<(String, String)>,
+}
+
+impl Url {
+    fn default_port(&self) -> u16 {
+        if self.tls {
+            443
+        } else {
+            80
+        }
+    }
+
+    /// The host and, if it isn't the default one, the port.
+    fn authority(&self) -> String {
+        let host = if self.host.contains(':') {
+            format!("[{}]", self.host)
+        } else {
+            self.host.clone()
+        };
+        if self.port == self.default_port() {
+            host
+        } else {
+            format!("{}:{}", host, self.port)
+        }
+    }
+
+    /// Returns the URL with the path appended to its path.
+    fn join(&self, path: &str) -> Url {
+        Url {
+            path: format!("{}{}", self.path.trim_end_matches('/'), path),
+            ..self.clone()
+        }
+    }
+}
+
+impl FromStr for Url {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Url, String> {
+        let (tls, rest) = if let Some(rest) = s.strip_prefix("http://") {
+            (false, rest)
+        } else if let Some(rest) = s.strip_prefix("https://") {
+            (true, rest)
+        } else {
+            return Err(String::from("expected an http:// or https:// URL"));
+        };
+        let rest = rest.split('#').next().unwrap_or_default();
+        let (authority, path) = match rest.find(['/', '?']) {
+            Some(i) if rest[i..].starts_with('?') => (&rest[..i], format!("/{}", &rest[i..])),
+            Some(i) => (&rest[..i], rest[i..].to_string()),
+            None => (rest, String::from("/")),
+        };
+
+        let (credentials, host_port) = match authority.rsplit_once('@') {
+            Some((userinfo, host_port)) => {
+                let (user, password) = userinfo.split_once(':').unwrap_or((userinfo, ""));
+                (
+                    Some((percent_decode(user)?, percent_decode(password)?)),
+                    host_port,
+                )
+            }
+            None => (None, authority),
+        };
+        let (host, port) = if let Some(rest) = host_port.strip_prefix('[') {
+            match rest.split_once(']') {
+                Some((host, "")) => (host, None),
+                Some((host, port)) => match port.strip_prefix(':') {
+                    Some(port) => (host, Some(port)),
+                    None => return Err(format!("invalid host '{}'", host_port)),
+                },
+                None => return Err(format!("invalid host '{}'", host_port)),
+            }
+        } else {
+            match host_port.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (host_port, None),
+            }
+        };
+        if host.is_empty() {
+            return Err(String::from("missing host"));
+        }
+        let port = match port {
+            Some(port) => port
+                .parse()
+                .map_err(|_| format!("invalid port '{}'", port))?,
+            None if tls => 443,
+            None => 80,
+        };
+
+        Ok(Url {
+            tls,
+            host: host.to_string(),
+            port,
+            path,
+            credentials,
+        })
+    }
+}
+
+/// Displays the URL without the credentials, e.g. for logging.
+impl fmt::Display for Url {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let scheme = if self.tls { "https" } else { "http" };
+        write!(f, "{}://{}{}", scheme, self.authority(), self.path)
+    }
+}
+
+/// Like Display, so that the credentials don't end up in logs.
+impl fmt::Debug for Url {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Url({})", self)
+    }
+}
+
+fn percent_decode(s: &str) -> Result<String, String> {
+    let invalid = || String::from("invalid percent-encoding in the credentials");
+    let mut bytes = vec![];
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' {
+            let hex = tail.get(..2).ok_or_else(invalid)?;
+            let hex = std::str::from_utf8(hex).map_err(|_| invalid())?;
+            bytes.push(u8::from_str_radix(hex, 16).map_err(|_| invalid())?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(b);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| invalid())
+}
+
+/// Where the metrics are pushed to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PushTarget {
+    /// The base URL of a Pushgateway.
+    Pushgateway(Url),
+    /// The URL of a Prometheus remote-write endpoint.
+    RemoteWrite(Url),
+}
+
+impl PushTarget {
+    /// The name used in the `target` label of the push metrics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            PushTarget::Pushgateway(_) => "pushgateway",
+            PushTarget::RemoteWrite(_) => "remote_write",
+        }
+    }
+
+    pub fn url(&self) -> &Url {
+        match self {
+            PushTarget::Pushgateway(url) | PushTarget::RemoteWrite(url) => url,
+        }
+    }
+}
+
+/// How failed pushes are retried. Pushes are retried after connection
+/// errors and responses with a 5xx or 429 status code.
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    /// The delay before the first retry, doubled for each further retry.
+    pub initial: Duration,
+    /// The maximum delay between retries.
+    pub max: Duration,
+    /// Number of attempts per push, including the first one.
+    pub attempts: u32,
+}
+
+/// Pushes metrics to a target.
+pub struct Pusher {
+    target: PushTarget,
+    job: String,
+    instance: String,
+    backoff: Backoff,
+}
+
+impl Pusher {
+    /// Creates a pusher labeling the metrics with the job and instance.
+    /// These are the grouping key on a Pushgateway.
+    pub fn new(target: PushTarget, job: &str, instance: &str, backoff: Backoff) -> Pusher {
+        Pusher {
+            target,
+            job: job.to_string(),
+            instance: instance.to_string(),
+            backoff,
+        }
+    }
+
+    pub fn target(&self) -> &PushTarget {
+        &self.target
+    }
+
+    /// Pushes the metric families, retrying with exponential backoff.
+    pub fn push(&self, metric_families: &[MetricFamily]) -> Result<(), PushError> {
+        let (method, url, content_type, body) = match self.target {
+            PushTarget::Pushgateway(ref url) => {
+                let encoder = TextEncoder::new();
+                let mut body = vec![];
+                encoder.encode(metric_families, &mut body)?;
+                let path = format!(
+                    "/metrics/{}/{}",
+                    grouping_key_segment("job", &self.job),
+                    grouping_key_segment("instance", &self.instance)
+                );
+                let content_type = encoder.format_type().to_string();
+                ("PUT", url.join(&path), content_type, body)
+            }
+            PushTarget::RemoteWrite(ref url) => {
+                let timestamp = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_millis() as i64;
+                let external_labels = [("instance", self.instance.as_str()), ("job", &self.job)];
+                let request = write_request(metric_families, &external_labels, timestamp);
+                let body = snap::raw::Encoder::new().compress_vec(&request)?;
+                let content_type = String::from("application/x-protobuf");
+                ("POST", url.clone(), content_type, body)
+            }
+        };
+        let mut headers = vec![("Content-Type", content_type.as_str())];
+        if let PushTarget::RemoteWrite(_) = self.target {
+            headers.push(("Content-Encoding", "snappy"));
+            headers.push(("X-Prometheus-Remote-Write-Version", "0.1.0"));
+        }
+
+        let mut delay = self.backoff.initial;
+        let mut attempt = 1;
+        loop {
+            match send(&url, method, &headers, &body) {
+                Ok(()) => return Ok(()),
+                Err(e) if e.is_retryable() && attempt < self.backoff.attempts => {
+                    log::warn!(
+                        target: LOG_TARGET,
+                        "Could not push the metrics to {} (attempt {} of {}), retrying in {:?}: {}",
+                        url,
+                        attempt,
+                        self.backoff.attempts,
+                        delay,
+                        e
+                    );
+                    thread::sleep(delay);
+                    delay = cmp::min(delay * 2, self.backoff.max);
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// Starts a thread per target pushing the metrics of the default registry
+/// every interval.
+pub fn start(targets: &[PushTarget], interval: Duration, job: &str, instance: &str) {
+    for target in targets {
+        let pusher = Pusher::new(target.clone(), job, instance, DEFAULT_BACKOFF);
+        log::info!(
+            target: LOG_TARGET,
+            "Pushing the metrics to {} every {:?}",
+            target.url(),
+            interval
+        );
+        thread::spawn(move || loop {
+            let start = Instant::now();
+            if let Err(e) = pusher.push(&prometheus::gather()) {
+                metrics::RUNTIME_PUSH_FAILURES
+                    .with_label_values(&[pusher.target().name()])
+                    .inc();
+                log::error!(
+                    target: LOG_TARGET,
+                    "Could not push the metrics to {}: {}",
+                    pusher.target().url(),
+                    e
+                );
+            }
+            thread::sleep(interval.saturating_sub(start.elapsed()));
+        });
+    }
+}
+
+/// Returns the hostname, the default instance of pushed metrics.
+pub fn hostname() -> Option<String> {
+    let mut buffer = [0u8; 256];
+    let ret = unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len()) };
+    if ret != 0 {
+        return None;
+    }
+    let length = buffer.iter().position(|b| *b == 0).unwrap_or(buffer.len());
+    String::from_utf8(buffer[..length].to_vec()).ok()
+}
+
+/// Formats a label of the Pushgateway grouping key as URL path segments.
+/// Values that aren't safe in a path are base64 encoded.
+fn grouping_key_segment(name: &str, value: &str) -> String {
+    let safe = |b: u8| b.is_ascii_alphanumeric() || b"-_.~".contains(&b);
+    if value.is_empty() {
+        format!("{}@base64/=", name)
+    } else if value.bytes().all(safe) {
+        format!("{}/{}", name, value)
+    } else {
+        let encoded = base64::engine::general_purpose::URL_SAFE.encode(value);
+        format!("{}@base64/{}", name, encoded)
+    }
+}
+
+/// Sends a request and fails if the response status isn't 2xx. Also used by
+/// the HTTP output sinks.
+pub fn send(url: &Url, method: &str, headers: &[(&str, &str)], body: &[u8]) -> Result<(), PushError> {
+    let address = (url.host.as_str(), url.port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "could not resolve the host"))?;
+    let stream = TcpStream::connect_timeout(&address, TIMEOUT)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    if url.tls {
+        let name = ServerName::try_from(url.host.clone())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let connection = ClientConnection::new(TLS_CONFIG.clone(), name)?;
+        exchange(
+            StreamOwned::new(connection, stream),
+            url,
+            method,
+            headers,
+            body,
+        )
+    } else {
+        exchange(stream, url, method, headers, body)
+    }
+}
+
+fn exchange<S: Read + Write>(
+    mut stream: S,
+    url: &Url,
+    method: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> Result<(), PushError> {
+    let mut head = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        method,
+        url.path,
+        url.authority(),
+        USER_AGENT,
+        body.len()
+    );
+    if let Some((ref user, ref password)) = url.credentials {
+        let credentials = format!("{}:{}", user, password);
+        let encoded = base64::engine::general_purpose::STANDARD.encode(credentials);
+        let _ = write!(head, "Authorization: Basic {}\r\n", encoded);
+    }
+    for (name, value) in headers {
+        let _ = write!(head, "{}: {}\r\n", name, value);
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(body)?;
+    stream.flush()?;
+
+    let mut reader = BufReader::new(stream);
+    let mut status_line = String::new();
+    reader.read_line(&mut status_line)?;
+    let status = match status_line.strip_prefix("HTTP/1.") {
+        Some(rest) => rest.split(' ').nth(1).and_then(|s| s.parse::<u16>().ok()),
+        None => None,
+    }
+    .ok_or(PushError::InvalidResponse)?;
+    if (200..300).contains(&status) {
+        return Ok(());
+    }
+
+    // The connection is closed after the response, so the body ends there.
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+            break;
+        }
+    }
+    let mut message = vec![];
+    let _ = reader
+        .take(MAX_ERROR_MESSAGE_BYTES)
+        .read_to_end(&mut message);
+    let message = String::from_utf8_lossy(&message).trim().to_string();
+    Err(PushError::Status(status, message))
+}
+
+/// Encodes the metric families as remote-write WriteRequest with a sample
+/// at the timestamp in milliseconds per series. The external labels are
+/// added to all series that don't have a label of the same name.
+fn write_request(
+    metric_families: &[MetricFamily],
+    external_labels: &[(&str, &str)],
+    timestamp: i64,
+) -> Vec<u8> {
+    let mut request = vec![];
+    for family in metric_families {
+        let name = family.get_name();
+        for metric in family.get_metric() {
+            let labels = metric.get_label();
+            let mut series = |suffix: &str, extra_label: Option<(&str, &str)>, value: f64| {
+                let series = TimeSeries {
+                    name: &format!("{}{}", name, suffix),
+                    external_labels,
+                    labels,
+                    extra_label,
+                };
+                series.encode(&mut request, value, timestamp);
+            };
+            match family.get_field_type() {
+                MetricType::COUNTER => series("", None, metric.get_counter().get_value()),
+                MetricType::GAUGE => series("", None, metric.get_gauge().get_value()),
+                MetricType::UNTYPED => series("", None, metric.get_untyped().get_value()),
+                MetricType::HISTOGRAM => {
+                    let histogram = metric.get_histogram();
+                    let mut inf_written = false;
+                    for bucket in histogram.get_bucket() {
+                        let le = format_bound(bucket.get_upper_bound());
+                        let count = bucket.get_cumulative_count() as f64;
+                        series("_bucket", Some(("le", &le)), count);
+                        inf_written = bucket.get_upper_bound() == f64::INFINITY;
+                    }
+                    let count = histogram.get_sample_count() as f64;
+                    if !inf_written {
+                        series("_bucket", Some(("le", "+Inf")), count);
+                    }
+                    series("_sum", None, histogram.get_sample_sum());
+                    series("_count", None, count);
+                }
+                MetricType::SUMMARY => {
+                    let summary = metric.get_summary();
+                    for quantile in summary.get_quantile() {
+                        let q = format_bound(quantile.get_quantile());
+                        series("", Some(("quantile", &q)), quantile.get_value());
+                    }
+                    series("_sum", None, summary.get_sample_sum());
+                    series("_count", None, summary.get_sample_count() as f64);
+                }
+            }
+        }
+    }
+    request
+}
+
+struct TimeSeries<'a> {
+    name: &'a str,
+    external_labels: &'a [(&'a str, &'a str)],
+    labels: &'a [LabelPair],
+    extra_label: Option<(&'a str, &'a str)>,
+}
+
+impl TimeSeries<'_> {
+    /// Appends the series with a single sample to the WriteRequest. The
+    /// labels are sorted by name, as the remote-write spec requires.
+    fn encode(&self, request: &mut Vec<u8>, value: f64, timestamp: i64) {
+        let mut labels: Vec<(&str, &str)> = vec![("__name__", self.name)];
+        labels.extend(self.labels.iter().map(|l| (l.get_name(), l.get_value())));
+        labels.extend(self.extra_label);
+        for (name, value) in self.external_labels {
+            if !labels.iter().any(|(n, _)| n == name) {
+                labels.push((name, value));
+            }
+        }
+        labels.sort_by(|a, b| a.0.cmp(b.0));
+
+        let mut series = vec![];
+        for (name, value) in labels {
+            let mut label = vec![];
+            write_length_delimited(&mut label, 1, name.as_bytes());
+            write_length_delimited(&mut label, 2, value.as_bytes());
+            write_length_delimited(&mut series, 1, &label);
+        }
+        let mut sample = vec![];
+        write_tag(&mut sample, 1, WIRE_TYPE_FIXED64);
+        sample.extend_from_slice(&value.to_le_bytes());
+        write_tag(&mut sample, 2, WIRE_TYPE_VARINT);
+        write_varint(&mut sample, timestamp as u64);
+        write_length_delimited(&mut series, 2, &sample);
+        write_length_delimited(request, 1, &series);
+    }
+}
+
+/// Formats a bucket bound or quantile like the Prometheus text format.
+fn format_bound(bound: f64) -> String {
+    if bound == f64::INFINITY {
+        String::from("+Inf")
+    } else {
+        bound.to_string()
+    }
+}
+
+fn write_varint(buffer: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buffer.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+fn write_tag(buffer: &mut Vec<u8>, field: u32, wire_type: u8) {
+    write_varint(buffer, (u64::from(field) << 3) | u64::from(wire_type));
+}
+
+fn write_length_delimited(buffer: &mut Vec<u8>, field: u32, bytes: &[u8]) {
+    write_tag(buffer, field, WIRE_TYPE_LENGTH_DELIMITED);
+    write_varint(buffer, bytes.len() as u64);
+    buffer.extend_from_slice(bytes);
+}
+
+#[derive(Debug)]
+pub enum PushError {
+    Io(io::Error),
+    Tls(rustls::Error),
+    Encoding(prometheus::Error),
+    Compression(snap::Error),
+    /// The response isn't an HTTP response.
+    InvalidResponse,
+    /// The target answered with a status code other than 2xx and the
+    /// message in the response body.
+    Status(u16, String),
+}
+
+impl PushError {
+    /// Whether the push may succeed when retried. Requests that were
+    /// rejected, e.g. because of missing credentials, aren't retried.
+    pub fn is_retryable(&self) -> bool {
+        match *self {
+            PushError::Io(_) => true,
+            PushError::Status(status, _) => status >= 500 || status == 429,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for PushError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PushError::Io(e) => write!(f, "{}", e),
+            PushError::Tls(e) => write!(f, "TLS error: {}", e),
+            PushError::Encoding(e) => write!(f, "could not encode the metrics: {}", e),
+            PushError::Compression(e) => write!(f, "could not compress the metrics: {}", e),
+            PushError::InvalidResponse => write!(f, "invalid HTTP response"),
+            PushError::Status(status, message) if message.is_empty() => {
+                write!(f, "status {}", status)
+            }
+            PushError::Status(status, message) => write!(f, "status {}: {}", status, message),
+        }
+    }
+}
+
+impl error::Error for PushError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            PushError::Io(ref e) => Some(e),
+            PushError::Tls(ref e) => Some(e),
+            PushError::Encoding(ref e) => Some(e),
+            PushError::Compression(ref e) => Some(e),
+            PushError::InvalidResponse => None,
+            PushError::Status(_, _) => None,
+        }
+    }
+}
+
+impl From<io::Error> for PushError {
+    fn from(err: io::Error) -> PushError {
+        PushError::Io(err)
+    }
+}
+
+impl From<rustls::Error> for PushError {
+    fn from(err: rustls::Error) -> PushError {
+        PushError::Tls(err)
+    }
+}
+
+impl From<prometheus::Error> for PushError {
+    fn from(err: prometheus::Error) -> PushError {
+        PushError::Encoding(err)
+    }
+}
+
+impl From<snap::Error> for PushError {
+    fn from(err: snap::Error) -> PushError {
+        PushError::Compression(err)
+    }
+}
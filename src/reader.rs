@@ -0,0 +1,207 @@
+use std::io;
+use std::os::raw::{c_int, c_void};
+use std::os::unix::io::RawFd;
+use std::panic;
+use std::slice;
+use std::time::Duration;
+
+use bcc::table::Table;
+use bcc::{cpuonline, BccError};
+use bcc_sys::bccapi::{
+    bpf_open_perf_buffer, perf_reader, perf_reader_event_read, perf_reader_fd, perf_reader_free,
+};
+
+/// Number of pages per CPU for each perf buffer. Same as the bcc default.
+pub const DEFAULT_PERF_BUFFER_PAGE_COUNT: i32 = 64;
+
+/// Called with each raw event read from a perf buffer.
+pub type PerfCallback = Box<dyn FnMut(&[u8]) + Send>;
+/// Called with the number of events lost because a perf buffer was full.
+pub type LostCallback = Box<dyn FnMut(u64) + Send>;
+
+/// Maximum number of ready file descriptors handled per epoll_wait() call.
+const MAX_EVENTS: usize = 64;
+
+/// A buffer the kernel submits events into. Each buffer exposes one or more
+/// file descriptors that become readable once events are available.
+pub trait EventBuffer {
+    /// The file descriptors to wait on.
+    fn fds(&self) -> Vec<RawFd>;
+    /// Consumes the available events behind the file descriptor with the
+    /// given index in fds().
+    fn consume(&mut self, index: usize);
+}
+
+/// The callbacks of a perf buffer. Passed as cookie to the perf readers.
+struct PerfBufferCallbacks {
+    event: PerfCallback,
+    lost: LostCallback,
+}
+
+/// A BPF_PERF_OUTPUT buffer with one perf reader per online CPU.
+pub struct PerfBuffer {
+    readers: Vec<*mut perf_reader>,
+    callbacks: *mut PerfBufferCallbacks,
+}
+
+impl PerfBuffer {
+    /// Opens a perf reader for each online CPU and registers the readers
+    /// in the BPF_PERF_OUTPUT table. All readers share the callbacks. The
+    /// lost callback is called with the number of events dropped by the
+    /// kernel because the buffer was full.
+    pub fn open(
+        mut table: Table,
+        callback: PerfCallback,
+        lost_callback: LostCallback,
+        page_count: i32,
+    ) -> Result<PerfBuffer, BccError> {
+        if table.key_size() != 4 || table.leaf_size() != 4 {
+            return Err(BccError::TableInvalidSize);
+        }
+
+        let mut buffer = PerfBuffer {
+            readers: vec![],
+            callbacks: Box::into_raw(Box::new(PerfBufferCallbacks {
+                event: callback,
+                lost: lost_callback,
+            })),
+        };
+        for cpu in cpuonline::get()? {
+            let reader = unsafe {
+                bpf_open_perf_buffer(
+                    Some(raw_event_callback),
+                    Some(raw_lost_callback),
+                    buffer.callbacks as *mut c_void,
+                    -1, /* pid */
+                    cpu as c_int,
+                    page_count,
+                )
+            } as *mut perf_reader;
+            if reader.is_null() {
+                return Err(BccError::OpenPerfBuffer);
+            }
+            buffer.readers.push(reader);
+
+            let fd = unsafe { perf_reader_fd(reader) };
+            let mut key = (cpu as u32).to_ne_bytes();
+            let mut leaf = (fd as u32).to_ne_bytes();
+            table
+                .set(&mut key, &mut leaf)
+                .map_err(|_| BccError::InitializePerfMap)?;
+        }
+        Ok(buffer)
+    }
+}
+
+impl EventBuffer for PerfBuffer {
+    fn fds(&self) -> Vec<RawFd> {
+        self.readers
+            .iter()
+            .map(|reader| unsafe { perf_reader_fd(*reader) })
+            .collect()
+    }
+
+    fn consume(&mut self, index: usize) {
+        if let Some(reader) = self.readers.get(index) {
+            unsafe { perf_reader_event_read(*reader) };
+        }
+    }
+}
+
+impl Drop for PerfBuffer {
+    fn drop(&mut self) {
+        for reader in self.readers.drain(..) {
+            unsafe { perf_reader_free(reader as *mut c_void) };
+        }
+        drop(unsafe { Box::from_raw(self.callbacks) });
+    }
+}
+
+unsafe extern "C" fn raw_event_callback(cookie: *mut c_void, raw: *mut c_void, size: c_int) {
+    let data = slice::from_raw_parts(raw as *const u8, size as usize);
+    let callbacks = &mut *(cookie as *mut PerfBufferCallbacks);
+    // Don't unwind into C code. The panic message is still printed.
+    let _ = panic::catch_unwind(panic::AssertUnwindSafe(|| (callbacks.event)(data)));
+}
+
+unsafe extern "C" fn raw_lost_callback(cookie: *mut c_void, lost: u64) {
+    let callbacks = &mut *(cookie as *mut PerfBufferCallbacks);
+    let _ = panic::catch_unwind(panic::AssertUnwindSafe(|| (callbacks.lost)(lost)));
+}
+
+/// Waits on the file descriptors of all registered event buffers with a
+/// single epoll instance and consumes the events of the ready buffers.
+pub struct EventReader {
+    epoll_fd: RawFd,
+    buffers: Vec<Box<dyn EventBuffer>>,
+    events: Vec<libc::epoll_event>,
+}
+
+impl EventReader {
+    pub fn new() -> io::Result<EventReader> {
+        let epoll_fd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if epoll_fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(EventReader {
+            epoll_fd,
+            buffers: vec![],
+            events: vec![libc::epoll_event { events: 0, u64: 0 }; MAX_EVENTS],
+        })
+    }
+
+    /// Registers all file descriptors of the buffer. The epoll event data
+    /// holds the buffer index in the upper and the fd index in the lower
+    /// 32 bits.
+    pub fn add(&mut self, buffer: Box<dyn EventBuffer>) -> io::Result<()> {
+        let buffer_index = self.buffers.len() as u64;
+        for (fd_index, fd) in buffer.fds().into_iter().enumerate() {
+            let mut event = libc::epoll_event {
+                events: libc::EPOLLIN as u32,
+                u64: buffer_index << 32 | fd_index as u64,
+            };
+            if unsafe { libc::epoll_ctl(self.epoll_fd, libc::EPOLL_CTL_ADD, fd, &mut event) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        self.buffers.push(buffer);
+        Ok(())
+    }
+
+    /// Blocks until at least one buffer has events or the timeout expires
+    /// and consumes the events of all ready buffers. Returns the number of
+    /// ready file descriptors.
+    pub fn poll(&mut self, timeout: Duration) -> io::Result<usize> {
+        let ready = unsafe {
+            libc::epoll_wait(
+                self.epoll_fd,
+                self.events.as_mut_ptr(),
+                self.events.len() as c_int,
+                timeout.as_millis().min(c_int::MAX as u128) as c_int,
+            )
+        };
+        if ready < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                return Ok(0);
+            }
+            return Err(e);
+        }
+        for event in &self.events[..ready as usize] {
+            let data = event.u64;
+            if let Some(buffer) = self.buffers.get_mut((data >> 32) as usize) {
+                buffer.consume((data & u64::from(u32::MAX)) as usize);
+            }
+        }
+        Ok(ready as usize)
+    }
+}
+
+impl Drop for EventReader {
+    fn drop(&mut self) {
+        // Free the buffers before closing the epoll instance they are
+        // registered with.
+        self.buffers.clear();
+        unsafe { libc::close(self.epoll_fd) };
+    }
+}
@@ -0,0 +1,296 @@
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{self, Duration, Instant};
+
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+use crate::source::{EventSource, TracedEvent};
+
+const LOG_TARGET: &str = "recording";
+
+/// Identifies a recording in the header line of the file.
+const FORMAT: &str = "bitcoind-observer-recording";
+
+/// Version of the recording format. Increased when the layout of the lines
+/// or of the recorded raw events changes.
+pub const VERSION: u32 = 1;
+
+/// Name used in place of a perf buffer for the increments of the in-kernel
+/// utxocache counters, recorded as little-endian u64 values.
+pub const UTXOCACHE_COUNTERS: &str = "utxocache_counters";
+
+lazy_static! {
+    static ref RECORDER: Mutex<Option<BufWriter<File>>> = Mutex::new(None);
+}
+
+/// The first line of a recording.
+#[derive(Serialize, Deserialize)]
+struct Header {
+    format: String,
+    version: u32,
+}
+
+/// A raw event as read from a perf buffer. One line per event.
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    /// UNIX epoch timestamp in milliseconds of when the event was read.
+    timestamp_ms: u64,
+    node: String,
+    /// The perf buffer the event was read from.
+    buffer: String,
+    /// The raw event as hex.
+    data: String,
+}
+
+/// Starts recording the raw events of all nodes to a new NDJSON file at the
+/// path. The file starts with a header line followed by one line per event.
+pub fn start(path: &Path) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    let header = Header {
+        format: String::from(FORMAT),
+        version: VERSION,
+    };
+    serde_json::to_writer(&mut writer, &header)?;
+    writer.write_all(b"\n")?;
+    *RECORDER.lock().unwrap() = Some(writer);
+    Ok(())
+}
+
+/// Returns whether the raw events are being recorded.
+pub fn is_active() -> bool {
+    RECORDER.lock().unwrap().is_some()
+}
+
+/// Records a raw event if recording is enabled. Recording stops on the first
+/// write error.
+pub fn record(node: &str, buffer: &str, data: &[u8]) {
+    let mut recorder = RECORDER.lock().unwrap();
+    let writer = match recorder.as_mut() {
+        Some(writer) => writer,
+        None => return,
+    };
+    let entry = Entry {
+        timestamp_ms: unix_timestamp_ms(),
+        node: node.to_string(),
+        buffer: buffer.to_string(),
+        data: data.iter().map(|b| format!("{:02x}", b)).collect(),
+    };
+    let result = serde_json::to_writer(&mut *writer, &entry)
+        .map_err(io::Error::from)
+        .and_then(|_| writer.write_all(b"\n"));
+    if let Err(e) = result {
+        log::error!(target: LOG_TARGET, "Could not record event, stopped recording: {}", e);
+        *recorder = None;
+    }
+}
+
+/// Writes the buffered events to the recording file.
+pub fn flush() {
+    let mut recorder = RECORDER.lock().unwrap();
+    if let Some(ref mut writer) = *recorder {
+        if let Err(e) = writer.flush() {
+            log::error!(target: LOG_TARGET, "Could not write recording, stopped recording: {}", e);
+            *recorder = None;
+        }
+    }
+}
+
+/// A recording opened for replay. The events are replayed with the original
+/// time between them divided by the speed, or as fast as possible with a
+/// speed of 0. A truncated last line, e.g. of a recording that was cut off
+/// when the bitcoind-observer was killed, ends the replay. Other malformed
+/// lines are an error.
+pub struct Replay {
+    reader: BufReader<File>,
+    line_number: usize,
+    speed: f64,
+    start: Instant,
+    first_timestamp_ms: Option<u64>,
+    /// The next event and its raw data, read but not due yet.
+    pending: Option<(Entry, Vec<u8>)>,
+    replayed: usize,
+}
+
+impl Replay {
+    /// Opens the recording at the path and checks its header.
+    pub fn open(path: &Path, speed: f64) -> Result<Replay, ReplayError> {
+        let mut reader = BufReader::new(File::open(path)?);
+
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(ReplayError::Parse(1, String::from("empty file")));
+        }
+        let header: Header =
+            serde_json::from_str(&line).map_err(|e| ReplayError::Parse(1, e.to_string()))?;
+        if header.format != FORMAT {
+            return Err(ReplayError::Parse(
+                1,
+                format!("not a recording (format '{}')", header.format),
+            ));
+        }
+        if header.version != VERSION {
+            return Err(ReplayError::Version(header.version));
+        }
+
+        Ok(Replay {
+            reader,
+            line_number: 1,
+            speed,
+            start: Instant::now(),
+            first_timestamp_ms: None,
+            pending: None,
+            replayed: 0,
+        })
+    }
+
+    /// Number of events replayed so far.
+    pub fn replayed(&self) -> usize {
+        self.replayed
+    }
+
+    fn next_entry(&mut self) -> Result<Option<(Entry, Vec<u8>)>, ReplayError> {
+        if let Some(pending) = self.pending.take() {
+            return Ok(Some(pending));
+        }
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            self.line_number += 1;
+            if line.trim_end().is_empty() {
+                continue;
+            }
+            let entry: Entry = match serde_json::from_str(&line) {
+                Ok(entry) => entry,
+                Err(_) if !line.ends_with('\n') => {
+                    log::warn!(
+                        target: LOG_TARGET,
+                        "Recording ends with a truncated line {}, ignoring it.",
+                        self.line_number
+                    );
+                    return Ok(None);
+                }
+                Err(e) => return Err(ReplayError::Parse(self.line_number, e.to_string())),
+            };
+            let data = from_hex(&entry.data).ok_or_else(|| {
+                ReplayError::Parse(self.line_number, String::from("event data is not hex"))
+            })?;
+            return Ok(Some((entry, data)));
+        }
+    }
+
+    /// When the event is due relative to the start of the replay.
+    fn due(&mut self, entry: &Entry) -> Instant {
+        if self.speed <= 0.0 {
+            return self.start;
+        }
+        let first = *self.first_timestamp_ms.get_or_insert(entry.timestamp_ms);
+        let offset_ms = entry.timestamp_ms.saturating_sub(first) as f64 / self.speed;
+        self.start + Duration::from_secs_f64(offset_ms / 1000.0)
+    }
+}
+
+impl EventSource for Replay {
+    type Error = ReplayError;
+
+    fn poll(
+        &mut self,
+        timeout: Duration,
+        handler: &mut dyn FnMut(&str, TracedEvent),
+    ) -> Result<bool, ReplayError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let (entry, data) = match self.next_entry()? {
+                Some(next) => next,
+                None => return Ok(false),
+            };
+            let due = self.due(&entry);
+            if due > deadline {
+                sleep_until(deadline);
+                self.pending = Some((entry, data));
+                return Ok(true);
+            }
+            sleep_until(due);
+
+            if let Some(event) = TracedEvent::decode_or_count(&entry.node, &entry.buffer, &data) {
+                handler(&entry.node, event);
+            }
+            self.replayed += 1;
+
+            if Instant::now() >= deadline {
+                return Ok(true);
+            }
+        }
+    }
+}
+
+fn sleep_until(instant: Instant) {
+    let now = Instant::now();
+    if instant > now {
+        thread::sleep(instant - now);
+    }
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn unix_timestamp_ms() -> u64 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+#[derive(Debug)]
+pub enum ReplayError {
+    Io(io::Error),
+    /// A malformed line and its line number.
+    Parse(usize, String),
+    /// The recording has an unsupported format version.
+    Version(u32),
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReplayError::Io(e) => write!(f, "IO error: {}", e),
+            ReplayError::Parse(line, reason) => write!(f, "line {}: {}", line, reason),
+            ReplayError::Version(version) => write!(
+                f,
+                "unsupported recording version {} (expected {})",
+                version, VERSION
+            ),
+        }
+    }
+}
+
+impl error::Error for ReplayError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            ReplayError::Io(ref e) => Some(e),
+            ReplayError::Parse(_, _) => None,
+            ReplayError::Version(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for ReplayError {
+    fn from(err: io::Error) -> ReplayError {
+        ReplayError::Io(err)
+    }
+}
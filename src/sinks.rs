@@ -0,0 +1,727 @@
+use std::collections::HashMap;
+use std::error;
+use std::fmt::{self, Write as _};
+use std::io;
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::str::FromStr;
+use std::sync::mpsc::RecvTimeoutError;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use prometheus::proto::{LabelPair, MetricFamily, MetricType};
+use serde_json::Value;
+
+use crate::config::SinkConfig;
+use crate::events::{self, EventFilter, Subscription};
+use crate::metrics;
+use crate::push::{self, PushError, Url};
+
+const LOG_TARGET: &str = "sinks";
+
+// Output sinks for monitoring systems other than Prometheus, e.g. InfluxDB
+// and Telegraf. A sink periodically emits snapshots of the metrics and/or
+// emits the traced events as they happen. A Format encodes them as lines,
+// which a Transport sends. The formats are the InfluxDB line protocol
+// (https://docs.influxdata.com/influxdb/v2/reference/syntax/line-protocol/)
+// and StatsD with DogStatsD tags, the transports are UDP and HTTP. The
+// events are received from the same event stream as the /events endpoint.
+
+/// Maximum size of a UDP datagram with lines. Small enough to not be
+/// fragmented on common networks.
+const MAX_DATAGRAM_BYTES: usize = 1432;
+/// Maximum number of event lines sent at once.
+const MAX_BATCH_LINES: usize = 5000;
+
+/// Event fields that are tags rather than fields: categories with few
+/// distinct values.
+const TAG_FIELDS: [&str; 6] = [
+    "connection_type",
+    "for_prune",
+    "mode",
+    "msg_type",
+    "network",
+    "reason",
+];
+
+/// Numeric event fields that identify something rather than measure it. They
+/// aren't sent as StatsD histogram samples.
+const STATSD_SKIPPED_FIELDS: [&str; 2] = ["height", "peer_id"];
+
+/// The output format of a sink.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Influx,
+    Statsd,
+}
+
+impl FromStr for Protocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "influx" => Ok(Protocol::Influx),
+            "statsd" => Ok(Protocol::Statsd),
+            _ => Err(format!(
+                "unknown protocol '{}' (expected influx or statsd)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Protocol::Influx => write!(f, "influx"),
+            Protocol::Statsd => write!(f, "statsd"),
+        }
+    }
+}
+
+/// Where a sink sends its lines to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Destination {
+    /// A host and port to send UDP datagrams to.
+    Udp(String),
+    /// A URL to POST the lines to, e.g. the InfluxDB write API. The token
+    /// is sent as `Authorization: Token <token>` header.
+    Http { url: Url, token: Option<String> },
+}
+
+/// Displays the destination as URL, without credentials and token.
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Destination::Udp(address) => write!(f, "udp://{}", address),
+            Destination::Http { url, .. } => write!(f, "{}", url),
+        }
+    }
+}
+
+/// A field value of a point.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FieldValue {
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    String(String),
+}
+
+/// An event as measurement with tags and fields.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Point {
+    pub measurement: String,
+    pub tags: Vec<(String, String)>,
+    pub fields: Vec<(String, FieldValue)>,
+    pub timestamp_ms: u64,
+}
+
+impl Point {
+    /// Builds a point from an event in the JSON format of the event stream.
+    /// The measurement is the event kind prefixed with `bitcoindobserver_`.
+    /// The node and the TAG_FIELDS become tags, the other fields fields.
+    pub fn from_event(json: &str) -> Option<Point> {
+        let object = match serde_json::from_str(json).ok()? {
+            Value::Object(object) => object,
+            _ => return None,
+        };
+        let mut point = Point {
+            measurement: String::new(),
+            tags: vec![],
+            fields: vec![],
+            timestamp_ms: 0,
+        };
+        for (key, value) in object {
+            if key == "kind" {
+                point.measurement = format!("bitcoindobserver_{}", value.as_str()?);
+            } else if key == "timestamp_ms" {
+                point.timestamp_ms = value.as_u64()?;
+            } else if key == "node" || TAG_FIELDS.contains(&key.as_str()) {
+                let value = match value {
+                    Value::String(s) => s,
+                    value => value.to_string(),
+                };
+                point.tags.push((key, value));
+            } else {
+                let value = match value {
+                    Value::String(s) => FieldValue::String(s),
+                    Value::Bool(b) => FieldValue::Boolean(b),
+                    Value::Number(n) => match n.as_i64() {
+                        Some(i) => FieldValue::Integer(i),
+                        None => FieldValue::Float(n.as_f64()?),
+                    },
+                    _ => continue,
+                };
+                point.fields.push((key, value));
+            }
+        }
+        if point.measurement.is_empty() {
+            return None;
+        }
+        Some(point)
+    }
+}
+
+/// Encodes metric snapshots and events as lines.
+pub trait Format: Send {
+    /// Encodes a snapshot of the metric families taken at the timestamp.
+    fn metrics(&mut self, metric_families: &[MetricFamily], timestamp_ms: u64) -> Vec<String>;
+
+    /// Encodes an event.
+    fn event(&mut self, point: &Point) -> Vec<String>;
+}
+
+/// The InfluxDB line protocol with nanosecond timestamps. A metric becomes
+/// a point with the metric name as measurement and the labels as tags.
+/// Counters, gauges and untyped metrics have a `value` field, histograms and
+/// summaries `count` and `sum` fields and a field per bucket bound or
+/// quantile.
+pub struct InfluxLineProtocol;
+
+impl Format for InfluxLineProtocol {
+    fn metrics(&mut self, metric_families: &[MetricFamily], timestamp_ms: u64) -> Vec<String> {
+        let mut lines = vec![];
+        for family in metric_families {
+            for metric in family.get_metric() {
+                let value = |v: f64| vec![(String::from("value"), FieldValue::Float(v))];
+                let fields = match family.get_field_type() {
+                    MetricType::COUNTER => value(metric.get_counter().get_value()),
+                    MetricType::GAUGE => value(metric.get_gauge().get_value()),
+                    MetricType::UNTYPED => value(metric.get_untyped().get_value()),
+                    MetricType::HISTOGRAM => {
+                        let histogram = metric.get_histogram();
+                        let count = histogram.get_sample_count();
+                        let mut fields = vec![
+                            (String::from("count"), integer(count)),
+                            (
+                                String::from("sum"),
+                                FieldValue::Float(histogram.get_sample_sum()),
+                            ),
+                        ];
+                        for bucket in histogram.get_bucket() {
+                            let bound = format_bound(bucket.get_upper_bound());
+                            fields.push((bound, integer(bucket.get_cumulative_count())));
+                        }
+                        if !fields.iter().any(|(name, _)| name == "+Inf") {
+                            fields.push((String::from("+Inf"), integer(count)));
+                        }
+                        fields
+                    }
+                    MetricType::SUMMARY => {
+                        let summary = metric.get_summary();
+                        let mut fields = vec![
+                            (String::from("count"), integer(summary.get_sample_count())),
+                            (
+                                String::from("sum"),
+                                FieldValue::Float(summary.get_sample_sum()),
+                            ),
+                        ];
+                        for quantile in summary.get_quantile() {
+                            let q = format_bound(quantile.get_quantile());
+                            fields.push((q, FieldValue::Float(quantile.get_value())));
+                        }
+                        fields
+                    }
+                };
+                let point = Point {
+                    measurement: family.get_name().to_string(),
+                    tags: labels(metric.get_label()),
+                    fields,
+                    timestamp_ms,
+                };
+                lines.extend(influx_line(&point));
+            }
+        }
+        lines
+    }
+
+    fn event(&mut self, point: &Point) -> Vec<String> {
+        influx_line(point).into_iter().collect()
+    }
+}
+
+/// Formats a point as line. Returns None if the point has no field that
+/// can be represented, as InfluxDB doesn't support NaN and infinite floats.
+fn influx_line(point: &Point) -> Option<String> {
+    let mut line = influx_escape(&point.measurement, &[',', ' ']);
+    for (key, value) in point.tags.iter() {
+        // Empty tag values aren't allowed.
+        if !value.is_empty() {
+            let key = influx_escape(key, &[',', '=', ' ']);
+            let value = influx_escape(value, &[',', '=', ' ']);
+            let _ = write!(line, ",{}={}", key, value);
+        }
+    }
+    let mut separator = ' ';
+    for (key, value) in point.fields.iter() {
+        let value = match value {
+            FieldValue::Integer(i) => format!("{}i", i),
+            FieldValue::Float(f) if f.is_finite() => f.to_string(),
+            FieldValue::Float(_) => continue,
+            FieldValue::Boolean(b) => b.to_string(),
+            FieldValue::String(s) => format!("\"{}\"", influx_escape(s, &['"'])),
+        };
+        let key = influx_escape(key, &[',', '=', ' ']);
+        let _ = write!(line, "{}{}={}", separator, key, value);
+        separator = ',';
+    }
+    if separator == ' ' {
+        return None;
+    }
+    let _ = write!(line, " {}", point.timestamp_ms * 1_000_000);
+    Some(line)
+}
+
+/// Escapes backslashes, line breaks and the special characters.
+fn influx_escape(s: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            c if special.contains(&c) => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// StatsD with DogStatsD tags (`|#name:value`), as supported by Telegraf
+/// with `datadog_extensions` and by Datadog. Counters are sent as their
+/// increase since the previous snapshot, gauges and untyped metrics as
+/// gauges. Histograms and summaries are sent as the increase of their
+/// `_count` and `_sum`. An event increments a counter named after the
+/// measurement and sends its numeric fields as histogram samples named
+/// `<measurement>_<field>`.
+#[derive(Default)]
+pub struct Statsd {
+    /// The counter values of the previous snapshot by name and tags.
+    previous: HashMap<String, f64>,
+}
+
+impl Statsd {
+    pub fn new() -> Statsd {
+        Statsd::default()
+    }
+
+    fn counter(
+        &self,
+        lines: &mut Vec<String>,
+        current: &mut HashMap<String, f64>,
+        name: &str,
+        tags: &str,
+        value: f64,
+    ) {
+        let key = format!("{}{}", name, tags);
+        let previous = self.previous.get(&key).copied().unwrap_or(0.0);
+        // The counter was reset, e.g. removed and recreated, if it
+        // decreased.
+        let increase = if value >= previous {
+            value - previous
+        } else {
+            value
+        };
+        if increase > 0.0 {
+            lines.push(format!("{}:{}|c{}", name, increase, tags));
+        }
+        current.insert(key, value);
+    }
+}
+
+impl Format for Statsd {
+    fn metrics(&mut self, metric_families: &[MetricFamily], _timestamp_ms: u64) -> Vec<String> {
+        let mut lines = vec![];
+        let mut current = HashMap::new();
+        for family in metric_families {
+            let name = statsd_escape(family.get_name());
+            for metric in family.get_metric() {
+                let tags = statsd_tags(&labels(metric.get_label()));
+                match family.get_field_type() {
+                    MetricType::COUNTER => {
+                        let value = metric.get_counter().get_value();
+                        self.counter(&mut lines, &mut current, &name, &tags, value);
+                    }
+                    MetricType::GAUGE | MetricType::UNTYPED => {
+                        let value = if family.get_field_type() == MetricType::GAUGE {
+                            metric.get_gauge().get_value()
+                        } else {
+                            metric.get_untyped().get_value()
+                        };
+                        if !value.is_finite() {
+                            continue;
+                        }
+                        // A signed value changes the gauge instead of
+                        // setting it, so negative values are set from 0.
+                        if value < 0.0 {
+                            lines.push(format!("{}:0|g{}", name, tags));
+                        }
+                        lines.push(format!("{}:{}|g{}", name, value, tags));
+                    }
+                    MetricType::HISTOGRAM | MetricType::SUMMARY => {
+                        let (count, sum) = if family.get_field_type() == MetricType::HISTOGRAM {
+                            let h = metric.get_histogram();
+                            (h.get_sample_count(), h.get_sample_sum())
+                        } else {
+                            let s = metric.get_summary();
+                            (s.get_sample_count(), s.get_sample_sum())
+                        };
+                        let count_name = format!("{}_count", name);
+                        self.counter(&mut lines, &mut current, &count_name, &tags, count as f64);
+                        let sum_name = format!("{}_sum", name);
+                        self.counter(&mut lines, &mut current, &sum_name, &tags, sum);
+                    }
+                }
+            }
+        }
+        // Removed series are forgotten.
+        self.previous = current;
+        lines
+    }
+
+    fn event(&mut self, point: &Point) -> Vec<String> {
+        let name = statsd_escape(&point.measurement);
+        let tags = statsd_tags(&point.tags);
+        let mut lines = vec![format!("{}:1|c{}", name, tags)];
+        for (field, value) in point.fields.iter() {
+            if STATSD_SKIPPED_FIELDS.contains(&field.as_str()) {
+                continue;
+            }
+            let value = match value {
+                FieldValue::Integer(i) => i.to_string(),
+                FieldValue::Float(f) if f.is_finite() => f.to_string(),
+                _ => continue,
+            };
+            let field = statsd_escape(field);
+            lines.push(format!("{}_{}:{}|h{}", name, field, value, tags));
+        }
+        lines
+    }
+}
+
+fn statsd_tags(tags: &[(String, String)]) -> String {
+    let mut formatted = String::new();
+    for (name, value) in tags.iter() {
+        formatted.push_str(if formatted.is_empty() { "|#" } else { "," });
+        let _ = write!(
+            formatted,
+            "{}:{}",
+            statsd_escape(name),
+            statsd_escape(value)
+        );
+    }
+    formatted
+}
+
+/// Replaces the characters with a special meaning in StatsD lines.
+fn statsd_escape(s: &str) -> String {
+    s.replace([':', '|', '@', '#', ',', '\n'], "_")
+}
+
+fn labels(labels: &[LabelPair]) -> Vec<(String, String)> {
+    labels
+        .iter()
+        .map(|l| (l.get_name().to_string(), l.get_value().to_string()))
+        .collect()
+}
+
+fn integer(value: u64) -> FieldValue {
+    FieldValue::Integer(value.min(i64::MAX as u64) as i64)
+}
+
+/// Formats a bucket bound or quantile like the Prometheus text format.
+fn format_bound(bound: f64) -> String {
+    if bound == f64::INFINITY {
+        String::from("+Inf")
+    } else {
+        bound.to_string()
+    }
+}
+
+/// Sends the lines of a sink.
+pub trait Transport: Send {
+    fn send(&mut self, lines: &[String]) -> Result<(), SinkError>;
+}
+
+/// Sends the lines in UDP datagrams, as many per datagram as fit.
+pub struct UdpTransport {
+    socket: UdpSocket,
+}
+
+impl UdpTransport {
+    pub fn new(address: &str) -> io::Result<UdpTransport> {
+        let address = address.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "could not resolve the address")
+        })?;
+        let socket = if address.is_ipv4() {
+            UdpSocket::bind("0.0.0.0:0")?
+        } else {
+            UdpSocket::bind("[::]:0")?
+        };
+        socket.connect(address)?;
+        Ok(UdpTransport { socket })
+    }
+}
+
+impl Transport for UdpTransport {
+    fn send(&mut self, lines: &[String]) -> Result<(), SinkError> {
+        let mut datagram = String::new();
+        for line in lines {
+            if !datagram.is_empty() && datagram.len() + 1 + line.len() > MAX_DATAGRAM_BYTES {
+                self.socket.send(datagram.as_bytes())?;
+                datagram.clear();
+            }
+            if !datagram.is_empty() {
+                datagram.push('\n');
+            }
+            datagram.push_str(line);
+        }
+        if !datagram.is_empty() {
+            self.socket.send(datagram.as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+/// POSTs the lines in one request.
+pub struct HttpTransport {
+    url: Url,
+    /// The value of the Authorization header.
+    authorization: Option<String>,
+}
+
+impl HttpTransport {
+    pub fn new(url: Url, token: Option<String>) -> HttpTransport {
+        HttpTransport {
+            url,
+            authorization: token.map(|t| format!("Token {}", t)),
+        }
+    }
+}
+
+impl Transport for HttpTransport {
+    fn send(&mut self, lines: &[String]) -> Result<(), SinkError> {
+        let mut body = lines.join("\n");
+        body.push('\n');
+        let mut headers = vec![("Content-Type", "text/plain; charset=utf-8")];
+        if let Some(ref authorization) = self.authorization {
+            headers.push(("Authorization", authorization));
+        }
+        push::send(&self.url, "POST", &headers, body.as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Emits metric snapshots and/or events in a format over a transport.
+pub struct Sink {
+    name: String,
+    format: Box<dyn Format>,
+    transport: Box<dyn Transport>,
+    /// The interval of the metric snapshots, None if no metrics are emitted.
+    interval: Option<Duration>,
+    /// The event stream subscription, None if no events are emitted.
+    subscription: Option<Subscription>,
+    /// Whether the last send failed. Failures are only logged once until
+    /// sending succeeds again.
+    failing: bool,
+}
+
+impl Sink {
+    /// Creates a sink emitting the metrics every interval (if any) and the
+    /// events of the kinds.
+    pub fn new(
+        name: &str,
+        format: Box<dyn Format>,
+        transport: Box<dyn Transport>,
+        interval: Option<Duration>,
+        event_kinds: &[String],
+    ) -> Result<Sink, SinkError> {
+        let subscription = if event_kinds.is_empty() {
+            None
+        } else {
+            let query = format!("kind={}", event_kinds.join(","));
+            let filter = EventFilter::from_query(&query).map_err(SinkError::Events)?;
+            match events::subscribe(filter) {
+                Some(subscription) => Some(subscription),
+                None => {
+                    return Err(SinkError::Events(String::from(
+                        "too many event stream subscribers",
+                    )))
+                }
+            }
+        };
+        Ok(Sink {
+            name: name.to_string(),
+            format,
+            transport,
+            interval,
+            subscription,
+            failing: false,
+        })
+    }
+
+    pub fn from_config(config: &SinkConfig) -> Result<Sink, SinkError> {
+        let format: Box<dyn Format> = match config.protocol {
+            Protocol::Influx => Box::new(InfluxLineProtocol),
+            Protocol::Statsd => Box::new(Statsd::new()),
+        };
+        let transport: Box<dyn Transport> = match config.destination {
+            Destination::Udp(ref address) => Box::new(UdpTransport::new(address)?),
+            Destination::Http { ref url, ref token } => {
+                Box::new(HttpTransport::new(url.clone(), token.clone()))
+            }
+        };
+        Sink::new(
+            &config.name,
+            format,
+            transport,
+            config.interval,
+            &config.events,
+        )
+    }
+
+    /// Emits the events as they are published and the metrics every
+    /// interval, starting with a snapshot of the metrics. Runs forever.
+    pub fn run(mut self) {
+        let mut next_snapshot = self.interval.map(|_| Instant::now());
+        loop {
+            let mut lines = vec![];
+            let timeout = next_snapshot.map(|t| t.saturating_duration_since(Instant::now()));
+            if let Some(ref subscription) = self.subscription {
+                let received = match timeout {
+                    Some(timeout) => subscription.receiver.recv_timeout(timeout),
+                    None => subscription
+                        .receiver
+                        .recv()
+                        .map_err(|_| RecvTimeoutError::Disconnected),
+                };
+                match received {
+                    Ok(json) => {
+                        let mut json = Some(json);
+                        while let Some(event) = json {
+                            if let Some(point) = Point::from_event(&event) {
+                                lines.extend(self.format.event(&point));
+                            }
+                            if lines.len() >= MAX_BATCH_LINES {
+                                break;
+                            }
+                            json = subscription.receiver.try_recv().ok();
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout) => (),
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+            } else if let Some(timeout) = timeout {
+                thread::sleep(timeout);
+            }
+
+            if let (Some(snapshot), Some(interval)) = (next_snapshot, self.interval) {
+                let now = Instant::now();
+                if now >= snapshot {
+                    let timestamp_ms = unix_timestamp_ms();
+                    lines.extend(self.format.metrics(&prometheus::gather(), timestamp_ms));
+                    // Snapshots that are due while sending are skipped.
+                    next_snapshot = Some(if snapshot + interval > now {
+                        snapshot + interval
+                    } else {
+                        now + interval
+                    });
+                }
+            }
+
+            if !lines.is_empty() {
+                self.send(&lines);
+            }
+        }
+    }
+
+    fn send(&mut self, lines: &[String]) {
+        match self.transport.send(lines) {
+            Ok(()) => {
+                if self.failing {
+                    log::info!(target: LOG_TARGET, "Sink {}: sending again.", self.name);
+                    self.failing = false;
+                }
+            }
+            Err(e) => {
+                metrics::RUNTIME_SINK_FAILURES
+                    .with_label_values(&[&self.name])
+                    .inc();
+                if !self.failing {
+                    log::warn!(
+                        target: LOG_TARGET,
+                        "Sink {}: could not send: {}",
+                        self.name,
+                        e
+                    );
+                    self.failing = true;
+                }
+            }
+        }
+    }
+}
+
+/// Starts a thread per sink.
+pub fn start(configs: &[SinkConfig]) -> Result<(), SinkError> {
+    for config in configs {
+        let sink = Sink::from_config(config)?;
+        log::info!(
+            target: LOG_TARGET,
+            "Sink {}: sending {} to {}",
+            config.name,
+            config.protocol,
+            config.destination
+        );
+        thread::spawn(move || sink.run());
+    }
+    Ok(())
+}
+
+fn unix_timestamp_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+#[derive(Debug)]
+pub enum SinkError {
+    Io(io::Error),
+    Http(PushError),
+    /// The sink couldn't subscribe to the events.
+    Events(String),
+}
+
+impl fmt::Display for SinkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SinkError::Io(e) => write!(f, "{}", e),
+            SinkError::Http(e) => write!(f, "{}", e),
+            SinkError::Events(reason) => write!(f, "could not subscribe to events: {}", reason),
+        }
+    }
+}
+
+impl error::Error for SinkError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            SinkError::Io(ref e) => Some(e),
+            SinkError::Http(ref e) => Some(e),
+            SinkError::Events(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for SinkError {
+    fn from(err: io::Error) -> SinkError {
+        SinkError::Io(err)
+    }
+}
+
+impl From<PushError> for SinkError {
+    fn from(err: PushError) -> SinkError {
+        SinkError::Http(err)
+    }
+}
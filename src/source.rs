@@ -0,0 +1,152 @@
+use std::collections::VecDeque;
+use std::convert::{Infallible, TryFrom};
+use std::error;
+use std::time::Duration;
+
+use crate::metrics;
+use crate::recording;
+use crate::types::{
+    BlockConnected, ClosedConnection, DecodeError, MempoolAdded, MempoolRejected, MempoolRemoved,
+    MempoolReplaced, MisbehavingConnection, NewConnection, P2PMessage, UTXOCacheEvents,
+    UTXOCacheFlush,
+};
+
+const LOG_TARGET: &str = "source";
+
+/// Names of the perf buffers the eBPF programs submit events to.
+pub const PERF_BUFFERS: [&str; 13] = [
+    "inbound_messages",
+    "outbound_messages",
+    "perf_inbound_connections",
+    "perf_outbound_connections",
+    "perf_closed_connections",
+    "perf_evicted_connections",
+    "perf_misbehaving_connections",
+    "perf_block_connected",
+    "perf_utxocache_flushes",
+    "perf_mempool_added",
+    "perf_mempool_removed",
+    "perf_mempool_replaced",
+    "perf_mempool_rejected",
+];
+
+/// A decoded event of a traced bitcoind node.
+pub enum TracedEvent {
+    InboundMessage(P2PMessage),
+    OutboundMessage(P2PMessage),
+    InboundConnection(NewConnection),
+    OutboundConnection(NewConnection),
+    ClosedConnection(ClosedConnection),
+    EvictedConnection(ClosedConnection),
+    MisbehavingConnection(MisbehavingConnection),
+    BlockConnected(BlockConnected),
+    UTXOCacheEvents(UTXOCacheEvents),
+    UTXOCacheFlush(UTXOCacheFlush),
+    MempoolAdded(MempoolAdded),
+    MempoolRemoved(MempoolRemoved),
+    MempoolReplaced(MempoolReplaced),
+    MempoolRejected(MempoolRejected),
+}
+
+impl TracedEvent {
+    /// Decodes a raw event read from the named perf buffer. The utxocache
+    /// counter increments are decoded from recording::UTXOCACHE_COUNTERS.
+    pub fn decode(buffer: &str, x: &[u8]) -> Result<TracedEvent, DecodeError> {
+        Ok(match buffer {
+            "inbound_messages" => TracedEvent::InboundMessage(P2PMessage::try_from(x)?),
+            "outbound_messages" => TracedEvent::OutboundMessage(P2PMessage::try_from(x)?),
+            "perf_inbound_connections" => {
+                TracedEvent::InboundConnection(NewConnection::try_from(x)?)
+            }
+            "perf_outbound_connections" => {
+                TracedEvent::OutboundConnection(NewConnection::try_from(x)?)
+            }
+            "perf_closed_connections" => {
+                TracedEvent::ClosedConnection(ClosedConnection::try_from(x)?)
+            }
+            "perf_evicted_connections" => {
+                TracedEvent::EvictedConnection(ClosedConnection::try_from(x)?)
+            }
+            "perf_misbehaving_connections" => {
+                TracedEvent::MisbehavingConnection(MisbehavingConnection::try_from(x)?)
+            }
+            "perf_block_connected" => TracedEvent::BlockConnected(BlockConnected::try_from(x)?),
+            "perf_utxocache_flushes" => TracedEvent::UTXOCacheFlush(UTXOCacheFlush::try_from(x)?),
+            "perf_mempool_added" => TracedEvent::MempoolAdded(MempoolAdded::try_from(x)?),
+            "perf_mempool_removed" => TracedEvent::MempoolRemoved(MempoolRemoved::try_from(x)?),
+            "perf_mempool_replaced" => TracedEvent::MempoolReplaced(MempoolReplaced::try_from(x)?),
+            "perf_mempool_rejected" => TracedEvent::MempoolRejected(MempoolRejected::try_from(x)?),
+            recording::UTXOCACHE_COUNTERS => {
+                TracedEvent::UTXOCacheEvents(UTXOCacheEvents::try_from(x)?)
+            }
+            _ => return Err(DecodeError::UnknownBuffer(buffer.to_string())),
+        })
+    }
+
+    /// Decodes a raw event like decode(). Decode failures are logged and
+    /// counted in the decode failure metric of the node.
+    pub fn decode_or_count(node: &str, buffer: &str, x: &[u8]) -> Option<TracedEvent> {
+        match TracedEvent::decode(buffer, x) {
+            Ok(event) => Some(event),
+            Err(e) => {
+                log::debug!(
+                    target: LOG_TARGET,
+                    "Node {}: could not decode event: {}",
+                    node,
+                    e
+                );
+                metrics::RUNTIME_DECODE_FAILURES
+                    .with_label_values(&[node, buffer])
+                    .inc();
+                None
+            }
+        }
+    }
+}
+
+/// A source of decoded events of one or more bitcoind nodes, e.g. the eBPF
+/// programs attached to bitcoind or a recording.
+pub trait EventSource {
+    type Error: error::Error;
+
+    /// Waits up to the timeout for events and passes each of them with the
+    /// name of its node to the handler. Returns false once the source has no
+    /// more events.
+    fn poll(
+        &mut self,
+        timeout: Duration,
+        handler: &mut dyn FnMut(&str, TracedEvent),
+    ) -> Result<bool, Self::Error>;
+}
+
+/// An event source of events pushed in memory, e.g. for tests. Polling
+/// passes all pushed events to the handler at once.
+#[derive(Default)]
+pub struct MemorySource {
+    events: VecDeque<(String, TracedEvent)>,
+}
+
+impl MemorySource {
+    pub fn new() -> MemorySource {
+        MemorySource::default()
+    }
+
+    pub fn push(&mut self, node: &str, event: TracedEvent) {
+        self.events.push_back((node.to_string(), event));
+    }
+}
+
+impl EventSource for MemorySource {
+    type Error = Infallible;
+
+    fn poll(
+        &mut self,
+        _timeout: Duration,
+        handler: &mut dyn FnMut(&str, TracedEvent),
+    ) -> Result<bool, Infallible> {
+        while let Some((node, event)) = self.events.pop_front() {
+            handler(&node, event);
+        }
+        Ok(false)
+    }
+}
@@ -0,0 +1,113 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The bitcoind process(es) the tracepoints are attached to.
+#[derive(Debug, Clone)]
+pub enum TargetProcess {
+    /// Attach to all processes running the bitcoind binary.
+    All,
+    /// Attach to a single process with the given PID.
+    Pid(i32),
+    /// Attach to the process with the PID in bitcoind's pidfile. When the
+    /// process exits, the pidfile is re-read and the tracepoints are
+    /// re-attached to the new process.
+    PidFile(PathBuf),
+}
+
+impl TargetProcess {
+    /// Returns the process to attach to, `None` when attaching to all
+    /// processes or when the PID (in the pidfile) doesn't name a running
+    /// process (yet).
+    pub fn process(&self) -> Option<Process> {
+        match self {
+            TargetProcess::All => None,
+            TargetProcess::Pid(pid) => Process::find(*pid),
+            TargetProcess::PidFile(path) => read_pidfile(path).and_then(Process::find),
+        }
+    }
+
+    /// The name of the mode, used in the `mode` label of the attach metric.
+    pub fn mode(&self) -> &'static str {
+        match self {
+            TargetProcess::All => "all",
+            TargetProcess::Pid(_) => "pid",
+            TargetProcess::PidFile(_) => "pidfile",
+        }
+    }
+}
+
+/// A running process, identified by its PID and its start time. A new
+/// process reusing the PID after the process exited isn't mistaken for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Process {
+    pub pid: i32,
+    /// Clock ticks since boot when the process started.
+    start_time: u64,
+}
+
+impl Process {
+    /// Returns the process with the PID if one is running.
+    pub fn find(pid: i32) -> Option<Process> {
+        let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+        Some(Process {
+            pid,
+            start_time: parse_start_time(&stat)?,
+        })
+    }
+
+    /// Returns true while the process is running, false once it exited,
+    /// even if its PID has been reused by another process since.
+    pub fn is_running(&self) -> bool {
+        Process::find(self.pid) == Some(*self)
+    }
+}
+
+/// Parses the start time from the contents of `/proc/<pid>/stat`. The
+/// command name in parentheses can contain spaces and parentheses, so the
+/// fields are counted from the last closing parenthesis.
+fn parse_start_time(stat: &str) -> Option<u64> {
+    let (_, fields) = stat.rsplit_once(')')?;
+    // starttime is the 22nd field, the 20th after the command name.
+    fields.split_whitespace().nth(19)?.parse().ok()
+}
+
+/// Reads a PID from a pidfile as written by bitcoind (`-pid=<file>`).
+pub fn read_pidfile(path: &Path) -> Option<i32> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    #[test]
+    fn parses_the_start_time() {
+        let stat = "4242 (b-msghand) S 1 4242 4242 0 -1 4194368 2016 0 0 0 13 4 0 0 20 0 \
+                    17 0 123456 1018888192 5051 18446744073709551615 1 1 0 0 0 0 0 4096 17507 \
+                    0 0 0 17 3 0 0 0 0 0\n";
+        assert_eq!(parse_start_time(stat), Some(123_456));
+        let stat = "4242 (a) b (c) S 1 4242 4242 0 -1 4194368 2016 0 0 0 13 4 0 0 20 0 17 0 99 1";
+        assert_eq!(parse_start_time(stat), Some(99));
+        assert_eq!(parse_start_time("4242 (bitcoind) S 1"), None);
+        assert_eq!(parse_start_time(""), None);
+    }
+
+    #[test]
+    fn detects_exited_processes() {
+        let process = Process::find(std::process::id() as i32).unwrap();
+        assert!(process.is_running());
+
+        let mut child = Command::new("true").spawn().unwrap();
+        let exited = Process::find(child.id() as i32).unwrap();
+        child.wait().unwrap();
+        assert!(!exited.is_running());
+
+        // A different process with the same PID.
+        let reused = Process {
+            start_time: process.start_time + 1,
+            ..process
+        };
+        assert!(!reused.is_running());
+    }
+}
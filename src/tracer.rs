@@ -0,0 +1,182 @@
+use std::io;
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bcc::{BPFBuilder, BccError, USDTContext, BPF};
+
+use crate::config::{NodeConfig, TracerConfig};
+use crate::counters::PerCpuCounters;
+use crate::metrics;
+use crate::probes::{self, ProbeGroup};
+use crate::reader::{EventReader, LostCallback, PerfBuffer, PerfCallback};
+use crate::recording;
+use crate::source::{EventSource, TracedEvent, PERF_BUFFERS};
+use crate::types::{self, UTXOCacheEvents};
+
+const LOG_TARGET: &str = "tracer";
+
+/// Interval in which the in-kernel counters are read.
+const COUNTER_READ_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The eBPF programs attached to a bitcoind node as event source. Dropping
+/// the Tracer detaches the probes.
+pub struct Tracer {
+    name: String,
+    reader: EventReader,
+    /// Events decoded by the perf buffer callbacks that weren't handled yet.
+    decoded: Arc<Mutex<Vec<TracedEvent>>>,
+    utxocache_events: Option<PerCpuCounters>,
+    last_counter_read: Instant,
+    _bpf: BPF,
+}
+
+impl Drop for Tracer {
+    fn drop(&mut self) {
+        recording::flush();
+    }
+}
+
+impl Tracer {
+    pub fn attach(
+        node: &NodeConfig,
+        tracer_config: &TracerConfig,
+        pid: Option<i32>,
+    ) -> Result<Tracer, BccError> {
+        let mut usdt_ctx = match (&node.bitcoind_path, pid) {
+            (Some(path), Some(pid)) => USDTContext::from_binary_path_and_pid(path, pid)?,
+            (None, Some(pid)) => USDTContext::from_pid(pid)?,
+            (Some(path), None) => USDTContext::from_binary_path(path)?,
+            (None, None) => unreachable!("a bitcoind path is required without a PID"),
+        };
+        if probes::enable(&mut usdt_ctx, &node.name, &tracer_config.probe_groups) == 0 {
+            return Err(BccError::EnableUSDTProbe);
+        }
+
+        let code = concat!(
+            "#include <uapi/linux/ptrace.h>",
+            "\n\n",
+            include_str!("../ebpf-programs/p2p_in_and_outbound.c"),
+            include_str!("../ebpf-programs/p2p_connections.c"),
+            include_str!("../ebpf-programs/validation_block_connected.c"),
+            include_str!("../ebpf-programs/utxo_set_cache_changes.c"),
+            include_str!("../ebpf-programs/utxo_set_cache_flushes.c"),
+            include_str!("../ebpf-programs/mempool.c"),
+        );
+        let bpf = BPFBuilder::new(code)?.add_usdt_context(usdt_ctx)?.build()?;
+
+        let decoded = Arc::new(Mutex::new(vec![]));
+        let record = recording::is_active();
+        let mut reader = EventReader::new()?;
+        for table in PERF_BUFFERS.iter() {
+            let buffer = PerfBuffer::open(
+                bpf.table(table)?,
+                callback_decode(node.name.clone(), table, decoded.clone(), record),
+                callback_events_lost(node.name.clone(), table),
+                tracer_config.perf_buffer_pages,
+            )?;
+            reader.add(Box::new(buffer))?;
+        }
+
+        // The utxocache events are counted in the kernel instead of being
+        // submitted to a perf buffer.
+        let utxocache_events = if tracer_config.probe_groups.contains(&ProbeGroup::UTXOCache) {
+            Some(PerCpuCounters::new(
+                bpf.table("utxocache_events")?,
+                types::UTXOCACHE_EVENTS,
+            )?)
+        } else {
+            None
+        };
+
+        Ok(Tracer {
+            name: node.name.clone(),
+            reader,
+            decoded,
+            utxocache_events,
+            last_counter_read: Instant::now(),
+            _bpf: bpf,
+        })
+    }
+
+    /// Reads the in-kernel counters and passes their increments to the
+    /// handler. Called periodically when polling and should be called once
+    /// more before detaching.
+    pub fn read_counters(&mut self, handler: &mut dyn FnMut(&str, TracedEvent)) {
+        self.last_counter_read = Instant::now();
+        if let Some(ref mut utxocache_events) = self.utxocache_events {
+            match utxocache_events.increments() {
+                Ok(increments) => {
+                    let utxocache_events = UTXOCacheEvents::from_increments(&increments);
+                    if !utxocache_events.is_empty() {
+                        recording::record(
+                            &self.name,
+                            recording::UTXOCACHE_COUNTERS,
+                            &utxocache_events.to_bytes(),
+                        );
+                        handler(&self.name, TracedEvent::UTXOCacheEvents(utxocache_events));
+                    }
+                }
+                Err(e) => log::warn!(
+                    target: LOG_TARGET,
+                    "Node {}: could not read the utxocache counters: {}",
+                    self.name,
+                    e
+                ),
+            }
+        }
+    }
+}
+
+impl EventSource for Tracer {
+    type Error = io::Error;
+
+    fn poll(
+        &mut self,
+        timeout: Duration,
+        handler: &mut dyn FnMut(&str, TracedEvent),
+    ) -> io::Result<bool> {
+        let result = self.reader.poll(timeout);
+        let decoded = mem::take(&mut *self.decoded.lock().unwrap());
+        for event in decoded {
+            handler(&self.name, event);
+        }
+        if self.last_counter_read.elapsed() >= COUNTER_READ_INTERVAL {
+            self.read_counters(handler);
+        }
+        result.map(|_| true)
+    }
+}
+
+/// Decodes the raw events of a perf buffer for the next poll, recording
+/// them first if recording is enabled.
+fn callback_decode(
+    node: String,
+    buffer: &'static str,
+    decoded: Arc<Mutex<Vec<TracedEvent>>>,
+    record: bool,
+) -> PerfCallback {
+    Box::new(move |x| {
+        if record {
+            recording::record(&node, buffer, x);
+        }
+        if let Some(event) = TracedEvent::decode_or_count(&node, buffer, x) {
+            decoded.lock().unwrap().push(event);
+        }
+    })
+}
+
+fn callback_events_lost(node: String, buffer: &str) -> LostCallback {
+    let events_lost = metrics::RUNTIME_EVENTS_LOST.with_label_values(&[&node, buffer]);
+    let buffer = buffer.to_string();
+    Box::new(move |lost| {
+        log::debug!(
+            target: LOG_TARGET,
+            "Node {}: lost {} events in perf buffer {}.",
+            node,
+            lost,
+            buffer
+        );
+        events_lost.inc_by(lost);
+    })
+}
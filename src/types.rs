@@ -1,10 +1,110 @@
-use std::{fmt, ptr};
+use std::convert::TryFrom;
+use std::error;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::{fmt, mem, ptr};
 
 // Tor v3 addresses are 62 chars + 6 chars for the port (':12345').
 const MAX_PEER_ADDR_LENGTH: usize = 62 + 6;
 const MAX_PEER_CONN_TYPE_LENGTH: usize = 20;
 const MAX_MSG_TYPE_LENGTH: usize = 20;
 
+const MAX_MISBEHAVING_MESSAGE_LENGTH: usize = 128;
+
+const HASH_LENGTH: usize = 32;
+// Longest removal reason is 'sizelimit' with 9 chars + the null terminator.
+const MAX_REMOVAL_REASON_LENGTH: usize = 9 + 1;
+const MAX_REJECT_REASON_LENGTH: usize = 118;
+
+/// Formats a hash as hex in reversed byte order, as txids and block hashes
+/// are usually displayed.
+pub fn reversed_hex(hash: &[u8]) -> String {
+    hash.iter().rev().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// An error decoding a raw event.
+#[derive(Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The raw event doesn't have the size of the event struct.
+    Size {
+        event: &'static str,
+        expected: usize,
+        actual: usize,
+    },
+    /// The raw event is from an unknown perf buffer.
+    UnknownBuffer(String),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::Size {
+                event,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{} has {} bytes, expected {} bytes",
+                event, actual, expected
+            ),
+            DecodeError::UnknownBuffer(buffer) => write!(f, "unknown perf buffer {}", buffer),
+        }
+    }
+}
+
+impl error::Error for DecodeError {}
+
+/// Reads a #[repr(C)] event struct from a raw event. The kernel pads the
+/// events in perf buffers to 8 bytes, so up to 7 bytes more than the size of
+/// the struct are accepted.
+///
+/// Only used for structs consisting of integers and arrays of them, for which
+/// any bit pattern is valid.
+fn read_event<T>(event: &'static str, x: &[u8]) -> Result<T, DecodeError> {
+    let expected = mem::size_of::<T>();
+    if x.len() < expected || x.len() - expected >= 8 {
+        return Err(DecodeError::Size {
+            event,
+            expected,
+            actual: x.len(),
+        });
+    }
+    // Safety: the slice holds enough bytes and any bit pattern is valid.
+    Ok(unsafe { ptr::read_unaligned(x.as_ptr() as *const T) })
+}
+
+/// Implements TryFrom<&[u8]> with read_event() for event structs.
+macro_rules! impl_try_from_bytes {
+    ($($event:ident),+) => {
+        $(
+            impl TryFrom<&[u8]> for $event {
+                type Error = DecodeError;
+
+                fn try_from(x: &[u8]) -> Result<$event, DecodeError> {
+                    read_event(stringify!($event), x)
+                }
+            }
+        )+
+    };
+}
+
+impl_try_from_bytes!(
+    P2PMessage,
+    NewConnection,
+    ClosedConnection,
+    MisbehavingConnection,
+    BlockConnected,
+    UTXOCacheFlush,
+    MempoolAdded,
+    MempoolRemoved,
+    MempoolReplaced,
+    MempoolRejected
+);
+
+/// Returns the string up to the first null byte in a C char array.
+fn c_string(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes.split(|c| *c == 0x00u8).next().unwrap()).into_owned()
+}
+
 /// Represents an inbound or outbound P2P message.
 #[repr(C)]
 pub struct P2PMessage {
@@ -16,22 +116,16 @@ pub struct P2PMessage {
 }
 
 impl P2PMessage {
-    pub fn from_bytes(x: &[u8]) -> P2PMessage {
-        unsafe { ptr::read_unaligned(x.as_ptr() as *const P2PMessage) }
-    }
-
     pub fn get_peer_addr(&self) -> String {
-        String::from_utf8_lossy(&self.peer_addr.split(|c| *c == 0x00u8).next().unwrap())
-            .into_owned()
+        c_string(&self.peer_addr)
     }
 
     pub fn get_peer_conn_type(&self) -> String {
-        String::from_utf8_lossy(&self.peer_conn_type.split(|c| *c == 0x00u8).next().unwrap())
-            .into_owned()
+        c_string(&self.peer_conn_type)
     }
 
     pub fn get_msg_type(&self) -> String {
-        String::from_utf8_lossy(&self.msg_type.split(|c| *c == 0x00u8).next().unwrap()).into_owned()
+        c_string(&self.msg_type)
     }
 }
 
@@ -49,9 +143,194 @@ impl fmt::Display for P2PMessage {
     }
 }
 
+pub const NETWORK_UNROUTABLE: u32 = 0;
+pub const NETWORK_IPV4: u32 = 1;
+pub const NETWORK_IPV6: u32 = 2;
+pub const NETWORK_ONION: u32 = 3;
+pub const NETWORK_I2P: u32 = 4;
+pub const NETWORK_CJDNS: u32 = 5;
+pub const NETWORK_INTERNAL: u32 = 6;
+
+/// Returns the name of a network as passed by bitcoind's net tracepoints.
+pub fn network_name(network: u32) -> &'static str {
+    match network {
+        NETWORK_UNROUTABLE => "unroutable",
+        NETWORK_IPV4 => "ipv4",
+        NETWORK_IPV6 => "ipv6",
+        NETWORK_ONION => "onion",
+        NETWORK_I2P => "i2p",
+        NETWORK_CJDNS => "cjdns",
+        NETWORK_INTERNAL => "internal",
+        _ => "unknown",
+    }
+}
+
+/// Classifies a peer address as formatted by bitcoind (e.g. `1.2.3.4:8333`,
+/// `[2001:db8::1]:8333`, `abc...xyz.onion:8333` or `abc...xyz.b32.i2p:0`)
+/// into the network names returned by network_name(). Like bitcoind, CJDNS
+/// addresses are IPv6 addresses in fc00::/8, local, private and reserved
+/// addresses are unroutable and IPv6 addresses with an embedded IPv4 address
+/// (6to4, Teredo and NAT64) are IPv4.
+pub fn network_from_addr(addr: &str) -> &'static str {
+    let host = match addr.rsplit_once(':') {
+        // [ipv6]:port
+        Some((host, _)) if host.starts_with('[') && host.ends_with(']') => &host[1..host.len() - 1],
+        // host:port, but not an IPv6 address without brackets and port
+        Some((host, _)) if !host.contains(':') => host,
+        _ => addr,
+    };
+
+    if host.ends_with(".onion") {
+        return network_name(NETWORK_ONION);
+    }
+    if host.ends_with(".i2p") {
+        return network_name(NETWORK_I2P);
+    }
+    if let Ok(ip) = host.parse::<Ipv4Addr>() {
+        let octets = ip.octets();
+        if ip.is_private()
+            || ip.is_loopback()
+            || ip.is_link_local()
+            || ip.is_broadcast()
+            || ip.is_documentation()
+            // 0.0.0.0/8
+            || octets[0] == 0
+            // shared address space 100.64.0.0/10
+            || (octets[0] == 100 && octets[1] & 0xc0 == 64)
+            // benchmarking 198.18.0.0/15
+            || (octets[0] == 198 && octets[1] & 0xfe == 18)
+        {
+            return network_name(NETWORK_UNROUTABLE);
+        }
+        return network_name(NETWORK_IPV4);
+    }
+    if let Ok(ip) = host.parse::<Ipv6Addr>() {
+        if let Some(ipv4) = ip.to_ipv4_mapped() {
+            return network_from_addr(&ipv4.to_string());
+        }
+        let segments = ip.segments();
+        if segments[0] & 0xff00 == 0xfc00 {
+            return network_name(NETWORK_CJDNS);
+        }
+        if ip.is_loopback()
+            || ip.is_unspecified()
+            // unique local fd00::/8 and link-local fe80::/10
+            || segments[0] & 0xff00 == 0xfd00
+            || segments[0] & 0xffc0 == 0xfe80
+            // documentation 2001:db8::/32
+            || (segments[0] == 0x2001 && segments[1] == 0x0db8)
+            // ORCHID 2001:10::/28 and ORCHIDv2 2001:20::/28
+            || (segments[0] == 0x2001 && matches!(segments[1] & 0xfff0, 0x0010 | 0x0020))
+        {
+            return network_name(NETWORK_UNROUTABLE);
+        }
+        // 6to4 2002::/16, Teredo 2001::/32 and NAT64 64:ff9b::/96
+        if segments[0] == 0x2002
+            || (segments[0] == 0x2001 && segments[1] == 0)
+            || (segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0])
+        {
+            return network_name(NETWORK_IPV4);
+        }
+        return network_name(NETWORK_IPV6);
+    }
+    "unknown"
+}
+
+/// Represents a new inbound or outbound connection
+/// (net:{inbound, outbound}_connection tracepoints).
+#[repr(C)]
+pub struct NewConnection {
+    pub id: u64,
+    pub addr: [u8; MAX_PEER_ADDR_LENGTH],
+    pub conn_type: [u8; MAX_PEER_CONN_TYPE_LENGTH],
+    pub network: u32,
+    /// Number of existing inbound or outbound connections.
+    pub existing: u64,
+}
+
+impl NewConnection {
+    pub fn get_addr(&self) -> String {
+        c_string(&self.addr)
+    }
+
+    pub fn get_conn_type(&self) -> String {
+        c_string(&self.conn_type)
+    }
+}
+
+impl fmt::Display for NewConnection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "new connection to peer {} ({}, {}, {}) with {} existing",
+            self.id,
+            self.get_addr(),
+            self.get_conn_type(),
+            network_name(self.network),
+            self.existing,
+        )
+    }
+}
+
+/// Represents a closed or evicted connection
+/// (net:{closed, evicted_inbound}_connection tracepoints).
+#[repr(C)]
+pub struct ClosedConnection {
+    pub id: u64,
+    pub addr: [u8; MAX_PEER_ADDR_LENGTH],
+    pub conn_type: [u8; MAX_PEER_CONN_TYPE_LENGTH],
+    pub network: u32,
+    /// UNIX epoch timestamp in seconds of when the connection was established.
+    pub time_established: u64,
+}
+
+impl ClosedConnection {
+    pub fn get_addr(&self) -> String {
+        c_string(&self.addr)
+    }
+
+    pub fn get_conn_type(&self) -> String {
+        c_string(&self.conn_type)
+    }
+}
+
+impl fmt::Display for ClosedConnection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "closed connection to peer {} ({}, {}, {}) established at {}",
+            self.id,
+            self.get_addr(),
+            self.get_conn_type(),
+            network_name(self.network),
+            self.time_established,
+        )
+    }
+}
+
+/// Represents a misbehaving peer (net:misbehaving_connection tracepoint).
+#[repr(C)]
+pub struct MisbehavingConnection {
+    pub id: u64,
+    pub message: [u8; MAX_MISBEHAVING_MESSAGE_LENGTH],
+}
+
+impl MisbehavingConnection {
+    pub fn get_message(&self) -> String {
+        c_string(&self.message)
+    }
+}
+
+impl fmt::Display for MisbehavingConnection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "peer {} misbehaving: {}", self.id, self.get_message())
+    }
+}
+
 /// Represents a connected block.
 #[repr(C)]
 pub struct BlockConnected {
+    pub hash: [u8; HASH_LENGTH],
     pub height: i32,
     pub transactions: u64,
     pub inputs: i32,
@@ -59,35 +338,57 @@ pub struct BlockConnected {
     pub connection_time: u64,
 }
 
-impl BlockConnected {
-    pub fn from_bytes(x: &[u8]) -> BlockConnected {
-        unsafe { ptr::read_unaligned(x.as_ptr() as *const BlockConnected) }
-    }
-}
-
 impl fmt::Display for BlockConnected {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
-            "connected height={} tx={}, ins={}, sigops={} time={}µs",
-            self.height, self.transactions, self.inputs, self.sigops, self.connection_time,
+            "connected hash={} height={} tx={}, ins={}, sigops={} time={}µs",
+            reversed_hex(&self.hash),
+            self.height,
+            self.transactions,
+            self.inputs,
+            self.sigops,
+            self.connection_time,
         )
     }
 }
 
-pub const UTXOCACHE_ADD: u8 = 0;
-pub const UTXOCACHE_SPENT: u8 = 1;
-pub const UTXOCACHE_UNCACHE: u8 = 2;
+/// Indices of the utxocache:{add, spent, uncache} counters in the
+/// utxocache_events per-CPU array.
+pub const UTXOCACHE_ADD: usize = 0;
+pub const UTXOCACHE_SPENT: usize = 1;
+pub const UTXOCACHE_UNCACHE: usize = 2;
+pub const UTXOCACHE_EVENTS: usize = 3;
 
-/// Represents a utxocache event (utxocache:{add, spent, uncache} tracepoints).
-#[repr(C)]
-pub struct UTXOCacheEvent {
-    pub event: u8,
+/// Number of utxocache:{add, spent, uncache} events since the in-kernel
+/// counters were last read.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct UTXOCacheEvents {
+    pub add: u64,
+    pub spent: u64,
+    pub uncache: u64,
 }
 
-impl UTXOCacheEvent {
-    pub fn from_bytes(x: &[u8]) -> UTXOCacheEvent {
-        unsafe { ptr::read_unaligned(x.as_ptr() as *const UTXOCacheEvent) }
+impl UTXOCacheEvents {
+    /// Takes the increments of the utxocache_events counters, indexed by
+    /// UTXOCACHE_ADD, UTXOCACHE_SPENT and UTXOCACHE_UNCACHE.
+    pub fn from_increments(increments: &[u64]) -> UTXOCacheEvents {
+        UTXOCacheEvents {
+            add: increments[UTXOCACHE_ADD],
+            spent: increments[UTXOCACHE_SPENT],
+            uncache: increments[UTXOCACHE_UNCACHE],
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [self.add, self.spent, self.uncache]
+            .iter()
+            .flat_map(|count| count.to_le_bytes())
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.add == 0 && self.spent == 0 && self.uncache == 0
     }
 }
 
@@ -96,6 +397,27 @@ pub const UTXOCACHE_FLUSHMODE_IFNEEDED: u32 = 1;
 pub const UTXOCACHE_FLUSHMODE_PERIODIC: u32 = 2;
 pub const UTXOCACHE_FLUSHMODE_ALWAYS: u32 = 3;
 
+/// Reads the counts as little-endian u64 values in counter index order, as
+/// written by UTXOCacheEvents::to_bytes().
+impl TryFrom<&[u8]> for UTXOCacheEvents {
+    type Error = DecodeError;
+
+    fn try_from(x: &[u8]) -> Result<UTXOCacheEvents, DecodeError> {
+        if x.len() != UTXOCACHE_EVENTS * 8 {
+            return Err(DecodeError::Size {
+                event: "UTXOCacheEvents",
+                expected: UTXOCACHE_EVENTS * 8,
+                actual: x.len(),
+            });
+        }
+        let increments: Vec<u64> = x
+            .chunks_exact(8)
+            .map(|c| u64::from_le_bytes([c[0], c[1], c[2], c[3], c[4], c[5], c[6], c[7]]))
+            .collect();
+        Ok(UTXOCacheEvents::from_increments(&increments))
+    }
+}
+
 /// Represents an UTXO cache flush.
 #[repr(C)]
 pub struct UTXOCacheFlush {
@@ -103,14 +425,11 @@ pub struct UTXOCacheFlush {
     pub mode: u32,
     pub coins_count: u64,
     pub coins_memusage: u64,
-    pub flush_for_prune: bool,
+    /// A C bool. Read as u8, as not every byte is a valid Rust bool.
+    pub flush_for_prune: u8,
 }
 
 impl UTXOCacheFlush {
-    pub fn from_bytes(x: &[u8]) -> UTXOCacheFlush {
-        unsafe { ptr::read_unaligned(x.as_ptr() as *const UTXOCacheFlush) }
-    }
-
     pub fn flush_mode(&self) -> &str {
         match self.mode {
             UTXOCACHE_FLUSHMODE_NONE => "NONE",
@@ -122,7 +441,7 @@ impl UTXOCacheFlush {
     }
 
     pub fn flush_for_prune(&self) -> &str {
-        if self.flush_for_prune {
+        if self.flush_for_prune != 0 {
             "true"
         } else {
             "false"
@@ -130,4 +449,169 @@ impl UTXOCacheFlush {
     }
 }
 
+/// Represents a transaction added to the mempool (mempool:added tracepoint).
+#[repr(C)]
+pub struct MempoolAdded {
+    pub txid: [u8; HASH_LENGTH],
+    pub vsize: i32,
+    pub fee: i64,
+}
+
+impl fmt::Display for MempoolAdded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "added txid={} vsize={} fee={}",
+            reversed_hex(&self.txid),
+            self.vsize,
+            self.fee,
+        )
+    }
+}
+
+/// Represents a transaction removed from the mempool (mempool:removed
+/// tracepoint).
+#[repr(C)]
+pub struct MempoolRemoved {
+    pub txid: [u8; HASH_LENGTH],
+    pub reason: [u8; MAX_REMOVAL_REASON_LENGTH],
+    pub vsize: i32,
+    pub fee: i64,
+    pub entry_time: u64,
+}
+
+impl MempoolRemoved {
+    pub fn get_reason(&self) -> String {
+        c_string(&self.reason)
+    }
+}
+
+impl fmt::Display for MempoolRemoved {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "removed txid={} reason={} vsize={} fee={} entry_time={}",
+            reversed_hex(&self.txid),
+            self.get_reason(),
+            self.vsize,
+            self.fee,
+            self.entry_time,
+        )
+    }
+}
+
+/// Represents a mempool transaction replaced by another transaction
+/// (mempool:replaced tracepoint).
+#[repr(C)]
+pub struct MempoolReplaced {
+    pub replaced_txid: [u8; HASH_LENGTH],
+    pub replaced_vsize: i32,
+    pub replaced_fee: i64,
+    pub replaced_entry_time: u64,
+    pub replacement_txid: [u8; HASH_LENGTH],
+    pub replacement_vsize: i32,
+    pub replacement_fee: i64,
+}
+
+impl fmt::Display for MempoolReplaced {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "replaced txid={} (vsize={} fee={}) with txid={} (vsize={} fee={})",
+            reversed_hex(&self.replaced_txid),
+            self.replaced_vsize,
+            self.replaced_fee,
+            reversed_hex(&self.replacement_txid),
+            self.replacement_vsize,
+            self.replacement_fee,
+        )
+    }
+}
+
+/// Represents a transaction rejected from the mempool (mempool:rejected
+/// tracepoint).
+#[repr(C)]
+pub struct MempoolRejected {
+    pub txid: [u8; HASH_LENGTH],
+    pub reason: [u8; MAX_REJECT_REASON_LENGTH],
+}
+
+impl MempoolRejected {
+    pub fn get_reason(&self) -> String {
+        c_string(&self.reason)
+    }
+}
+
+impl fmt::Display for MempoolRejected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "rejected txid={} reason={}",
+            reversed_hex(&self.txid),
+            self.get_reason(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
+    #[test]
+    fn classifies_peer_addresses() {
+        let onion = "pg6mmjiyjmcrsslvykfwnntlaru7p5svn6y2ymmju6nubxndf4pscryd.onion";
+        let i2p = "ukeu3k5oycgaauneqgtnvselmt4yemvoilkln7jpvamvfx7dnkdq.b32.i2p";
+        let cases = [
+            // IPv4
+            ("1.1.1.1:8333", "ipv4"),
+            ("1.1.1.1", "ipv4"),
+            ("100.128.0.1:8333", "ipv4"),
+            ("127.0.0.1:8333", "unroutable"),
+            ("10.0.0.1:8333", "unroutable"),
+            ("172.16.0.1:8333", "unroutable"),
+            ("192.168.1.1:8333", "unroutable"),
+            ("169.254.1.1:8333", "unroutable"),
+            ("0.0.0.0:8333", "unroutable"),
+            ("0.1.2.3:8333", "unroutable"),
+            ("100.64.0.1:8333", "unroutable"),
+            ("198.18.0.1:8333", "unroutable"),
+            ("192.0.2.1:8333", "unroutable"),
+            ("255.255.255.255:8333", "unroutable"),
+            // IPv6
+            ("[2606:4700:4700::1111]:8333", "ipv6"),
+            ("2606:4700:4700::1111", "ipv6"),
+            ("[::1]:8333", "unroutable"),
+            ("[::]:8333", "unroutable"),
+            ("[fd12:3456::1]:8333", "unroutable"),
+            ("[fe80::1]:8333", "unroutable"),
+            ("[2001:db8::1]:8333", "unroutable"),
+            ("[2001:10::1]:8333", "unroutable"),
+            ("[2001:20::1]:8333", "unroutable"),
+            // IPv6 with an embedded IPv4 address
+            ("[::ffff:1.1.1.1]:8333", "ipv4"),
+            ("[::ffff:10.0.0.1]:8333", "unroutable"),
+            ("[2002:101:101::1]:8333", "ipv4"),
+            ("[2001:0:4136:e378:8000:63bf:3fff:fdd2]:8333", "ipv4"),
+            ("[64:ff9b::101:101]:8333", "ipv4"),
+            // Tor, I2P and CJDNS
+            (&format!("{}:8333", onion), "onion"),
+            (onion, "onion"),
+            (&format!("{}:0", i2p), "i2p"),
+            (i2p, "i2p"),
+            ("[fc32:17ea:e415:c3bf:9808:149d:b5a2:c9aa]:8333", "cjdns"),
+            ("[fc00::1]:8333", "cjdns"),
+            // Malformed
+            ("", "unknown"),
+            ("bitcoind", "unknown"),
+            ("example.com:8333", "unknown"),
+            ("1.2.3:8333", "unknown"),
+            ("1.2.3.256:8333", "unknown"),
+            ("[::1:8333", "unknown"),
+            ("[fc00::1", "unknown"),
+            ("i2p:8333", "unknown"),
+        ];
+        for (addr, network) in cases.iter() {
+            assert_eq!(network_from_addr(addr), *network, "{}", addr);
+        }
+    }
+}
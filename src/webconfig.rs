@@ -0,0 +1,353 @@
+use std::collections::{HashMap, HashSet};
+use std::error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use base64::Engine;
+use lazy_static::lazy_static;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig, SupportedProtocolVersion};
+use serde::Deserialize;
+
+use crate::http::{Request, Response};
+
+// The web config file of the metric server, in the format of the Prometheus
+// exporter-toolkit (https://github.com/prometheus/exporter-toolkit/blob/master/docs/web-configuration.md):
+//
+//   tls_server_config:
+//     cert_file: server.crt
+//     key_file: server.key
+//     client_auth_type: RequireAndVerifyClientCert
+//     client_ca_file: ca.crt
+//     min_version: TLS12
+//   basic_auth_users:
+//     prometheus: $2y$10$... # bcrypt hash of the password
+//
+// As an addition to the format, bearer_tokens is a list of bcrypt hashes of
+// accepted bearer tokens. Relative paths are relative to the directory of
+// the file. Unsupported options of the format are rejected instead of being
+// ignored.
+
+/// Number of successfully verified credentials that are cached, as verifying
+/// a bcrypt hash deliberately takes a while.
+const MAX_CACHED_CREDENTIALS: usize = 1024;
+
+lazy_static! {
+    /// Verified against for unknown users, so that they take as long to
+    /// reject as wrong passwords.
+    static ref DUMMY_HASH: String = bcrypt::hash("", 10).unwrap();
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WebConfigFile {
+    tls_server_config: Option<TlsServerConfigFile>,
+    #[serde(default)]
+    basic_auth_users: HashMap<String, String>,
+    #[serde(default)]
+    bearer_tokens: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TlsServerConfigFile {
+    cert_file: PathBuf,
+    key_file: PathBuf,
+    #[serde(default)]
+    client_auth_type: ClientAuthType,
+    client_ca_file: Option<PathBuf>,
+    min_version: Option<TlsVersion>,
+    max_version: Option<TlsVersion>,
+}
+
+/// The client certificate policies of the exporter-toolkit. The policies
+/// accepting unverified certificates aren't supported.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+enum ClientAuthType {
+    #[default]
+    NoClientCert,
+    RequestClientCert,
+    RequireAnyClientCert,
+    VerifyClientCertIfGiven,
+    RequireAndVerifyClientCert,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd, Deserialize)]
+enum TlsVersion {
+    TLS10,
+    TLS11,
+    TLS12,
+    TLS13,
+}
+
+/// A loaded web config: the TLS configuration, if TLS is enabled, and the
+/// credentials required for requests.
+pub struct WebConfig {
+    path: PathBuf,
+    tls: Option<Arc<ServerConfig>>,
+    basic_auth_users: HashMap<String, String>,
+    bearer_tokens: Vec<String>,
+    verified: Mutex<HashSet<String>>,
+}
+
+impl WebConfig {
+    /// Loads and validates the web config file and the files it references.
+    pub fn load(path: &Path) -> Result<WebConfig, WebConfigError> {
+        let contents =
+            fs::read_to_string(path).map_err(|e| WebConfigError::Io(path.to_path_buf(), e))?;
+        let file: WebConfigFile = serde_yaml::from_str(&contents)?;
+        let directory = path.parent().unwrap_or_else(|| Path::new(""));
+
+        let tls = match file.tls_server_config {
+            Some(ref tls) => Some(Arc::new(tls_config(tls, directory)?)),
+            None => None,
+        };
+        for (user, hash) in file.basic_auth_users.iter() {
+            if hash.parse::<bcrypt::HashParts>().is_err() {
+                return Err(WebConfigError::Invalid(format!(
+                    "the password of basic auth user {} isn't a bcrypt hash",
+                    user
+                )));
+            }
+        }
+        if file
+            .bearer_tokens
+            .iter()
+            .any(|h| h.parse::<bcrypt::HashParts>().is_err())
+        {
+            return Err(WebConfigError::Invalid(String::from(
+                "a bearer token isn't a bcrypt hash",
+            )));
+        }
+
+        Ok(WebConfig {
+            path: path.to_path_buf(),
+            tls,
+            basic_auth_users: file.basic_auth_users,
+            bearer_tokens: file.bearer_tokens,
+            verified: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// Loads the file again, e.g. after the certificate was renewed.
+    pub fn reload(&self) -> Result<WebConfig, WebConfigError> {
+        WebConfig::load(&self.path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The TLS configuration, if TLS is enabled.
+    pub fn tls(&self) -> Option<Arc<ServerConfig>> {
+        self.tls.clone()
+    }
+
+    /// Checks the credentials in the Authorization header of the request if
+    /// any are required. Returns the 401 Unauthorized response if the
+    /// request isn't authorized.
+    pub fn authorize(&self, request: &Request) -> Option<Response> {
+        if self.basic_auth_users.is_empty() && self.bearer_tokens.is_empty() {
+            return None;
+        }
+        let authorized = match request
+            .header("Authorization")
+            .and_then(|a| a.split_once(' '))
+        {
+            Some((scheme, credentials)) if scheme.eq_ignore_ascii_case("Basic") => {
+                self.verify_basic(credentials.trim())
+            }
+            Some((scheme, token)) if scheme.eq_ignore_ascii_case("Bearer") => {
+                self.verify_bearer(token.trim())
+            }
+            _ => false,
+        };
+        if authorized {
+            return None;
+        }
+
+        let mut response = Response::text(401, "unauthorized");
+        if !self.basic_auth_users.is_empty() {
+            response = response.header("WWW-Authenticate", "Basic realm=\"bitcoind-observer\"");
+        }
+        if !self.bearer_tokens.is_empty() {
+            response = response.header("WWW-Authenticate", "Bearer realm=\"bitcoind-observer\"");
+        }
+        Some(response)
+    }
+
+    fn verify_basic(&self, credentials: &str) -> bool {
+        let decoded = match base64::engine::general_purpose::STANDARD.decode(credentials) {
+            Ok(decoded) => decoded,
+            Err(_) => return false,
+        };
+        let decoded = String::from_utf8_lossy(&decoded);
+        let (user, password) = match decoded.split_once(':') {
+            Some(credentials) => credentials,
+            None => return false,
+        };
+        match self.basic_auth_users.get(user) {
+            Some(hash) => self.verify(password, hash),
+            None => {
+                let _ = bcrypt::verify(password, &DUMMY_HASH);
+                false
+            }
+        }
+    }
+
+    fn verify_bearer(&self, token: &str) -> bool {
+        self.bearer_tokens
+            .iter()
+            .any(|hash| self.verify(token, hash))
+    }
+
+    /// Verifies the secret against the bcrypt hash, caching the result if
+    /// it's correct.
+    fn verify(&self, secret: &str, hash: &str) -> bool {
+        let key = format!("{}\0{}", hash, secret);
+        if self.verified.lock().unwrap().contains(&key) {
+            return true;
+        }
+        if !bcrypt::verify(secret, hash).unwrap_or(false) {
+            return false;
+        }
+        let mut verified = self.verified.lock().unwrap();
+        if verified.len() >= MAX_CACHED_CREDENTIALS {
+            verified.clear();
+        }
+        verified.insert(key);
+        true
+    }
+}
+
+fn tls_config(tls: &TlsServerConfigFile, directory: &Path) -> Result<ServerConfig, WebConfigError> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+
+    let min_version = tls.min_version.unwrap_or(TlsVersion::TLS12);
+    let max_version = tls.max_version.unwrap_or(TlsVersion::TLS13);
+    if min_version < TlsVersion::TLS12 {
+        return Err(WebConfigError::Invalid(String::from(
+            "TLS versions below TLS12 aren't supported",
+        )));
+    }
+    let versions: Vec<&'static SupportedProtocolVersion> = [
+        (TlsVersion::TLS12, &rustls::version::TLS12),
+        (TlsVersion::TLS13, &rustls::version::TLS13),
+    ]
+    .iter()
+    .filter(|(v, _)| min_version <= *v && *v <= max_version)
+    .map(|(_, v)| *v)
+    .collect();
+    if versions.is_empty() {
+        return Err(WebConfigError::Invalid(String::from(
+            "max_version is below min_version",
+        )));
+    }
+
+    let builder =
+        ServerConfig::builder_with_provider(provider.clone()).with_protocol_versions(&versions)?;
+
+    let builder = match tls.client_auth_type {
+        ClientAuthType::NoClientCert => builder.with_no_client_auth(),
+        ClientAuthType::RequestClientCert | ClientAuthType::RequireAnyClientCert => {
+            return Err(WebConfigError::Invalid(format!(
+                "client_auth_type {:?} isn't supported, as it doesn't verify the client certificates",
+                tls.client_auth_type
+            )))
+        }
+        ClientAuthType::VerifyClientCertIfGiven | ClientAuthType::RequireAndVerifyClientCert => {
+            let ca_file = tls.client_ca_file.as_ref().ok_or_else(|| {
+                WebConfigError::Invalid(format!(
+                    "client_auth_type {:?} requires a client_ca_file",
+                    tls.client_auth_type
+                ))
+            })?;
+            let mut roots = RootCertStore::empty();
+            for certificate in read_certificates(&directory.join(ca_file))? {
+                roots.add(certificate)?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+            let verifier = if tls.client_auth_type == ClientAuthType::VerifyClientCertIfGiven {
+                verifier.allow_unauthenticated()
+            } else {
+                verifier
+            };
+            let verifier = verifier
+                .build()
+                .map_err(|e| WebConfigError::Invalid(format!("invalid client_ca_file: {}", e)))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+    };
+
+    let certificates = read_certificates(&directory.join(&tls.cert_file))?;
+    let key_file = directory.join(&tls.key_file);
+    let key = read_private_key(&key_file)?;
+    Ok(builder.with_single_cert(certificates, key)?)
+}
+
+fn read_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, WebConfigError> {
+    let file = File::open(path).map_err(|e| WebConfigError::Io(path.to_path_buf(), e))?;
+    let certificates = rustls_pemfile::certs(&mut BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| WebConfigError::Io(path.to_path_buf(), e))?;
+    if certificates.is_empty() {
+        return Err(WebConfigError::Invalid(format!(
+            "no certificates in {}",
+            path.display()
+        )));
+    }
+    Ok(certificates)
+}
+
+fn read_private_key(path: &Path) -> Result<PrivateKeyDer<'static>, WebConfigError> {
+    let file = File::open(path).map_err(|e| WebConfigError::Io(path.to_path_buf(), e))?;
+    rustls_pemfile::private_key(&mut BufReader::new(file))
+        .map_err(|e| WebConfigError::Io(path.to_path_buf(), e))?
+        .ok_or_else(|| WebConfigError::Invalid(format!("no private key in {}", path.display())))
+}
+
+#[derive(Debug)]
+pub enum WebConfigError {
+    Io(PathBuf, io::Error),
+    Yaml(serde_yaml::Error),
+    Tls(rustls::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for WebConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WebConfigError::Io(path, e) => write!(f, "could not read {}: {}", path.display(), e),
+            WebConfigError::Yaml(e) => write!(f, "invalid web config: {}", e),
+            WebConfigError::Tls(e) => write!(f, "invalid TLS config: {}", e),
+            WebConfigError::Invalid(reason) => write!(f, "invalid web config: {}", reason),
+        }
+    }
+}
+
+impl error::Error for WebConfigError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            WebConfigError::Io(_, ref e) => Some(e),
+            WebConfigError::Yaml(ref e) => Some(e),
+            WebConfigError::Tls(ref e) => Some(e),
+            WebConfigError::Invalid(_) => None,
+        }
+    }
+}
+
+impl From<serde_yaml::Error> for WebConfigError {
+    fn from(err: serde_yaml::Error) -> WebConfigError {
+        WebConfigError::Yaml(err)
+    }
+}
+
+impl From<rustls::Error> for WebConfigError {
+    fn from(err: rustls::Error) -> WebConfigError {
+        WebConfigError::Tls(err)
+    }
+}
@@ -0,0 +1,494 @@
+//! Integration tests sending raw requests to the metric server.
+
+use std::collections::BTreeSet;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+use bitcoind_observer::blocks::{self, BlockRecord};
+use bitcoind_observer::events::{Event, EventFilter, EventRecord, Message};
+use bitcoind_observer::http::{decode_query_component, http_date, quality_values};
+use bitcoind_observer::types::BlockConnected;
+use bitcoind_observer::{metrics, metricserver};
+use flate2::read::GzDecoder;
+
+struct Client {
+    reader: BufReader<TcpStream>,
+}
+
+/// A response with lowercase header names.
+struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+impl Client {
+    fn connect(address: SocketAddr) -> Client {
+        let stream = TcpStream::connect(address).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        Client {
+            reader: BufReader::new(stream),
+        }
+    }
+
+    fn send(&mut self, request: &str) {
+        self.reader.get_mut().write_all(request.as_bytes()).unwrap();
+    }
+
+    fn response(&mut self, head: bool) -> Response {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).unwrap();
+        let status = line.split(' ').nth(1).unwrap().parse().unwrap();
+        let mut headers = vec![];
+        loop {
+            let mut line = String::new();
+            self.reader.read_line(&mut line).unwrap();
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line.split_once(':').unwrap();
+            headers.push((name.to_lowercase(), value.trim().to_string()));
+        }
+        let mut response = Response {
+            status,
+            headers,
+            body: vec![],
+        };
+        if !head {
+            let length = response.header("content-length").unwrap().parse().unwrap();
+            let mut body = vec![0; length];
+            self.reader.read_exact(&mut body).unwrap();
+            response.body = body;
+        }
+        response
+    }
+
+    fn get(&mut self, path: &str) -> Response {
+        self.get_with(path, "")
+    }
+
+    /// Sends a GET request with additional header lines.
+    fn get_with(&mut self, path: &str, headers: &str) -> Response {
+        self.send(&format!(
+            "GET {} HTTP/1.1\r\nHost: test\r\n{}\r\n",
+            path, headers
+        ));
+        self.response(false)
+    }
+
+    /// Whether the server closed the connection.
+    fn is_closed(&mut self) -> bool {
+        let mut buffer = [0; 1];
+        matches!(self.reader.read(&mut buffer), Ok(0))
+    }
+}
+
+fn start() -> SocketAddr {
+    metricserver::start("127.0.0.1:0", None).unwrap()
+}
+
+#[test]
+fn routes_requests() {
+    let mut client = Client::connect(start());
+
+    let metrics = client.get("/metrics");
+    assert_eq!(metrics.status, 200);
+    assert!(metrics
+        .header("content-type")
+        .unwrap()
+        .starts_with("text/plain"));
+
+    let blocks = client.get("/blocks?node=none");
+    assert_eq!(blocks.status, 200);
+    assert_eq!(blocks.header("content-type"), Some("application/json"));
+    assert_eq!(blocks.body, b"[]");
+
+    assert_eq!(client.get("/health").status, 200);
+    assert_eq!(client.get("/").status, 404);
+    assert_eq!(client.get("/metrics/").status, 404);
+
+    client.send("POST /metrics HTTP/1.1\r\nHost: test\r\nContent-Length: 4\r\n\r\nbody");
+    let post = client.response(false);
+    assert_eq!(post.status, 405);
+    assert_eq!(post.header("allow"), Some("GET, HEAD"));
+
+    client.send("HEAD /health HTTP/1.1\r\nHost: test\r\n\r\n");
+    let head = client.response(true);
+    assert_eq!(head.status, 200);
+    assert_eq!(head.header("content-length"), Some("2"));
+
+    // All responses were sent on the same connection.
+    assert_eq!(head.header("connection"), Some("keep-alive"));
+}
+
+#[test]
+fn filters_blocks_by_encoded_node_names() {
+    let block = BlockConnected {
+        hash: [0; 32],
+        height: 800_000,
+        transactions: 1,
+        inputs: 1,
+        sigops: 1,
+        connection_time: 1,
+    };
+    blocks::RECENT_BLOCKS
+        .lock()
+        .unwrap()
+        .push(BlockRecord::new("signet #2", 0, &block));
+    let mut client = Client::connect(start());
+
+    for query in ["node=signet%20%232", "node=signet+%232"].iter() {
+        let response = client.get(&format!("/blocks?{}", query));
+        assert_eq!(response.status, 200);
+        let body = String::from_utf8(response.body).unwrap();
+        assert!(body.contains("\"height\":800000"), "{}: {}", query, body);
+    }
+    let response = client.get("/blocks?node=signet%2");
+    assert_eq!(response.status, 400);
+}
+
+#[test]
+fn decodes_query_components() {
+    let cases = [
+        ("mainnet", Some("mainnet")),
+        ("main+net", Some("main net")),
+        ("%5B2001%3Adb8%3A%3A1%5D%3A8333", Some("[2001:db8::1]:8333")),
+        ("a%2bb%2Cc", Some("a+b,c")),
+        ("%C3%A9", Some("\u{e9}")),
+        ("", Some("")),
+        ("%", None),
+        ("%2", None),
+        ("%zz", None),
+        ("%FF", None),
+    ];
+    for (component, decoded) in cases.iter() {
+        assert_eq!(
+            decode_query_component(component).as_deref(),
+            *decoded,
+            "{}",
+            component
+        );
+    }
+}
+
+#[test]
+fn parses_encoded_event_filters() {
+    let message = |node: &str, peer_id, peer_addr: &str| EventRecord {
+        node: node.to_string(),
+        timestamp_ms: 0,
+        event: Event::InboundMessage(Message {
+            peer_id,
+            peer_addr: peer_addr.to_string(),
+            connection_type: String::from("inbound"),
+            msg_type: String::from("tx"),
+            size: 250,
+        }),
+    };
+    let ipv6 = message("mainnet", 1, "[2001:db8::1]:8333");
+    let ipv4 = message("main net", 2, "1.1.1.1:8333");
+
+    let filter = EventFilter::from_query("peer=%5B2001%3Adb8%3A%3A1%5D%3A8333").unwrap();
+    assert!(filter.matches(&ipv6));
+    assert!(!filter.matches(&ipv4));
+    let filter = EventFilter::from_query("peer=1.1.1.1%3A8333%2C2").unwrap();
+    assert!(!filter.matches(&ipv6));
+    assert!(filter.matches(&ipv4));
+
+    for query in [
+        "node=main+net",
+        "node=main%20net",
+        "%6Eode=main%20net&kind=inbound_message",
+    ]
+    .iter()
+    {
+        let filter = EventFilter::from_query(query).unwrap();
+        assert!(filter.matches(&ipv4), "{}", query);
+        assert!(!filter.matches(&ipv6), "{}", query);
+    }
+
+    assert!(EventFilter::from_query("peer=%5B2001%3").is_err());
+    assert!(EventFilter::from_query("kind=block%5Fconnected").is_ok());
+    assert!(EventFilter::from_query("kind=block%20connected").is_err());
+
+    // Over HTTP, invalid encodings are rejected.
+    let mut client = Client::connect(start());
+    let response = client.get("/events?peer=%zz");
+    assert_eq!(response.status, 400);
+}
+
+#[test]
+fn keeps_connections_alive_as_requested() {
+    let address = start();
+
+    let mut client = Client::connect(address);
+    client.send("GET /health HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n");
+    assert_eq!(client.response(false).header("connection"), Some("close"));
+    assert!(client.is_closed());
+
+    let mut client = Client::connect(address);
+    client.send("GET /health HTTP/1.0\r\n\r\n");
+    assert_eq!(client.response(false).header("connection"), Some("close"));
+    assert!(client.is_closed());
+
+    let mut client = Client::connect(address);
+    client.send("GET /health HTTP/1.0\r\nConnection: keep-alive\r\n\r\n");
+    assert_eq!(
+        client.response(false).header("connection"),
+        Some("keep-alive")
+    );
+
+    // Pipelined requests are answered in order.
+    let mut client = Client::connect(address);
+    client
+        .send("GET /health HTTP/1.1\r\nHost: test\r\n\r\nGET /nope HTTP/1.1\r\nHost: test\r\n\r\n");
+    assert_eq!(client.response(false).status, 200);
+    assert_eq!(client.response(false).status, 404);
+}
+
+#[test]
+fn closes_idle_connections_for_waiting_clients() {
+    let address = start();
+
+    // Occupy every worker with an idle kept-alive connection.
+    let mut idle: Vec<Client> = (0..metricserver::WORKERS)
+        .map(|_| Client::connect(address))
+        .collect();
+    for client in idle.iter_mut() {
+        assert_eq!(client.get("/health").status, 200);
+    }
+
+    // A new connection is served once the idle ones are closed, well within
+    // the client's read timeout.
+    let mut client = Client::connect(address);
+    assert_eq!(client.get("/metrics").status, 200);
+    for client in idle.iter_mut() {
+        assert!(client.is_closed());
+    }
+}
+
+#[test]
+fn rejects_connections_when_busy() {
+    let address = start();
+
+    let mut idle: Vec<Client> = (0..metricserver::WORKERS)
+        .map(|_| Client::connect(address))
+        .collect();
+    for client in idle.iter_mut() {
+        assert_eq!(client.get("/health").status, 200);
+    }
+    let _queued: Vec<Client> = (0..metricserver::QUEUE_LENGTH)
+        .map(|_| Client::connect(address))
+        .collect();
+
+    // Further connections are answered right away, without a request.
+    for _ in 0..2 {
+        let mut client = Client::connect(address);
+        let response = client.response(false);
+        assert_eq!(response.status, 503);
+        assert_eq!(response.header("connection"), Some("close"));
+    }
+}
+
+#[test]
+fn rejects_invalid_requests() {
+    let address = start();
+    let cases = [
+        ("GET /health HTTP/1.1\r\n\r\n", 400),
+        ("GET /health\r\n\r\n", 400),
+        ("GET health HTTP/1.1\r\nHost: test\r\n\r\n", 400),
+        ("GET /health HTTP/2.0\r\nHost: test\r\n\r\n", 505),
+        ("GET /health HTTP/1.1\r\nHost : test\r\n\r\n", 400),
+        (
+            "POST /metrics HTTP/1.1\r\nHost: test\r\nTransfer-Encoding: chunked\r\n\r\n",
+            501,
+        ),
+    ];
+    for (request, status) in cases.iter() {
+        let mut client = Client::connect(address);
+        client.send(request);
+        let response = client.response(false);
+        assert_eq!(response.status, *status, "{:?}", request);
+        assert_eq!(response.header("connection"), Some("close"));
+        assert!(client.is_closed());
+    }
+
+    let mut client = Client::connect(address);
+    client.send(&format!(
+        "GET /health HTTP/1.1\r\nHost: test\r\nX-Large: {}\r\n\r\n",
+        "a".repeat(10_000)
+    ));
+    assert_eq!(client.response(false).status, 431);
+}
+
+#[test]
+fn reports_ready_with_an_attached_source() {
+    let mut client = Client::connect(start());
+    assert_eq!(client.get("/ready").status, 503);
+    metricserver::source_attached();
+    assert_eq!(client.get("/ready").status, 200);
+    metricserver::source_detached();
+    assert_eq!(client.get("/ready").status, 503);
+}
+
+#[test]
+fn formats_dates() {
+    assert_eq!(http_date(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+    assert_eq!(http_date(784_111_777), "Sun, 06 Nov 1994 08:49:37 GMT");
+    assert_eq!(http_date(951_825_600), "Tue, 29 Feb 2000 12:00:00 GMT");
+    assert_eq!(http_date(1_790_000_000), "Mon, 21 Sep 2026 14:13:20 GMT");
+}
+
+#[test]
+fn negotiates_the_metrics_format() {
+    let mut client = Client::connect(start());
+
+    let text = client.get("/metrics");
+    assert_eq!(
+        text.header("content-type"),
+        Some("text/plain; version=0.0.4")
+    );
+    assert_eq!(text.header("vary"), Some("Accept, Accept-Encoding"));
+
+    // As sent by Prometheus.
+    let openmetrics = client.get_with(
+        "/metrics",
+        "Accept: application/openmetrics-text;version=1.0.0,application/openmetrics-text;version=0.0.1;q=0.75,text/plain;version=0.0.4;q=0.5,*/*;q=0.1\r\n",
+    );
+    assert_eq!(
+        openmetrics.header("content-type"),
+        Some("application/openmetrics-text; version=1.0.0; charset=utf-8")
+    );
+    assert!(openmetrics.body.ends_with(b"# EOF\n"));
+
+    let old = client.get_with(
+        "/metrics",
+        "Accept: application/openmetrics-text; version=0.0.1, text/plain; q=0.5\r\n",
+    );
+    assert_eq!(
+        old.header("content-type"),
+        Some("application/openmetrics-text; version=0.0.1; charset=utf-8")
+    );
+
+    let preferred = client.get_with(
+        "/metrics",
+        "Accept: application/openmetrics-text; q=0.2, text/plain\r\n",
+    );
+    assert_eq!(
+        preferred.header("content-type"),
+        Some("text/plain; version=0.0.4")
+    );
+
+    let any = client.get_with("/metrics", "Accept: */*\r\n");
+    assert_eq!(
+        any.header("content-type"),
+        Some("text/plain; version=0.0.4")
+    );
+
+    assert_eq!(
+        client
+            .get_with("/metrics", "Accept: application/json\r\n")
+            .status,
+        406
+    );
+    assert_eq!(
+        client
+            .get_with(
+                "/metrics",
+                "Accept: text/plain; version=9.9.9, */*; q=0\r\n"
+            )
+            .status,
+        406
+    );
+}
+
+/// Returns the names of the series in a metrics response.
+fn series_names(body: &[u8]) -> BTreeSet<String> {
+    String::from_utf8_lossy(body)
+        .lines()
+        .filter(|l| !l.starts_with('#'))
+        .map(|l| l.split(['{', ' ']).next().unwrap().to_string())
+        .collect()
+}
+
+#[test]
+fn exposes_corresponding_series_in_both_formats() {
+    metrics::VALIDATION_BLOCK_CONNECTED_COUNT
+        .with_label_values(&["series-names"])
+        .inc();
+    metrics::RUNTIME_START_TIMESTAMP.get();
+    let mut client = Client::connect(start());
+
+    // Other tests may add series in between, but not remove them.
+    let openmetrics = series_names(
+        &client
+            .get_with("/metrics", "Accept: application/openmetrics-text\r\n")
+            .body,
+    );
+    let text = series_names(&client.get("/metrics").body);
+    // Counter samples get a `_total` suffix in OpenMetrics if they don't
+    // have one yet.
+    let added: Vec<_> = openmetrics
+        .iter()
+        .filter(|name| !name.ends_with("_created"))
+        .filter(|name| {
+            !text.contains(*name)
+                && !name
+                    .strip_suffix("_total")
+                    .is_some_and(|name| text.contains(name))
+        })
+        .collect();
+    assert!(added.is_empty(), "{:?}", added);
+    assert!(openmetrics.contains("bitcoindobserver_validation_block_connected_count_total"));
+    assert!(openmetrics.contains("bitcoindobserver_validation_block_connected_count_created"));
+    assert!(text.contains("bitcoindobserver_validation_block_connected_count"));
+    assert!(openmetrics.contains("bitcoindobserver_runtime_start_timestamp"));
+}
+
+#[test]
+fn compresses_with_gzip() {
+    // Registers the metric, which is otherwise registered on its first use.
+    metrics::RUNTIME_START_TIMESTAMP.get();
+    let mut client = Client::connect(start());
+
+    let plain = client.get("/metrics");
+    assert_eq!(plain.header("content-encoding"), None);
+
+    let compressed = client.get_with("/metrics", "Accept-Encoding: deflate, gzip\r\n");
+    assert_eq!(compressed.header("content-encoding"), Some("gzip"));
+    let mut body = String::new();
+    GzDecoder::new(&compressed.body[..])
+        .read_to_string(&mut body)
+        .unwrap();
+    assert!(body.contains("# TYPE bitcoindobserver_runtime_start_timestamp gauge"));
+
+    let blocks = client.get_with("/blocks", "Accept-Encoding: *\r\n");
+    assert_eq!(blocks.header("content-encoding"), Some("gzip"));
+
+    let refused = client.get_with("/metrics", "Accept-Encoding: gzip;q=0, identity\r\n");
+    assert_eq!(refused.header("content-encoding"), None);
+}
+
+#[test]
+fn parses_quality_values() {
+    let values = quality_values("text/plain;version=0.0.4;q=0.5, */* ; q=0.1,,gzip;q=x");
+    assert_eq!(values.len(), 3);
+    assert_eq!(values[0].value, "text/plain");
+    assert_eq!(values[0].param("Version"), Some("0.0.4"));
+    assert_eq!(values[0].quality, 0.5);
+    assert_eq!(values[1].value, "*/*");
+    assert_eq!(values[1].quality, 0.1);
+    assert_eq!(values[2].quality, 1.0);
+}
@@ -0,0 +1,260 @@
+//! Pins the layout of the event structs in types.rs to the C structs in
+//! ebpf-programs/, which the eBPF programs submit to the perf buffers. The C
+//! layout is computed from the struct definitions with the usual alignment
+//! rules of the BPF target (naturally aligned fields, size padded to the
+//! largest alignment).
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::mem;
+
+use bitcoind_observer::types::{
+    BlockConnected, ClosedConnection, DecodeError, MempoolAdded, MempoolRejected, MempoolRemoved,
+    MempoolReplaced, MisbehavingConnection, NewConnection, P2PMessage, UTXOCacheEvents,
+    UTXOCacheFlush,
+};
+
+/// The eBPF programs, concatenated like when they are loaded.
+const EBPF_PROGRAMS: &str = concat!(
+    include_str!("../ebpf-programs/p2p_in_and_outbound.c"),
+    include_str!("../ebpf-programs/p2p_connections.c"),
+    include_str!("../ebpf-programs/validation_block_connected.c"),
+    include_str!("../ebpf-programs/utxo_set_cache_changes.c"),
+    include_str!("../ebpf-programs/utxo_set_cache_flushes.c"),
+    include_str!("../ebpf-programs/mempool.c"),
+);
+
+/// Field names with their offsets, and the size of a struct.
+#[derive(Debug, PartialEq)]
+struct Layout {
+    fields: Vec<(String, usize)>,
+    size: usize,
+}
+
+/// Evaluates a #define value, which is a number or a sum of numbers.
+fn eval(value: &str, defines: &HashMap<String, String>) -> usize {
+    value
+        .split('+')
+        .map(|term| {
+            let term = term.trim();
+            match defines.get(term) {
+                Some(define) => eval(define, defines),
+                None => term
+                    .parse()
+                    .unwrap_or_else(|_| panic!("can't evaluate '{}'", term)),
+            }
+        })
+        .sum()
+}
+
+fn c_type_size(c_type: &str) -> usize {
+    match c_type {
+        "u8" | "s8" | "char" | "bool" => 1,
+        "u16" | "s16" => 2,
+        "u32" | "s32" | "int32_t" | "uint32_t" | "int" => 4,
+        "u64" | "s64" | "int64_t" | "uint64_t" => 8,
+        _ => panic!("unknown C type '{}'", c_type),
+    }
+}
+
+fn align(offset: usize, alignment: usize) -> usize {
+    offset.div_ceil(alignment) * alignment
+}
+
+/// Computes the layout of a C struct in the eBPF programs.
+fn c_layout(name: &str) -> Layout {
+    let mut defines = HashMap::new();
+    for line in EBPF_PROGRAMS.lines() {
+        if let Some(define) = line.trim().strip_prefix("#define ") {
+            let (key, value) = define.split_once(char::is_whitespace).unwrap();
+            defines.insert(key.to_string(), value.trim().to_string());
+        }
+    }
+
+    let start = EBPF_PROGRAMS
+        .find(&format!("struct {}", name))
+        .unwrap_or_else(|| panic!("no struct {} in the eBPF programs", name));
+    let body = &EBPF_PROGRAMS[start..];
+    let body = &body[body.find('{').unwrap() + 1..body.find("};").unwrap()];
+
+    let mut fields = vec![];
+    let mut offset = 0;
+    let mut max_alignment = 1;
+    for field in body.split(';').map(str::trim).filter(|f| !f.is_empty()) {
+        let mut parts = field.split_whitespace();
+        let c_type = parts.next().unwrap();
+        let declarator: String = parts.collect();
+        let (field_name, count) = match declarator.split_once('[') {
+            Some((field_name, length)) => (
+                field_name.to_string(),
+                eval(length.trim_end_matches(']'), &defines),
+            ),
+            None => (declarator, 1),
+        };
+        let alignment = c_type_size(c_type);
+        max_alignment = max_alignment.max(alignment);
+        offset = align(offset, alignment);
+        fields.push((field_name, offset));
+        offset += alignment * count;
+    }
+    Layout {
+        fields,
+        size: align(offset, max_alignment),
+    }
+}
+
+/// The layout of a Rust event struct with the given fields.
+macro_rules! rust_layout {
+    ($event:ty, $($field:ident),+) => {
+        Layout {
+            fields: vec![$((stringify!($field).to_string(), mem::offset_of!($event, $field))),+],
+            size: mem::size_of::<$event>(),
+        }
+    };
+}
+
+#[test]
+fn p2p_layouts_match_c_structs() {
+    assert_eq!(
+        rust_layout!(
+            P2PMessage,
+            peer_id,
+            peer_addr,
+            peer_conn_type,
+            msg_type,
+            msg_size
+        ),
+        c_layout("p2p_message")
+    );
+    assert_eq!(
+        rust_layout!(NewConnection, id, addr, conn_type, network, existing),
+        c_layout("new_connection")
+    );
+    assert_eq!(
+        rust_layout!(
+            ClosedConnection,
+            id,
+            addr,
+            conn_type,
+            network,
+            time_established
+        ),
+        c_layout("closed_connection")
+    );
+    assert_eq!(
+        rust_layout!(MisbehavingConnection, id, message),
+        c_layout("misbehaving_connection")
+    );
+}
+
+#[test]
+fn validation_and_utxocache_layouts_match_c_structs() {
+    assert_eq!(
+        rust_layout!(
+            BlockConnected,
+            hash,
+            height,
+            transactions,
+            inputs,
+            sigops,
+            connection_time
+        ),
+        c_layout("block_connected")
+    );
+    assert_eq!(
+        rust_layout!(
+            UTXOCacheFlush,
+            duration,
+            mode,
+            coins_count,
+            coins_memusage,
+            flush_for_prune
+        ),
+        c_layout("utxo_cache_flush")
+    );
+}
+
+#[test]
+fn mempool_layouts_match_c_structs() {
+    assert_eq!(
+        rust_layout!(MempoolAdded, txid, vsize, fee),
+        c_layout("mempool_added")
+    );
+    assert_eq!(
+        rust_layout!(MempoolRemoved, txid, reason, vsize, fee, entry_time),
+        c_layout("mempool_removed")
+    );
+    assert_eq!(
+        rust_layout!(
+            MempoolReplaced,
+            replaced_txid,
+            replaced_vsize,
+            replaced_fee,
+            replaced_entry_time,
+            replacement_txid,
+            replacement_vsize,
+            replacement_fee
+        ),
+        c_layout("mempool_replaced")
+    );
+    assert_eq!(
+        rust_layout!(MempoolRejected, txid, reason),
+        c_layout("mempool_rejected")
+    );
+}
+
+#[test]
+fn decoding_checks_the_size() {
+    let size = mem::size_of::<BlockConnected>();
+
+    let short = vec![0u8; size - 1];
+    assert_eq!(
+        BlockConnected::try_from(&short[..]).err(),
+        Some(DecodeError::Size {
+            event: "BlockConnected",
+            expected: size,
+            actual: size - 1,
+        })
+    );
+
+    // Events in perf buffers are padded to 8 bytes.
+    let padded = vec![0u8; size + 4];
+    assert!(BlockConnected::try_from(&padded[..]).is_ok());
+
+    let long = vec![0u8; size + 8];
+    assert!(BlockConnected::try_from(&long[..]).is_err());
+
+    assert!(P2PMessage::try_from(&[][..]).is_err());
+}
+
+#[test]
+fn decoding_reads_the_fields() {
+    let mut raw = vec![0u8; mem::size_of::<MempoolAdded>()];
+    raw[0] = 0xaa;
+    raw[32..36].copy_from_slice(&141i32.to_ne_bytes());
+    raw[40..48].copy_from_slice(&(-1i64).to_ne_bytes());
+    let added = MempoolAdded::try_from(&raw[..]).unwrap();
+    assert_eq!(added.txid[0], 0xaa);
+    assert_eq!(added.vsize, 141);
+    assert_eq!(added.fee, -1);
+
+    // Any byte is accepted for the C bool.
+    let mut raw = vec![0u8; mem::size_of::<UTXOCacheFlush>()];
+    raw[32] = 0xff;
+    let flush = UTXOCacheFlush::try_from(&raw[..]).unwrap();
+    assert_eq!(flush.flush_for_prune(), "true");
+}
+
+#[test]
+fn utxocache_events_round_trip() {
+    let events = UTXOCacheEvents {
+        add: 1,
+        spent: u64::MAX,
+        uncache: 3,
+    };
+    assert_eq!(
+        UTXOCacheEvents::try_from(&events.to_bytes()[..]),
+        Ok(events)
+    );
+    assert!(UTXOCacheEvents::try_from(&events.to_bytes()[..16]).is_err());
+}
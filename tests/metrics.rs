@@ -0,0 +1,426 @@
+//! Integration tests feeding events through the event sources and handlers
+//! and asserting on the resulting Prometheus output. Each test uses its own
+//! node name, as the metrics are global.
+
+use std::env;
+use std::fs;
+use std::time::Duration;
+
+use bitcoind_observer::handler::EventHandlers;
+use bitcoind_observer::message_sizes::MessageSizeHistograms;
+use bitcoind_observer::recording::{self, Replay, ReplayError};
+use bitcoind_observer::source::{EventSource, MemorySource, TracedEvent};
+use bitcoind_observer::types::{
+    BlockConnected, ClosedConnection, MempoolAdded, NewConnection, P2PMessage, UTXOCacheEvents,
+    NETWORK_IPV4,
+};
+use prometheus::{Encoder, HistogramOpts, Registry, TextEncoder};
+
+const TIMEOUT: Duration = Duration::from_millis(100);
+
+fn gather() -> String {
+    let mut buffer = vec![];
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buffer)
+        .unwrap();
+    String::from_utf8(buffer).unwrap()
+}
+
+fn assert_metric(output: &str, metric: &str) {
+    assert!(
+        output.lines().any(|line| line == metric),
+        "metric '{}' not found in:\n{}",
+        metric,
+        output
+    );
+}
+
+fn c_chars<const N: usize>(s: &str) -> [u8; N] {
+    let mut chars = [0u8; N];
+    chars[..s.len()].copy_from_slice(s.as_bytes());
+    chars
+}
+
+fn drain<S: EventSource>(source: &mut S, handlers: &mut EventHandlers) {
+    while source
+        .poll(TIMEOUT, &mut |node, event| handlers.handle(node, event))
+        .unwrap()
+    {}
+}
+
+/// A BlockConnected event as submitted by the eBPF program, laid out like
+/// the C struct.
+fn raw_block_connected(height: i32, transactions: u64, inputs: i32, sigops: u64) -> Vec<u8> {
+    let mut raw = vec![0xab; 32];
+    raw.extend_from_slice(&height.to_ne_bytes());
+    raw.extend_from_slice(&[0; 4]);
+    raw.extend_from_slice(&transactions.to_ne_bytes());
+    raw.extend_from_slice(&inputs.to_ne_bytes());
+    raw.extend_from_slice(&[0; 4]);
+    raw.extend_from_slice(&sigops.to_ne_bytes());
+    raw.extend_from_slice(&1_500_000u64.to_ne_bytes());
+    raw
+}
+
+fn hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[test]
+fn memory_source_updates_metrics() {
+    let node = "memory";
+    let mut source = MemorySource::new();
+    source.push(
+        node,
+        TracedEvent::InboundConnection(NewConnection {
+            id: 7,
+            addr: c_chars("1.1.1.1:8333"),
+            conn_type: c_chars("inbound"),
+            network: NETWORK_IPV4,
+            existing: 0,
+        }),
+    );
+    for _ in 0..3 {
+        source.push(
+            node,
+            TracedEvent::InboundMessage(P2PMessage {
+                peer_id: 7,
+                peer_addr: c_chars("1.1.1.1:8333"),
+                peer_conn_type: c_chars("inbound"),
+                msg_type: c_chars("tx"),
+                msg_size: 250,
+            }),
+        );
+    }
+    source.push(
+        node,
+        TracedEvent::ClosedConnection(ClosedConnection {
+            id: 7,
+            addr: c_chars("1.1.1.1:8333"),
+            conn_type: c_chars("inbound"),
+            network: NETWORK_IPV4,
+            time_established: 0,
+        }),
+    );
+    source.push(
+        node,
+        TracedEvent::BlockConnected(BlockConnected {
+            hash: [0; 32],
+            height: 700_000,
+            transactions: 2_000,
+            inputs: 5_000,
+            sigops: 10_000,
+            connection_time: 250_000,
+        }),
+    );
+    source.push(
+        node,
+        TracedEvent::MempoolAdded(MempoolAdded {
+            txid: [0; 32],
+            vsize: 141,
+            fee: 1_410,
+        }),
+    );
+    source.push(
+        node,
+        TracedEvent::UTXOCacheEvents(UTXOCacheEvents {
+            add: 10,
+            spent: 20,
+            uncache: 30,
+        }),
+    );
+
+    let mut handlers = EventHandlers::new(0);
+    drain(&mut source, &mut handlers);
+
+    let output = gather();
+    assert_metric(
+        &output,
+        "bitcoindobserver_p2p_message_inbound_count{connection_type=\"inbound\",msg_type=\"tx\",network=\"ipv4\",node=\"memory\"} 3",
+    );
+    assert_metric(
+        &output,
+        "bitcoindobserver_p2p_message_inbound_bytes{connection_type=\"inbound\",msg_type=\"tx\",network=\"ipv4\",node=\"memory\"} 750",
+    );
+    assert_metric(
+        &output,
+        "bitcoindobserver_p2p_message_inbound_size_bytes_bucket{msg_type=\"tx\",node=\"memory\",le=\"256\"} 3",
+    );
+    assert_metric(
+        &output,
+        "bitcoindobserver_p2p_connections_opened{connection_type=\"inbound\",network=\"ipv4\",node=\"memory\"} 1",
+    );
+    assert_metric(
+        &output,
+        "bitcoindobserver_p2p_connections_open_since_attach{connection_type=\"inbound\",network=\"ipv4\",node=\"memory\"} 0",
+    );
+    assert_metric(
+        &output,
+        "bitcoindobserver_validation_block_connected_height_last{node=\"memory\"} 700000",
+    );
+    assert_metric(
+        &output,
+        "bitcoindobserver_validation_block_connected_transaction_count{node=\"memory\"} 2000",
+    );
+    assert_metric(&output, "bitcoindobserver_mempool_added{node=\"memory\"} 1");
+    assert_metric(
+        &output,
+        "bitcoindobserver_mempool_added_fee{node=\"memory\"} 1410",
+    );
+    assert_metric(
+        &output,
+        "bitcoindobserver_utxocache_add{node=\"memory\"} 10",
+    );
+    assert_metric(
+        &output,
+        "bitcoindobserver_utxocache_uncache{node=\"memory\"} 30",
+    );
+}
+
+#[test]
+fn message_size_histograms_are_merged_into_one_family() {
+    let histograms =
+        MessageSizeHistograms::new(HistogramOpts::new("test_message_size_bytes", "Sizes."))
+            .unwrap();
+    let registry = Registry::new();
+    registry.register(Box::new(histograms.clone())).unwrap();
+    for node in ["a", "b"].iter() {
+        histograms.observe(node, "ping", 8);
+        histograms.observe(node, "inv", 1_000);
+        histograms.observe(node, "tx", 250);
+        histograms.observe(node, "block", 1_500_000);
+        histograms.observe(node, "unknown", 20);
+    }
+    histograms.observe("a", "tx", 100_000);
+
+    let families = registry.gather();
+    assert_eq!(families.len(), 1);
+    let family = &families[0];
+    assert_eq!(family.get_name(), "test_message_size_bytes");
+    assert_eq!(
+        family.get_field_type(),
+        prometheus::proto::MetricType::HISTOGRAM
+    );
+    assert_eq!(family.get_metric().len(), 10);
+
+    let powers = |start: f64, factor: f64, count: i32| -> Vec<f64> {
+        (0..count).map(|i| start * factor.powi(i)).collect()
+    };
+    for metric in family.get_metric() {
+        let label = |name: &str| {
+            metric
+                .get_label()
+                .iter()
+                .find(|l| l.get_name() == name)
+                .unwrap()
+                .get_value()
+        };
+        let expected = match label("msg_type") {
+            "ping" => vec![0.0, 8.0, 16.0, 32.0, 64.0, 128.0, 256.0, 512.0],
+            "inv" => powers(64.0, 4.0, 9),
+            "tx" => powers(128.0, 2.0, 13),
+            "block" => powers(1024.0, 2.0, 13),
+            "unknown" => powers(16.0, 4.0, 10),
+            msg_type => panic!("unexpected msg_type {}", msg_type),
+        };
+        let histogram = metric.get_histogram();
+        let bounds: Vec<f64> = histogram
+            .get_bucket()
+            .iter()
+            .map(|b| b.get_upper_bound())
+            .collect();
+        assert_eq!(bounds, expected, "{}", label("msg_type"));
+        let expected_count = match (label("node"), label("msg_type")) {
+            ("a", "tx") => 2,
+            _ => 1,
+        };
+        assert_eq!(histogram.get_sample_count(), expected_count);
+        assert_eq!(
+            histogram
+                .get_bucket()
+                .last()
+                .unwrap()
+                .get_cumulative_count(),
+            expected_count
+        );
+    }
+
+    // The text format has a single HELP and TYPE line for the family.
+    let mut buffer = vec![];
+    TextEncoder::new().encode(&families, &mut buffer).unwrap();
+    let output = String::from_utf8(buffer).unwrap();
+    assert_eq!(output.matches("# TYPE test_message_size_bytes ").count(), 1);
+    assert_metric(
+        &output,
+        "test_message_size_bytes_bucket{msg_type=\"ping\",node=\"b\",le=\"8\"} 1",
+    );
+    assert_metric(
+        &output,
+        "test_message_size_bytes_bucket{msg_type=\"tx\",node=\"a\",le=\"+Inf\"} 2",
+    );
+}
+
+#[test]
+fn connections_opened_before_attaching_are_not_counted_as_open() {
+    let node = "close-before-open";
+    let new_connection = |id, existing| NewConnection {
+        id,
+        addr: c_chars("1.1.1.1:8333"),
+        conn_type: c_chars("inbound"),
+        network: NETWORK_IPV4,
+        existing,
+    };
+    let closed_connection = |id| ClosedConnection {
+        id,
+        addr: c_chars("1.1.1.1:8333"),
+        conn_type: c_chars("inbound"),
+        network: NETWORK_IPV4,
+        time_established: 0,
+    };
+    let mut source = MemorySource::new();
+    // Connection 1 was opened before attaching.
+    source.push(node, TracedEvent::ClosedConnection(closed_connection(1)));
+    source.push(node, TracedEvent::InboundConnection(new_connection(2, 9)));
+    source.push(node, TracedEvent::InboundConnection(new_connection(3, 10)));
+    source.push(node, TracedEvent::ClosedConnection(closed_connection(2)));
+
+    let mut handlers = EventHandlers::new(0);
+    drain(&mut source, &mut handlers);
+
+    let output = gather();
+    // The number of open connections reported by bitcoind includes the
+    // connections opened before attaching.
+    assert_metric(
+        &output,
+        "bitcoindobserver_p2p_connections_open{direction=\"inbound\",node=\"close-before-open\"} 10",
+    );
+    assert!(!output.contains(
+        "bitcoindobserver_p2p_connections_open{direction=\"outbound\",node=\"close-before-open\"}"
+    ));
+    let labels = "connection_type=\"inbound\",network=\"ipv4\",node=\"close-before-open\"";
+    assert_metric(
+        &output,
+        &format!(
+            "bitcoindobserver_p2p_connections_open_since_attach{{{}}} 1",
+            labels
+        ),
+    );
+    assert_metric(
+        &output,
+        &format!("bitcoindobserver_p2p_connections_opened{{{}}} 2", labels),
+    );
+    assert_metric(
+        &output,
+        &format!("bitcoindobserver_p2p_connections_closed{{{}}} 2", labels),
+    );
+    assert_metric(
+        &output,
+        &format!(
+            "bitcoindobserver_p2p_connection_lifetime_seconds_count{{{}}} 1",
+            labels
+        ),
+    );
+}
+
+#[test]
+fn per_peer_metrics_are_opt_in() {
+    let mut source = MemorySource::new();
+    for node in ["peers-disabled", "peers-enabled"].iter() {
+        source.push(
+            node,
+            TracedEvent::OutboundMessage(P2PMessage {
+                peer_id: 3,
+                peer_addr: c_chars("[2606:4700:4700::1111]:8333"),
+                peer_conn_type: c_chars("outbound-full-relay"),
+                msg_type: c_chars("ping"),
+                msg_size: 8,
+            }),
+        );
+    }
+
+    let mut disabled = EventHandlers::new(0);
+    let mut enabled = EventHandlers::new(10);
+    source
+        .poll(TIMEOUT, &mut |node, event| match node {
+            "peers-enabled" => enabled.handle(node, event),
+            _ => disabled.handle(node, event),
+        })
+        .unwrap();
+
+    let output = gather();
+    assert_metric(
+        &output,
+        "bitcoindobserver_p2p_peer_message_outbound_count{addr=\"[2606:4700:4700::1111]:8333\",connection_type=\"outbound-full-relay\",network=\"ipv6\",node=\"peers-enabled\",peer_id=\"3\"} 1",
+    );
+    assert!(!output.contains("node=\"peers-disabled\",peer_id"));
+}
+
+#[test]
+fn replay_source_updates_metrics() {
+    let path = env::temp_dir().join(format!(
+        "bitcoind-observer-test-{}.ndjson",
+        std::process::id()
+    ));
+    let utxocache_events = UTXOCacheEvents {
+        add: 1,
+        spent: 2,
+        uncache: 3,
+    };
+    let recording = format!(
+        "{{\"format\":\"bitcoind-observer-recording\",\"version\":{}}}\n\
+         {{\"timestamp_ms\":1000,\"node\":\"replay\",\"buffer\":\"perf_block_connected\",\"data\":\"{}\"}}\n\
+         {{\"timestamp_ms\":2000,\"node\":\"replay\",\"buffer\":\"perf_block_connected\",\"data\":\"{}\"}}\n\
+         {{\"timestamp_ms\":2500,\"node\":\"replay\",\"buffer\":\"{}\",\"data\":\"{}\"}}\n\
+         {{\"timestamp_ms\":3000,\"node\":\"replay\",\"buffer\":\"unknown\",\"data\":\"00\"}}\n",
+        recording::VERSION,
+        hex(&raw_block_connected(100, 10, 20, 30)),
+        hex(&raw_block_connected(101, 5, 6, 7)),
+        recording::UTXOCACHE_COUNTERS,
+        hex(&utxocache_events.to_bytes()),
+    );
+    fs::write(&path, recording).unwrap();
+
+    let mut replay = Replay::open(&path, 0.0).unwrap();
+    let mut handlers = EventHandlers::new(0);
+    drain(&mut replay, &mut handlers);
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(replay.replayed(), 4);
+    let output = gather();
+    assert_metric(
+        &output,
+        "bitcoindobserver_validation_block_connected_height_last{node=\"replay\"} 101",
+    );
+    assert_metric(
+        &output,
+        "bitcoindobserver_validation_block_connected_count{node=\"replay\"} 2",
+    );
+    assert_metric(
+        &output,
+        "bitcoindobserver_validation_block_connected_sigops_count{node=\"replay\"} 37",
+    );
+    assert_metric(
+        &output,
+        "bitcoindobserver_utxocache_spent{node=\"replay\"} 2",
+    );
+    assert_metric(
+        &output,
+        "bitcoindobserver_runtime_decode_failures_total{buffer=\"unknown\",node=\"replay\"} 1",
+    );
+}
+
+#[test]
+fn replay_rejects_unsupported_versions() {
+    let path = env::temp_dir().join(format!(
+        "bitcoind-observer-test-version-{}.ndjson",
+        std::process::id()
+    ));
+    fs::write(
+        &path,
+        "{\"format\":\"bitcoind-observer-recording\",\"version\":999}\n",
+    )
+    .unwrap();
+    let result = Replay::open(&path, 1.0);
+    fs::remove_file(&path).unwrap();
+    assert!(matches!(result, Err(ReplayError::Version(999))));
+}
@@ -0,0 +1,157 @@
+//! Tests of the OpenMetrics encoding. The created timestamps are tracked
+//! across encodings, so everything is encoded in a single test.
+
+use prometheus::{
+    HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+};
+
+use bitcoind_observer::{metrics, openmetrics};
+
+fn assert_line(output: &str, line: &str) {
+    assert!(
+        output.lines().any(|l| l == line),
+        "line '{}' not found in:\n{}",
+        line,
+        output
+    );
+}
+
+/// Returns the value of the sample with the name and labels.
+fn sample(output: &str, series: &str) -> f64 {
+    output
+        .lines()
+        .find_map(|l| l.strip_prefix(series)?.strip_prefix(' '))
+        .unwrap_or_else(|| panic!("sample '{}' not found in:\n{}", series, output))
+        .parse()
+        .unwrap()
+}
+
+#[test]
+fn encodes_openmetrics() {
+    let registry = Registry::new();
+    let events = IntCounterVec::new(
+        Opts::new("test_events_total", "Number of \"events\"."),
+        &["node"],
+    )
+    .unwrap();
+    let messages = IntCounter::new("test_messages", "Number of messages.").unwrap();
+    let height = IntGauge::new("test_height", "Height.").unwrap();
+    let duration = HistogramVec::new(
+        HistogramOpts::new("test_duration_seconds", "Duration.").buckets(vec![0.5, 1.0, 2.0]),
+        &["node"],
+    )
+    .unwrap();
+    registry.register(Box::new(events.clone())).unwrap();
+    registry.register(Box::new(messages.clone())).unwrap();
+    registry.register(Box::new(height.clone())).unwrap();
+    registry.register(Box::new(duration.clone())).unwrap();
+
+    events.with_label_values(&["a"]).inc_by(3);
+    messages.inc();
+    height.set(700_000);
+    duration.with_label_values(&["a"]).observe(0.25);
+    openmetrics::observe_with_exemplar(
+        &duration.with_label_values(&["a"]),
+        1.5,
+        &[("block_hash", "00ab")],
+    );
+    openmetrics::observe_with_exemplar(
+        &duration.with_label_values(&["a"]),
+        3.0,
+        &[("block_hash", "00cd")],
+    );
+
+    let output = openmetrics::encode(&registry.gather());
+    assert_line(&output, "# TYPE test_events counter");
+    assert_line(&output, "# HELP test_events Number of \\\"events\\\".");
+    assert_line(&output, "test_events_total{node=\"a\"} 3");
+    assert_line(&output, "test_events_created{node=\"a\"} 0");
+    assert_line(&output, "# TYPE test_messages counter");
+    assert_line(&output, "test_messages_total 1");
+    assert_line(&output, "# TYPE test_height gauge");
+    assert_line(&output, "test_height 700000");
+    assert!(!output.contains("test_height_created"));
+    assert_line(&output, "# TYPE test_duration_seconds histogram");
+    assert_line(
+        &output,
+        "test_duration_seconds_bucket{node=\"a\",le=\"0.5\"} 1",
+    );
+    assert_line(
+        &output,
+        "test_duration_seconds_bucket{node=\"a\",le=\"1.0\"} 1",
+    );
+    assert_line(&output, "test_duration_seconds_count{node=\"a\"} 3");
+    assert_line(&output, "test_duration_seconds_sum{node=\"a\"} 4.75");
+    assert!(output.ends_with("\n# EOF\n"));
+
+    // The exemplars are attached to the buckets the values fall into.
+    let bucket = output
+        .lines()
+        .find(|l| l.starts_with("test_duration_seconds_bucket{node=\"a\",le=\"2.0\"}"))
+        .unwrap();
+    assert!(
+        bucket.starts_with(
+            "test_duration_seconds_bucket{node=\"a\",le=\"2.0\"} 2 # {block_hash=\"00ab\"} 1.5 "
+        ),
+        "{}",
+        bucket
+    );
+    let bucket = output
+        .lines()
+        .find(|l| l.starts_with("test_duration_seconds_bucket{node=\"a\",le=\"+Inf\"}"))
+        .unwrap();
+    assert!(
+        bucket.starts_with(
+            "test_duration_seconds_bucket{node=\"a\",le=\"+Inf\"} 3 # {block_hash=\"00cd\"} 3 "
+        ),
+        "{}",
+        bucket
+    );
+
+    // Series created later are created after the previous encoding.
+    events.with_label_values(&["b"]).inc();
+    let output = openmetrics::encode(&registry.gather());
+    assert_line(&output, "test_events_created{node=\"a\"} 0");
+    let created = sample(&output, "test_events_created{node=\"b\"}");
+    assert!(created > 0.0);
+
+    // Removed and recreated series get a new created timestamp and lose their
+    // exemplars.
+    duration.remove_label_values(&["a"]).unwrap();
+    openmetrics::encode(&registry.gather());
+    duration.with_label_values(&["a"]).observe(1.5);
+    let output = openmetrics::encode(&registry.gather());
+    assert!(sample(&output, "test_duration_seconds_created{node=\"a\"}") >= created);
+    assert_line(
+        &output,
+        "test_duration_seconds_bucket{node=\"a\",le=\"2.0\"} 1",
+    );
+
+    // The observer's counters without a `_total` suffix are counters with
+    // created timestamps as well.
+    metrics::P2P_MESSAGE_INBOUND_COUNT
+        .with_label_values(&["mainnet", "tx", "inbound", "ipv4"])
+        .inc();
+    let output = openmetrics::encode(&prometheus::gather());
+    let series = "{connection_type=\"inbound\",msg_type=\"tx\",network=\"ipv4\",node=\"mainnet\"}";
+    assert_line(
+        &output,
+        "# TYPE bitcoindobserver_p2p_message_inbound_count counter",
+    );
+    assert_eq!(
+        sample(
+            &output,
+            &format!("bitcoindobserver_p2p_message_inbound_count_total{}", series)
+        ),
+        1.0
+    );
+    assert!(
+        sample(
+            &output,
+            &format!(
+                "bitcoindobserver_p2p_message_inbound_count_created{}",
+                series
+            )
+        ) > 0.0
+    );
+}
@@ -0,0 +1,262 @@
+//! Tests of recording raw events and replaying them. Recording is global, so
+//! these tests live in their own test binary.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use bitcoind_observer::metrics;
+use bitcoind_observer::recording::{self, Replay, ReplayError};
+use bitcoind_observer::source::{EventSource, MemorySource, TracedEvent};
+use bitcoind_observer::types::UTXOCacheEvents;
+
+const TIMEOUT: Duration = Duration::from_millis(100);
+
+fn test_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!(
+        "bitcoind-observer-test-recording-{}-{}.ndjson",
+        name,
+        std::process::id()
+    ))
+}
+
+/// A BlockConnected event as submitted by the eBPF program.
+fn raw_block_connected(height: i32) -> Vec<u8> {
+    let mut raw = vec![0xab; 32];
+    raw.extend_from_slice(&height.to_ne_bytes());
+    raw.extend_from_slice(&[0; 4]);
+    raw.extend_from_slice(&2_000u64.to_ne_bytes());
+    raw.extend_from_slice(&5_000i32.to_ne_bytes());
+    raw.extend_from_slice(&[0; 4]);
+    raw.extend_from_slice(&10_000u64.to_ne_bytes());
+    raw.extend_from_slice(&250_000u64.to_ne_bytes());
+    raw
+}
+
+/// A P2PMessage event as submitted by the eBPF program.
+fn raw_p2p_message(peer_id: u64, msg_type: &str, size: u64) -> Vec<u8> {
+    let mut raw = peer_id.to_ne_bytes().to_vec();
+    let mut addr = [0u8; 68];
+    addr[..12].copy_from_slice(b"1.1.1.1:8333");
+    raw.extend_from_slice(&addr);
+    let mut conn_type = [0u8; 20];
+    conn_type[..7].copy_from_slice(b"inbound");
+    raw.extend_from_slice(&conn_type);
+    let mut msg = [0u8; 20];
+    msg[..msg_type.len()].copy_from_slice(msg_type.as_bytes());
+    raw.extend_from_slice(&msg);
+    raw.extend_from_slice(&[0; 4]);
+    raw.extend_from_slice(&size.to_ne_bytes());
+    raw
+}
+
+/// A MempoolAdded event as submitted by the eBPF program.
+fn raw_mempool_added(vsize: i32, fee: i64) -> Vec<u8> {
+    let mut raw = vec![0xcd; 32];
+    raw.extend_from_slice(&vsize.to_ne_bytes());
+    raw.extend_from_slice(&[0; 4]);
+    raw.extend_from_slice(&fee.to_ne_bytes());
+    raw
+}
+
+/// Describes an event passed to a handler, so handler calls can be compared.
+fn describe(node: &str, event: TracedEvent) -> String {
+    let event = match event {
+        TracedEvent::InboundMessage(msg) => format!("inbound {}", msg),
+        TracedEvent::OutboundMessage(msg) => format!("outbound {}", msg),
+        TracedEvent::BlockConnected(block) => block.to_string(),
+        TracedEvent::MempoolAdded(added) => added.to_string(),
+        TracedEvent::UTXOCacheEvents(events) => format!(
+            "utxocache add={} spent={} uncache={}",
+            events.add, events.spent, events.uncache
+        ),
+        _ => panic!("unexpected event"),
+    };
+    format!("{}: {}", node, event)
+}
+
+fn drain<S: EventSource>(source: &mut S) -> Vec<String> {
+    let mut calls = vec![];
+    while source
+        .poll(TIMEOUT, &mut |node, event| {
+            calls.push(describe(node, event))
+        })
+        .unwrap()
+    {}
+    calls
+}
+
+#[test]
+fn replays_recorded_events() {
+    let events = [
+        (
+            "mainnet",
+            "perf_block_connected",
+            raw_block_connected(800_000),
+        ),
+        ("mainnet", "inbound_messages", raw_p2p_message(7, "tx", 250)),
+        ("signet", "outbound_messages", raw_p2p_message(3, "ping", 8)),
+        (
+            "signet",
+            "perf_mempool_added",
+            raw_mempool_added(141, 1_410),
+        ),
+        (
+            "mainnet",
+            recording::UTXOCACHE_COUNTERS,
+            UTXOCacheEvents {
+                add: 1,
+                spent: 2,
+                uncache: 3,
+            }
+            .to_bytes(),
+        ),
+        (
+            "mainnet",
+            "perf_block_connected",
+            raw_block_connected(800_001),
+        ),
+    ];
+
+    let path = test_path("round-trip");
+    recording::start(&path).unwrap();
+    assert!(recording::is_active());
+    let mut source = MemorySource::new();
+    for (node, buffer, raw) in events.iter() {
+        recording::record(node, buffer, raw);
+        source.push(node, TracedEvent::decode(buffer, raw).unwrap());
+    }
+    recording::flush();
+    let expected = drain(&mut source);
+    assert_eq!(expected.len(), events.len());
+
+    let mut replay = Replay::open(&path, 0.0).unwrap();
+    let replayed = drain(&mut replay);
+    fs::remove_file(&path).unwrap();
+    assert_eq!(replayed, expected);
+    assert_eq!(replay.replayed(), events.len());
+}
+
+fn recording(entries: &[String]) -> String {
+    let mut recording = format!(
+        "{{\"format\":\"bitcoind-observer-recording\",\"version\":{}}}\n",
+        recording::VERSION
+    );
+    for entry in entries {
+        recording.push_str(entry);
+    }
+    recording
+}
+
+fn entry(node: &str, buffer: &str, data: &[u8]) -> String {
+    let hex: String = data.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{{\"timestamp_ms\":1000,\"node\":\"{}\",\"buffer\":\"{}\",\"data\":\"{}\"}}\n",
+        node, buffer, hex
+    )
+}
+
+/// Replays the recording and returns the handled events and the result of
+/// the replay.
+fn replay(name: &str, contents: &str) -> (Vec<String>, Result<(), ReplayError>) {
+    let path = test_path(name);
+    fs::write(&path, contents).unwrap();
+    let result = Replay::open(&path, 0.0);
+    let mut calls = vec![];
+    let result = result.and_then(|mut replay| loop {
+        match replay.poll(TIMEOUT, &mut |node, event| {
+            calls.push(describe(node, event))
+        }) {
+            Ok(true) => continue,
+            Ok(false) => break Ok(()),
+            Err(e) => break Err(e),
+        }
+    });
+    fs::remove_file(&path).unwrap();
+    (calls, result)
+}
+
+#[test]
+fn replays_truncated_recordings() {
+    let first = entry("truncated", "perf_block_connected", &raw_block_connected(1));
+    let second = entry("truncated", "perf_block_connected", &raw_block_connected(2));
+
+    // Cut off in the middle of the last line.
+    let contents = recording(&[first.clone(), second.clone()]);
+    let (calls, result) = replay("truncated-line", &contents[..contents.len() - 20]);
+    assert!(result.is_ok());
+    assert_eq!(calls.len(), 1);
+    assert!(calls[0].contains("height=1 "), "{}", calls[0]);
+
+    // Cut off right after a complete line, or with an empty last line.
+    let (calls, result) = replay(
+        "complete",
+        &recording(&[first.clone(), second, "\n".into()]),
+    );
+    assert!(result.is_ok());
+    assert_eq!(calls.len(), 2);
+
+    // Cut off in the middle of the header.
+    let contents = recording(&[]);
+    let (_, result) = replay("truncated-header", &contents[..10]);
+    assert!(matches!(result, Err(ReplayError::Parse(1, _))));
+    let (_, result) = replay("empty", "");
+    assert!(matches!(result, Err(ReplayError::Parse(1, _))));
+
+    // A truncated raw event is counted as a decode failure and skipped.
+    let raw = raw_block_connected(3);
+    let short = entry("truncated", "perf_block_connected", &raw[..raw.len() - 8]);
+    let (calls, result) = replay("truncated-event", &recording(&[short, first]));
+    assert!(result.is_ok());
+    assert_eq!(calls.len(), 1);
+    assert_eq!(
+        metrics::RUNTIME_DECODE_FAILURES
+            .with_label_values(&["truncated", "perf_block_connected"])
+            .get(),
+        1
+    );
+}
+
+#[test]
+fn rejects_corrupt_recordings() {
+    let valid = entry("corrupt", "perf_block_connected", &raw_block_connected(1));
+    let cases = [
+        ("json", String::from("{\"timestamp_ms\":1000,\"node\":\n"), 3),
+        (
+            "fields",
+            String::from("{\"timestamp_ms\":1000,\"node\":\"corrupt\"}\n"),
+            3,
+        ),
+        (
+            "hex",
+            String::from(
+                "{\"timestamp_ms\":1000,\"node\":\"corrupt\",\"buffer\":\"perf_block_connected\",\"data\":\"0g\"}\n",
+            ),
+            3,
+        ),
+        (
+            "odd-hex",
+            String::from(
+                "{\"timestamp_ms\":1000,\"node\":\"corrupt\",\"buffer\":\"perf_block_connected\",\"data\":\"abc\"}\n",
+            ),
+            3,
+        ),
+        ("binary", String::from("\u{1}\u{2}garbage\n"), 3),
+    ];
+    for (name, line, line_number) in cases.iter() {
+        let contents = recording(&[valid.clone(), line.clone(), valid.clone()]);
+        let (calls, result) = replay(name, &contents);
+        // The events before the corrupt line are replayed.
+        assert_eq!(calls.len(), 1, "{}", name);
+        match result {
+            Err(ReplayError::Parse(line, _)) => assert_eq!(line, *line_number, "{}", name),
+            result => panic!("{}: unexpected result {:?}", name, result),
+        }
+    }
+
+    let (_, result) = replay("format", "{\"format\":\"something-else\",\"version\":1}\n");
+    assert!(matches!(result, Err(ReplayError::Parse(1, _))));
+    let (_, result) = replay("header", "not json\n");
+    assert!(matches!(result, Err(ReplayError::Parse(1, _))));
+}
@@ -0,0 +1,368 @@
+//! Tests of the output sinks: the encoding of metrics and events as InfluxDB
+//! line protocol and StatsD, and sending them over UDP and HTTP.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use prometheus::{HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry};
+
+use bitcoind_observer::config::SinkConfig;
+use bitcoind_observer::events::{self, Block, Event, EventRecord, Message};
+use bitcoind_observer::metrics;
+use bitcoind_observer::sinks::{
+    Destination, FieldValue, Format, HttpTransport, InfluxLineProtocol, Point, Protocol, Sink,
+    SinkError, Statsd, Transport, UdpTransport,
+};
+
+fn block(height: i32) -> Event {
+    Event::BlockConnected(Block {
+        hash: String::from("00000000000000000002a7c4c1e48d76c5a37902165a270156b7a8d72728a054"),
+        height,
+        transactions: 2_500,
+        inputs: 6_000,
+        sigops: 9_000,
+        connection_time: 150_000,
+    })
+}
+
+fn block_point() -> Point {
+    let record = EventRecord {
+        node: String::from("main net"),
+        timestamp_ms: 1_700_000_000_123,
+        event: block(800_000),
+    };
+    Point::from_event(&serde_json::to_string(&record).unwrap()).unwrap()
+}
+
+struct TestMetrics {
+    registry: Registry,
+    messages: IntCounterVec,
+    balance: IntGauge,
+    duration: HistogramVec,
+}
+
+fn test_metrics() -> TestMetrics {
+    let registry = Registry::new();
+    let messages = IntCounterVec::new(
+        Opts::new("test_messages_total", "Messages."),
+        &["msg_type", "addr"],
+    )
+    .unwrap();
+    let balance = IntGauge::new("test_balance", "Balance.").unwrap();
+    let duration = HistogramVec::new(
+        HistogramOpts::new("test_duration_seconds", "Duration.").buckets(vec![0.5, 1.0]),
+        &["node"],
+    )
+    .unwrap();
+    registry.register(Box::new(messages.clone())).unwrap();
+    registry.register(Box::new(balance.clone())).unwrap();
+    registry.register(Box::new(duration.clone())).unwrap();
+    TestMetrics {
+        registry,
+        messages,
+        balance,
+        duration,
+    }
+}
+
+#[test]
+fn converts_events_to_points() {
+    let point = block_point();
+    assert_eq!(point.measurement, "bitcoindobserver_block_connected");
+    assert_eq!(point.timestamp_ms, 1_700_000_000_123);
+    assert_eq!(
+        point.tags,
+        vec![(String::from("node"), String::from("main net"))]
+    );
+    assert!(point
+        .fields
+        .contains(&(String::from("height"), FieldValue::Integer(800_000))));
+    assert!(point.fields.contains(&(
+        String::from("hash"),
+        FieldValue::String(String::from(
+            "00000000000000000002a7c4c1e48d76c5a37902165a270156b7a8d72728a054"
+        ))
+    )));
+
+    let json = r#"{"node":"n","timestamp_ms":1,"kind":"utxocache_flush","duration":5,"mode":"PERIODIC","coins_count":2,"coins_memusage":3,"for_prune":false}"#;
+    let point = Point::from_event(json).unwrap();
+    assert_eq!(
+        point.tags,
+        vec![
+            (String::from("for_prune"), String::from("false")),
+            (String::from("mode"), String::from("PERIODIC")),
+            (String::from("node"), String::from("n")),
+        ]
+    );
+    assert_eq!(point.fields.len(), 3);
+
+    assert!(Point::from_event("{\"node\":\"n\",\"timestamp_ms\":1}").is_none());
+    assert!(Point::from_event("[]").is_none());
+}
+
+#[test]
+fn encodes_influx_line_protocol() {
+    let test = test_metrics();
+    test.messages
+        .with_label_values(&["tx", "[::1]:8333"])
+        .inc_by(3);
+    test.balance.set(-5);
+    test.duration.with_label_values(&["a,b"]).observe(0.75);
+
+    let lines = InfluxLineProtocol.metrics(&test.registry.gather(), 1_700_000_000_000);
+    assert_eq!(
+        lines,
+        vec![
+            "test_balance value=-5 1700000000000000000",
+            "test_duration_seconds,node=a\\,b count=1i,sum=0.75,0.5=0i,1=1i,+Inf=1i 1700000000000000000",
+            "test_messages_total,addr=[::1]:8333,msg_type=tx value=3 1700000000000000000",
+        ]
+    );
+
+    let lines = InfluxLineProtocol.event(&block_point());
+    assert_eq!(
+        lines,
+        vec![
+            "bitcoindobserver_block_connected,node=main\\ net connection_time=150000i,\
+             hash=\"00000000000000000002a7c4c1e48d76c5a37902165a270156b7a8d72728a054\",\
+             height=800000i,inputs=6000i,sigops=9000i,transactions=2500i 1700000000123000000"
+        ]
+    );
+
+    // Strings are quoted and escaped, points without representable fields
+    // are skipped.
+    let point = Point {
+        measurement: String::from("test event"),
+        tags: vec![(String::from("empty"), String::new())],
+        fields: vec![
+            (String::from("nan"), FieldValue::Float(f64::NAN)),
+            (
+                String::from("message"),
+                FieldValue::String(String::from("a \"b\" \\c")),
+            ),
+            (String::from("ok"), FieldValue::Boolean(true)),
+        ],
+        timestamp_ms: 1,
+    };
+    assert_eq!(
+        InfluxLineProtocol.event(&point),
+        vec!["test\\ event message=\"a \\\"b\\\" \\\\c\",ok=true 1000000"]
+    );
+    let point = Point {
+        fields: vec![(String::from("nan"), FieldValue::Float(f64::NAN))],
+        ..point
+    };
+    assert!(InfluxLineProtocol.event(&point).is_empty());
+}
+
+#[test]
+fn encodes_statsd() {
+    let test = test_metrics();
+    let mut statsd = Statsd::new();
+    let counter = test.messages.with_label_values(&["tx", "1.2.3.4:8333"]);
+    counter.inc_by(3);
+    test.balance.set(-5);
+    test.duration.with_label_values(&["a"]).observe(0.75);
+
+    let lines = statsd.metrics(&test.registry.gather(), 0);
+    assert_eq!(
+        lines,
+        vec![
+            "test_balance:0|g",
+            "test_balance:-5|g",
+            "test_duration_seconds_count:1|c|#node:a",
+            "test_duration_seconds_sum:0.75|c|#node:a",
+            "test_messages_total:3|c|#addr:1.2.3.4_8333,msg_type:tx",
+        ]
+    );
+
+    // Counters are sent as their increase, unchanged counters not at all.
+    counter.inc_by(2);
+    test.balance.set(7);
+    let lines = statsd.metrics(&test.registry.gather(), 0);
+    assert_eq!(
+        lines,
+        vec![
+            "test_balance:7|g",
+            "test_messages_total:2|c|#addr:1.2.3.4_8333,msg_type:tx",
+        ]
+    );
+
+    // Recreated counters start from 0.
+    test.messages
+        .remove_label_values(&["tx", "1.2.3.4:8333"])
+        .unwrap();
+    statsd.metrics(&test.registry.gather(), 0);
+    test.messages
+        .with_label_values(&["tx", "1.2.3.4:8333"])
+        .inc();
+    let lines = statsd.metrics(&test.registry.gather(), 0);
+    assert!(lines.contains(&String::from(
+        "test_messages_total:1|c|#addr:1.2.3.4_8333,msg_type:tx"
+    )));
+
+    let lines = statsd.event(&block_point());
+    assert_eq!(
+        lines,
+        vec![
+            "bitcoindobserver_block_connected:1|c|#node:main net",
+            "bitcoindobserver_block_connected_connection_time:150000|h|#node:main net",
+            "bitcoindobserver_block_connected_inputs:6000|h|#node:main net",
+            "bitcoindobserver_block_connected_sigops:9000|h|#node:main net",
+            "bitcoindobserver_block_connected_transactions:2500|h|#node:main net",
+        ]
+    );
+
+    // Identifiers aren't sent as samples.
+    let record = EventRecord {
+        node: String::from("mainnet"),
+        timestamp_ms: 1_700_000_000_123,
+        event: Event::InboundMessage(Message {
+            peer_id: 7,
+            peer_addr: String::from("1.2.3.4:8333"),
+            connection_type: String::from("inbound"),
+            msg_type: String::from("tx"),
+            size: 250,
+        }),
+    };
+    let point = Point::from_event(&serde_json::to_string(&record).unwrap()).unwrap();
+    assert!(point
+        .fields
+        .contains(&(String::from("peer_id"), FieldValue::Integer(7))));
+    let lines = statsd.event(&point);
+    assert!(lines.iter().all(|l| !l.contains("peer_id")), "{:?}", lines);
+    assert!(
+        lines
+            .iter()
+            .any(|l| l.starts_with("bitcoindobserver_inbound_message_size:250|h")),
+        "{:?}",
+        lines
+    );
+}
+
+#[test]
+fn packs_lines_into_datagrams() {
+    let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+    receiver
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let address = receiver.local_addr().unwrap().to_string();
+    let mut transport = UdpTransport::new(&address).unwrap();
+
+    let lines: Vec<String> = (0..100).map(|i| format!("line{:04}", i)).collect();
+    transport.send(&lines).unwrap();
+    let mut received = vec![];
+    let mut buffer = [0; 65536];
+    while received.len() < lines.len() {
+        let length = receiver.recv(&mut buffer).unwrap();
+        assert!(length <= 1432);
+        let datagram = std::str::from_utf8(&buffer[..length]).unwrap();
+        received.extend(datagram.split('\n').map(String::from));
+    }
+    assert_eq!(received, lines);
+}
+
+#[test]
+fn sends_events_and_metrics_over_udp() {
+    let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+    receiver
+        .set_read_timeout(Some(Duration::from_millis(100)))
+        .unwrap();
+    metrics::RUNTIME_START_TIMESTAMP.set(1_700_000_000);
+
+    let config = SinkConfig {
+        name: String::from("telegraf"),
+        protocol: Protocol::Influx,
+        destination: Destination::Udp(receiver.local_addr().unwrap().to_string()),
+        interval: Some(Duration::from_millis(50)),
+        events: vec![String::from("block_connected")],
+    };
+    let sink = Sink::from_config(&config).unwrap();
+    thread::spawn(move || sink.run());
+
+    let mut event_received = false;
+    let mut snapshots = 0;
+    let start = Instant::now();
+    let mut buffer = [0; 65536];
+    while !event_received || snapshots < 2 {
+        assert!(start.elapsed() < Duration::from_secs(5), "timed out");
+        events::publish("mainnet", || block(800_001));
+        let length = match receiver.recv(&mut buffer) {
+            Ok(length) => length,
+            Err(_) => continue,
+        };
+        for line in std::str::from_utf8(&buffer[..length]).unwrap().lines() {
+            if line.starts_with("bitcoindobserver_block_connected,node=mainnet ") {
+                assert!(line.contains(",height=800001i,"), "{}", line);
+                event_received = true;
+            } else if line.starts_with("bitcoindobserver_runtime_start_timestamp value=1700000000 ")
+            {
+                snapshots += 1;
+            }
+            // Other event kinds aren't sent.
+            assert!(!line.starts_with("bitcoindobserver_mempool_added"));
+        }
+        events::publish("mainnet", || {
+            Event::MempoolAdded(events::MempoolTransaction {
+                txid: String::from("00"),
+                vsize: Some(100),
+                fee: Some(1000),
+                reason: None,
+            })
+        });
+    }
+}
+
+#[test]
+fn posts_over_http() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let address = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let mut requests = vec![];
+        for status in [204, 401].iter() {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut head = String::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                head.push_str(&line);
+                if line == "\r\n" {
+                    break;
+                }
+            }
+            let length: usize = head
+                .lines()
+                .find_map(|l| l.strip_prefix("Content-Length: "))
+                .unwrap()
+                .parse()
+                .unwrap();
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            // The client may close the connection after the status line.
+            let response = format!(
+                "HTTP/1.1 {} Stub\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                status
+            );
+            let _ = reader.get_mut().write_all(response.as_bytes());
+            requests.push((head, String::from_utf8(body).unwrap()));
+        }
+        requests
+    });
+
+    let url = format!("http://{}/api/v2/write?org=o&bucket=b", address)
+        .parse()
+        .unwrap();
+    let mut transport = HttpTransport::new(url, Some(String::from("secret")));
+    let lines = vec![String::from("a value=1 1"), String::from("b value=2 1")];
+    transport.send(&lines).unwrap();
+    assert!(matches!(transport.send(&lines), Err(SinkError::Http(_))));
+
+    let requests = server.join().unwrap();
+    let (head, body) = &requests[0];
+    assert!(head.starts_with("POST /api/v2/write?org=o&bucket=b HTTP/1.1\r\n"));
+    assert!(head.contains("\r\nAuthorization: Token secret\r\n"));
+    assert_eq!(body, "a value=1 1\nb value=2 1\n");
+}
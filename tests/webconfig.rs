@@ -0,0 +1,309 @@
+//! Integration tests of TLS and authentication of the metric server, with
+//! certificates generated for each test.
+
+use std::convert::TryFrom;
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use base64::Engine;
+use rcgen::{
+    BasicConstraints, CertificateParams, CertifiedKey, DnType, ExtendedKeyUsagePurpose, IsCa,
+    KeyPair,
+};
+use rustls::pki_types::{PrivateKeyDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+
+use bitcoind_observer::metricserver;
+use bitcoind_observer::webconfig::{WebConfig, WebConfigError};
+
+/// A directory for the files of a test, removed when dropped.
+struct TestDir(PathBuf);
+
+impl TestDir {
+    fn new(name: &str) -> TestDir {
+        let path = env::temp_dir().join(format!(
+            "bitcoind-observer-test-{}-{}",
+            name,
+            std::process::id()
+        ));
+        fs::create_dir_all(&path).unwrap();
+        TestDir(path)
+    }
+
+    fn write(&self, name: &str, contents: &str) -> PathBuf {
+        let path = self.0.join(name);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+struct Certificates {
+    ca: CertifiedKey,
+    client: CertifiedKey,
+}
+
+fn signed(
+    mut params: CertificateParams,
+    usage: ExtendedKeyUsagePurpose,
+    ca: &CertifiedKey,
+) -> CertifiedKey {
+    params.extended_key_usages = vec![usage];
+    let key_pair = KeyPair::generate().unwrap();
+    let cert = params.signed_by(&key_pair, &ca.cert, &ca.key_pair).unwrap();
+    CertifiedKey { cert, key_pair }
+}
+
+/// Generates a CA and writes it with a server and a client certificate
+/// signed by it to the directory.
+fn certificates(dir: &TestDir) -> Certificates {
+    let mut params = CertificateParams::new(vec![]).unwrap();
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params
+        .distinguished_name
+        .push(DnType::CommonName, "bitcoind-observer test CA");
+    let key_pair = KeyPair::generate().unwrap();
+    let cert = params.self_signed(&key_pair).unwrap();
+    let ca = CertifiedKey { cert, key_pair };
+
+    let params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+    let server = signed(params, ExtendedKeyUsagePurpose::ServerAuth, &ca);
+    let mut params = CertificateParams::new(vec![]).unwrap();
+    params
+        .distinguished_name
+        .push(DnType::CommonName, "prometheus");
+    let client = signed(params, ExtendedKeyUsagePurpose::ClientAuth, &ca);
+
+    dir.write("ca.crt", &ca.cert.pem());
+    dir.write("server.crt", &server.cert.pem());
+    dir.write("server.key", &server.key_pair.serialize_pem());
+    Certificates { ca, client }
+}
+
+fn tls_client(certificates: &Certificates, client_certificate: bool) -> Arc<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    roots.add(certificates.ca.cert.der().clone()).unwrap();
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots);
+    let config = if client_certificate {
+        let key = PrivateKeyDer::try_from(certificates.client.key_pair.serialize_der()).unwrap();
+        builder
+            .with_client_auth_cert(vec![certificates.client.cert.der().clone()], key)
+            .unwrap()
+    } else {
+        builder.with_no_client_auth()
+    };
+    Arc::new(config)
+}
+
+/// Sends a GET request over the stream and returns the status and the raw
+/// response.
+fn get<S: Read + Write>(
+    stream: &mut S,
+    path: &str,
+    headers: &str,
+) -> std::io::Result<(u16, String)> {
+    write!(
+        stream,
+        "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n{}\r\n",
+        path, headers
+    )?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let status = response.split(' ').nth(1).unwrap().parse().unwrap();
+    Ok((status, response))
+}
+
+fn get_tls(
+    address: SocketAddr,
+    config: Arc<ClientConfig>,
+    path: &str,
+) -> std::io::Result<(u16, String)> {
+    let name = ServerName::try_from("localhost").unwrap();
+    let connection = ClientConnection::new(config, name).unwrap();
+    let mut stream = StreamOwned::new(connection, TcpStream::connect(address)?);
+    get(&mut stream, path, "")
+}
+
+fn get_plain(address: SocketAddr, path: &str, headers: &str) -> (u16, String) {
+    get(&mut TcpStream::connect(address).unwrap(), path, headers).unwrap()
+}
+
+fn basic_auth(user: &str, password: &str) -> String {
+    format!(
+        "Authorization: Basic {}\r\n",
+        base64::engine::general_purpose::STANDARD.encode(format!("{}:{}", user, password))
+    )
+}
+
+/// A bcrypt hash with the lowest cost, so the tests run fast.
+fn hash(secret: &str) -> String {
+    bcrypt::hash(secret, 4).unwrap()
+}
+
+#[test]
+fn serves_tls_with_client_certificates() {
+    let dir = TestDir::new("mtls");
+    let certificates = certificates(&dir);
+    let path = dir.write(
+        "web.yml",
+        "tls_server_config:\n  \
+           cert_file: server.crt\n  \
+           key_file: server.key\n  \
+           client_auth_type: RequireAndVerifyClientCert\n  \
+           client_ca_file: ca.crt\n",
+    );
+    let address =
+        metricserver::start("127.0.0.1:0", Some(WebConfig::load(&path).unwrap())).unwrap();
+
+    let (status, response) = get_tls(address, tls_client(&certificates, true), "/health").unwrap();
+    assert_eq!(status, 200);
+    assert!(response.ends_with("\r\n\r\nOK"));
+
+    assert!(get_tls(address, tls_client(&certificates, false), "/health").is_err());
+
+    // Plain HTTP requests fail the handshake.
+    let mut stream = TcpStream::connect(address).unwrap();
+    assert!(get(&mut stream, "/health", "").map_or(true, |(status, _)| status != 200));
+}
+
+#[test]
+fn serves_tls_with_optional_client_certificates() {
+    let dir = TestDir::new("tls");
+    let certificates = certificates(&dir);
+    let path = dir.write(
+        "web.yml",
+        "tls_server_config:\n  \
+           cert_file: server.crt\n  \
+           key_file: server.key\n  \
+           client_auth_type: VerifyClientCertIfGiven\n  \
+           client_ca_file: ca.crt\n  \
+           min_version: TLS13\n",
+    );
+    let address =
+        metricserver::start("127.0.0.1:0", Some(WebConfig::load(&path).unwrap())).unwrap();
+
+    for client_certificate in [true, false].iter() {
+        let client = tls_client(&certificates, *client_certificate);
+        let (status, _) = get_tls(address, client, "/metrics").unwrap();
+        assert_eq!(status, 200);
+    }
+}
+
+#[test]
+fn requires_credentials() {
+    let dir = TestDir::new("auth");
+    let path = dir.write(
+        "web.yml",
+        &format!(
+            "basic_auth_users:\n  prometheus: {}\nbearer_tokens:\n  - {}\n",
+            hash("secret"),
+            hash("token")
+        ),
+    );
+    let address =
+        metricserver::start("127.0.0.1:0", Some(WebConfig::load(&path).unwrap())).unwrap();
+
+    let (status, response) = get_plain(address, "/metrics", "");
+    assert_eq!(status, 401);
+    assert!(response.contains("\r\nWWW-Authenticate: Basic realm=\"bitcoind-observer\"\r\n"));
+    assert!(response.contains("\r\nWWW-Authenticate: Bearer realm=\"bitcoind-observer\"\r\n"));
+
+    let correct = basic_auth("prometheus", "secret");
+    assert_eq!(get_plain(address, "/metrics", &correct).0, 200);
+    // Again, with the verification cached.
+    assert_eq!(get_plain(address, "/metrics", &correct).0, 200);
+    assert_eq!(get_plain(address, "/events?kind=nope", &correct).0, 400);
+
+    let wrong = basic_auth("prometheus", "wrong");
+    assert_eq!(get_plain(address, "/metrics", &wrong).0, 401);
+    let unknown = basic_auth("grafana", "secret");
+    assert_eq!(get_plain(address, "/metrics", &unknown).0, 401);
+    assert_eq!(get_plain(address, "/events", &unknown).0, 401);
+    let malformed = "Authorization: Basic !!!\r\n";
+    assert_eq!(get_plain(address, "/metrics", malformed).0, 401);
+
+    let bearer = "Authorization: Bearer token\r\n";
+    assert_eq!(get_plain(address, "/health", bearer).0, 200);
+    let bearer = "Authorization: bearer secret\r\n";
+    assert_eq!(get_plain(address, "/health", bearer).0, 401);
+}
+
+#[test]
+fn rejects_invalid_web_configs() {
+    let dir = TestDir::new("invalid");
+    certificates(&dir);
+    let tls = "tls_server_config:\n  cert_file: server.crt\n  key_file: server.key\n";
+    let cases = [
+        "unknown_option: true\n".to_string(),
+        "basic_auth_users:\n  prometheus: secret\n".to_string(),
+        "bearer_tokens:\n  - token\n".to_string(),
+        format!("{}  client_auth_type: RequestClientCert\n", tls),
+        format!("{}  client_auth_type: RequireAndVerifyClientCert\n", tls),
+        format!("{}  min_version: TLS11\n", tls),
+        format!("{}  min_version: TLS13\n  max_version: TLS12\n", tls),
+        "tls_server_config:\n  cert_file: missing.crt\n  key_file: server.key\n".to_string(),
+        "tls_server_config:\n  cert_file: server.key\n  key_file: server.key\n".to_string(),
+    ];
+    for contents in cases.iter() {
+        let path = dir.write("web.yml", contents);
+        assert!(WebConfig::load(&path).is_err(), "{}", contents);
+    }
+
+    let path = dir.write(
+        "web.yml",
+        "tls_server_config:\n  cert_file: missing.crt\n  key_file: server.key\n",
+    );
+    assert!(matches!(
+        WebConfig::load(&path),
+        Err(WebConfigError::Io(_, _))
+    ));
+    let path = dir.write("web.yml", tls);
+    assert!(WebConfig::load(&path).is_ok());
+}
+
+#[test]
+fn reloads_on_sighup() {
+    let dir = TestDir::new("reload");
+    let path = dir.write(
+        "web.yml",
+        &format!("basic_auth_users:\n  prometheus: {}\n", hash("old")),
+    );
+    let address =
+        metricserver::start("127.0.0.1:0", Some(WebConfig::load(&path).unwrap())).unwrap();
+    let old = basic_auth("prometheus", "old");
+    let new = basic_auth("prometheus", "new");
+    assert_eq!(get_plain(address, "/health", &old).0, 200);
+
+    dir.write(
+        "web.yml",
+        &format!("basic_auth_users:\n  prometheus: {}\n", hash("new")),
+    );
+    signal_hook::low_level::raise(signal_hook::consts::SIGHUP).unwrap();
+    let start = Instant::now();
+    while get_plain(address, "/health", &new).0 != 200 {
+        assert!(start.elapsed() < Duration::from_secs(5), "not reloaded");
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(get_plain(address, "/health", &old).0, 401);
+
+    // An invalid config is not applied.
+    dir.write("web.yml", "basic_auth_users: [\n");
+    signal_hook::low_level::raise(signal_hook::consts::SIGHUP).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(get_plain(address, "/health", &new).0, 200);
+}